@@ -0,0 +1,305 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    convert::Infallible,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use axum::{
+    extract::{
+        ws::{Message as WsMessage, WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    http::StatusCode,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::get,
+    Json, Router,
+};
+use futures_util::stream::{self, Stream};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{
+    net::TcpListener,
+    select,
+    sync::broadcast::{self, error::RecvError},
+};
+
+use crate::{capture::CapturedMessage, filter::Filter, message::Direction, output::captured_json};
+
+// A local HTTP api for other tools: decoded messages as JSON, streamed over a websocket or server-sent events, the
+// most recent ones and the connections they came from. Streams and the message list take `filter` (the same syntax
+// as --filter) and `connection` query parameters.
+
+// how many messages GET /messages can go back
+const RECENT: usize = 1000;
+
+pub struct Api {
+    next_id: Mutex<u64>,
+    recent: Mutex<VecDeque<Arc<Published>>>,
+    connections: Mutex<BTreeMap<u32, ConnectionInfo>>,
+    sender: broadcast::Sender<Arc<Published>>,
+}
+
+pub struct Published {
+    pub message: CapturedMessage,
+    pub json: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionInfo {
+    pub connection: u32,
+    // seconds since the unix epoch, or since the start of the capture
+    pub first_seen: f64,
+    pub last_seen: f64,
+    pub messages_in: u64,
+    pub messages_out: u64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct MessageQuery {
+    filter: Option<String>,
+    connection: Option<u32>,
+    // GET /messages only, how many of the most recent matching messages to return
+    limit: Option<usize>,
+}
+
+// What a client asked for
+struct Subscription {
+    filter: Option<Filter>,
+    connection: Option<u32>,
+}
+
+impl Subscription {
+    fn new(query: &MessageQuery) -> Result<Self, (StatusCode, String)> {
+        let filter = match &query.filter {
+            Some(f) => Some(
+                Filter::parse(f)
+                    .map_err(|e| (StatusCode::BAD_REQUEST, format!("invalid filter: {:#}", e)))?,
+            ),
+            None => None,
+        };
+        Ok(Self {
+            filter,
+            connection: query.connection,
+        })
+    }
+    fn wants(&self, p: &Published) -> bool {
+        let m = &p.message;
+        if self.connection.is_some_and(|c| c != m.connection) {
+            return false;
+        }
+        self.filter.iter().all(|f| f.passes(m))
+    }
+}
+
+impl Default for Api {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Api {
+    pub fn new() -> Self {
+        Self {
+            next_id: Mutex::new(0),
+            recent: Mutex::new(VecDeque::with_capacity(RECENT)),
+            connections: Mutex::new(BTreeMap::new()),
+            // a client that falls this far behind skips ahead
+            sender: broadcast::channel(RECENT).0,
+        }
+    }
+    pub fn publish(&self, m: &CapturedMessage) {
+        let id = {
+            let mut next = self.next_id.lock().unwrap();
+            *next += 1;
+            *next
+        };
+        let mut json = captured_json(m);
+        json["id"] = json!(id);
+
+        let seen = m.timestamp.as_secs_f64();
+        let mut connections = self.connections.lock().unwrap();
+        let c = connections
+            .entry(m.connection)
+            .or_insert_with(|| ConnectionInfo {
+                connection: m.connection,
+                first_seen: seen,
+                last_seen: seen,
+                messages_in: 0,
+                messages_out: 0,
+            });
+        c.last_seen = seen;
+        match m.direction {
+            Direction::Incoming => c.messages_in += 1,
+            Direction::Outgoing => c.messages_out += 1,
+        }
+        drop(connections);
+
+        let published = Arc::new(Published {
+            message: m.clone(),
+            json,
+        });
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == RECENT {
+            recent.pop_front();
+        }
+        recent.push_back(published.clone());
+        // no subscribers isn't an error
+        let _ = self.sender.send(published);
+    }
+    // The connection is gone and stops being listed
+    pub fn close(&self, connection: u32) {
+        self.connections.lock().unwrap().remove(&connection);
+    }
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.connections.lock().unwrap().values().cloned().collect()
+    }
+}
+
+// Serves the api until the listener fails:
+// GET /messages, /messages/ws, /messages/sse and /connections
+pub async fn serve(listener: TcpListener, api: Arc<Api>) -> Result<()> {
+    let app = Router::new()
+        .route("/messages", get(recent_messages))
+        .route("/messages/ws", get(websocket))
+        .route("/messages/sse", get(server_sent_events))
+        .route("/connections", get(connections))
+        .with_state(api);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn connections(State(api): State<Arc<Api>>) -> Json<Vec<ConnectionInfo>> {
+    Json(api.connections())
+}
+
+async fn recent_messages(
+    State(api): State<Arc<Api>>,
+    Query(query): Query<MessageQuery>,
+) -> Response {
+    let subscription = match Subscription::new(&query) {
+        Ok(s) => s,
+        Err(e) => return e.into_response(),
+    };
+    let recent = api.recent.lock().unwrap();
+    let mut messages: Vec<_> = recent
+        .iter()
+        .rev()
+        .filter(|p| subscription.wants(p))
+        .take(query.limit.unwrap_or(100))
+        .map(|p| p.json.clone())
+        .collect();
+    messages.reverse();
+    Json(messages).into_response()
+}
+
+async fn websocket(
+    State(api): State<Arc<Api>>,
+    Query(query): Query<MessageQuery>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let subscription = match Subscription::new(&query) {
+        Ok(s) => s,
+        Err(e) => return e.into_response(),
+    };
+    let receiver = api.sender.subscribe();
+    upgrade.on_upgrade(move |socket| stream_to_socket(socket, receiver, subscription))
+}
+
+async fn stream_to_socket(
+    mut socket: WebSocket,
+    mut receiver: broadcast::Receiver<Arc<Published>>,
+    subscription: Subscription,
+) {
+    loop {
+        select! {
+            published = receiver.recv() => match published {
+                Ok(p) if subscription.wants(&p) => {
+                    if socket.send(WsMessage::Text(p.json.to_string())).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return,
+            },
+            // nothing is expected from the client, this is only here to notice it's gone
+            incoming = socket.recv() => match incoming {
+                Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+async fn server_sent_events(
+    State(api): State<Arc<Api>>,
+    Query(query): Query<MessageQuery>,
+) -> Response {
+    let subscription = match Subscription::new(&query) {
+        Ok(s) => s,
+        Err(e) => return e.into_response(),
+    };
+    Sse::new(events(api.sender.subscribe(), subscription))
+        .keep_alive(KeepAlive::new().interval(Duration::from_secs(15)))
+        .into_response()
+}
+
+fn events(
+    receiver: broadcast::Receiver<Arc<Published>>,
+    subscription: Subscription,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    stream::unfold(
+        (receiver, subscription),
+        |(mut receiver, subscription)| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(p) if subscription.wants(&p) => {
+                        let event = Event::default().data(p.json.to_string());
+                        return Some((Ok(event), (receiver, subscription)));
+                    }
+                    Ok(_) | Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Api, MessageQuery, Subscription};
+    use crate::{capture::CapturedMessage, message::Direction};
+
+    #[test]
+    fn test_publish_and_filter() {
+        let api = Api::new();
+        for (connection, raw) in [(1, vec![0xF3, 2, 66, 0, 0]), (2, vec![0xF3, 2, 3, 0, 0])] {
+            api.publish(&CapturedMessage {
+                timestamp: Duration::from_secs(connection as u64),
+                connection,
+                ..CapturedMessage::decoded(Direction::Outgoing, raw)
+            });
+        }
+        let subscription = Subscription::new(&MessageQuery {
+            filter: Some("opcode == LoadWallet".into()),
+            ..Default::default()
+        })
+        .unwrap();
+        let recent = api.recent.lock().unwrap();
+        let wanted: Vec<_> = recent.iter().filter(|p| subscription.wants(p)).collect();
+        assert_eq!(wanted.len(), 1);
+        assert_eq!(wanted[0].json["connection"], 1);
+        assert_eq!(wanted[0].json["name"], "LoadWallet");
+        drop(recent);
+
+        assert_eq!(api.connections().len(), 2);
+        api.close(1);
+        assert_eq!(api.connections()[0].connection, 2);
+    }
+}
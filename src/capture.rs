@@ -0,0 +1,486 @@
+use std::{
+    fs::File,
+    io::{BufReader, Read},
+    net::SocketAddrV4,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use pnet::packet::{
+    ethernet::EthernetPacket,
+    ipv4::Ipv4Packet,
+    tcp::{TcpFlags, TcpPacket},
+    Packet,
+};
+
+use crate::{
+    message::{Direction, Message},
+    pipeline::Pipeline,
+};
+
+pub const WEBSERVICES_PORT: u16 = 4533;
+
+// One link layer (ethernet) frame from a capture file
+pub struct CapturedPacket {
+    pub timestamp: Duration,
+    pub data: Vec<u8>,
+}
+
+// One TCP segment going to or coming from the WebServices port
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub direction: Direction,
+    // the two ends of the connection, the same whichever way the segment goes
+    pub client: SocketAddrV4,
+    pub server: SocketAddrV4,
+    pub seq: u32,
+    pub syn: bool,
+    pub fin: bool,
+    pub rst: bool,
+    pub payload: Vec<u8>,
+}
+
+// Pulls the TCP segment out of an ethernet frame if it's going to or coming from `port`
+pub fn dissect(packet: &[u8], port: u16) -> Option<Segment> {
+    let ethernet = EthernetPacket::new(packet)?;
+    let ipv4 = Ipv4Packet::new(ethernet.payload())?;
+    let tcp = TcpPacket::new(ipv4.payload())?;
+    let source = SocketAddrV4::new(ipv4.get_source(), tcp.get_source());
+    let destination = SocketAddrV4::new(ipv4.get_destination(), tcp.get_destination());
+    let (direction, client, server) = if tcp.get_source() == port {
+        (Direction::Incoming, destination, source)
+    } else if tcp.get_destination() == port {
+        (Direction::Outgoing, source, destination)
+    } else {
+        return None;
+    };
+    let flags = tcp.get_flags();
+    Some(Segment {
+        direction,
+        client,
+        server,
+        seq: tcp.get_sequence(),
+        syn: flags & TcpFlags::SYN != 0,
+        fin: flags & TcpFlags::FIN != 0,
+        rst: flags & TcpFlags::RST != 0,
+        payload: tcp.payload().to_vec(),
+    })
+}
+
+// Reads pcap and pcapng files (ethernet link type only), as written by tcpdump/wireshark
+pub struct PcapReader<R> {
+    reader: R,
+    format: PcapFormat,
+}
+enum PcapFormat {
+    Pcap {
+        big_endian: bool,
+        nanos: bool,
+        snaplen: usize,
+    },
+    PcapNg {
+        big_endian: bool,
+        interfaces: Vec<Interface>,
+    },
+}
+struct Interface {
+    // timestamp units per second
+    resolution: u64,
+    snaplen: usize,
+}
+
+// Nothing records more than this per packet (it's wireshark's limit), anything claiming to is corrupt and
+// shouldn't get allocated
+const MAX_SNAPLEN: usize = 262144;
+// pcapng blocks also hold options and comments around the packet
+const MAX_BLOCK_LEN: usize = 16 * 1024 * 1024;
+
+// a snaplen of 0 means there's none
+fn snaplen(len: u32) -> usize {
+    match len as usize {
+        0 => MAX_SNAPLEN,
+        len => len.min(MAX_SNAPLEN),
+    }
+}
+
+impl PcapReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let f = File::open(path).context(format!("opening capture {}", path.display()))?;
+        Self::new(BufReader::new(f))
+    }
+}
+impl<R: Read> PcapReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        let (big_endian, nanos) = match magic {
+            [0xd4, 0xc3, 0xb2, 0xa1] => (false, false),
+            [0xa1, 0xb2, 0xc3, 0xd4] => (true, false),
+            [0x4d, 0x3c, 0xb2, 0xa1] => (false, true),
+            [0xa1, 0xb2, 0x3c, 0x4d] => (true, true),
+            [0x0a, 0x0d, 0x0d, 0x0a] => {
+                // section header block: length, then the byte order magic
+                let mut head = [0u8; 8];
+                reader.read_exact(&mut head)?;
+                let big_endian = head[4..8] == [0x1a, 0x2b, 0x3c, 0x4d];
+                let len = read_u32(&head[0..4], big_endian) as usize;
+                if !(12..=MAX_BLOCK_LEN).contains(&len) {
+                    bail!("invalid pcapng section header length {}", len);
+                }
+                skip(&mut reader, len - 12)?;
+                return Ok(Self {
+                    reader,
+                    format: PcapFormat::PcapNg {
+                        big_endian,
+                        interfaces: Vec::new(),
+                    },
+                });
+            }
+            _ => bail!("not a pcap or pcapng file"),
+        };
+        // version, timezone, accuracy, snaplen, link type
+        let mut header = [0u8; 20];
+        reader.read_exact(&mut header)?;
+        let link_type = read_u32(&header[16..20], big_endian);
+        if link_type != 1 {
+            bail!(
+                "unsupported pcap link type {}, only ethernet captures are supported",
+                link_type
+            );
+        }
+        Ok(Self {
+            reader,
+            format: PcapFormat::Pcap {
+                big_endian,
+                nanos,
+                snaplen: snaplen(read_u32(&header[12..16], big_endian)),
+            },
+        })
+    }
+    pub fn next_packet(&mut self) -> Result<Option<CapturedPacket>> {
+        match &mut self.format {
+            PcapFormat::Pcap {
+                big_endian,
+                nanos,
+                snaplen,
+            } => {
+                let mut header = [0u8; 16];
+                if !read_or_eof(&mut self.reader, &mut header)? {
+                    return Ok(None);
+                }
+                let secs = read_u32(&header[0..4], *big_endian) as u64;
+                let frac = read_u32(&header[4..8], *big_endian) as u64;
+                let len = read_u32(&header[8..12], *big_endian) as usize;
+                if len > *snaplen {
+                    bail!(
+                        "pcap record of {} bytes is longer than the snapshot length {}",
+                        len,
+                        snaplen
+                    );
+                }
+                let mut data = vec![0u8; len];
+                self.reader
+                    .read_exact(&mut data)
+                    .context("capture file ends in the middle of a record")?;
+                let timestamp = if *nanos {
+                    Duration::new(secs, frac as u32)
+                } else {
+                    Duration::new(secs, (frac * 1000) as u32)
+                };
+                Ok(Some(CapturedPacket { timestamp, data }))
+            }
+            PcapFormat::PcapNg {
+                big_endian,
+                interfaces,
+            } => loop {
+                let big_endian = *big_endian;
+                let mut header = [0u8; 8];
+                if !read_or_eof(&mut self.reader, &mut header)? {
+                    return Ok(None);
+                }
+                let block_type = read_u32(&header[0..4], big_endian);
+                let len = read_u32(&header[4..8], big_endian) as usize;
+                if !(12..=MAX_BLOCK_LEN).contains(&len) {
+                    bail!("invalid pcapng block length {}", len);
+                }
+                let mut body = vec![0u8; len - 12];
+                self.reader
+                    .read_exact(&mut body)
+                    .context("capture file ends in the middle of a block")?;
+                // trailing copy of the block length
+                skip(&mut self.reader, 4)?;
+                match block_type {
+                    // interface description
+                    1 => {
+                        if body.len() < 8 {
+                            bail!("pcapng interface description block too short");
+                        }
+                        let link_type = read_u16(&body[0..2], big_endian);
+                        if link_type != 1 {
+                            bail!("unsupported pcapng link type {}, only ethernet captures are supported", link_type);
+                        }
+                        interfaces.push(Interface {
+                            resolution: interface_resolution(&body[8..], big_endian)?,
+                            snaplen: snaplen(read_u32(&body[4..8], big_endian)),
+                        });
+                    }
+                    // enhanced packet
+                    6 => {
+                        if body.len() < 20 {
+                            bail!("pcapng enhanced packet block too short");
+                        }
+                        let interface = read_u32(&body[0..4], big_endian) as usize;
+                        let interface = interfaces
+                            .get(interface)
+                            .context(format!("pcapng packet on unknown interface {}", interface))?;
+                        let ts = ((read_u32(&body[4..8], big_endian) as u64) << 32)
+                            | read_u32(&body[8..12], big_endian) as u64;
+                        let cap_len = read_u32(&body[12..16], big_endian) as usize;
+                        if cap_len > interface.snaplen {
+                            bail!(
+                                "pcapng packet of {} bytes is longer than the snapshot length {}",
+                                cap_len,
+                                interface.snaplen
+                            );
+                        }
+                        let data = body
+                            .get(20..20 + cap_len)
+                            .context("pcapng packet shorter than its captured length")?
+                            .to_vec();
+                        let res = interface.resolution;
+                        let timestamp = Duration::new(
+                            ts / res,
+                            ((ts % res) as u128 * 1_000_000_000 / res as u128) as u32,
+                        );
+                        return Ok(Some(CapturedPacket { timestamp, data }));
+                    }
+                    // simple packet, no timestamp. It's cut at the first interface's snaplen and padded to 4 bytes,
+                    // the original length says how much of it is packet
+                    3 => {
+                        if body.len() < 4 {
+                            bail!("pcapng simple packet block too short");
+                        }
+                        let interface = interfaces
+                            .first()
+                            .context("pcapng simple packet before any interface")?;
+                        let len = (read_u32(&body[0..4], big_endian) as usize)
+                            .min(interface.snaplen)
+                            .min(body.len() - 4);
+                        return Ok(Some(CapturedPacket {
+                            timestamp: Duration::ZERO,
+                            data: body[4..4 + len].to_vec(),
+                        }));
+                    }
+                    _ => {}
+                }
+            },
+        }
+    }
+}
+
+// if_tsresol option of an interface description block, defaults to microseconds
+fn interface_resolution(mut options: &[u8], big_endian: bool) -> Result<u64> {
+    while options.len() >= 4 {
+        let code = read_u16(&options[0..2], big_endian);
+        let len = read_u16(&options[2..4], big_endian) as usize;
+        if code == 0 {
+            break;
+        }
+        if code == 9 && len == 1 && options.len() > 4 {
+            let v = options[4];
+            let res = if v & 0x80 != 0 {
+                2u64.checked_pow((v & 0x7f) as u32)
+            } else {
+                10u64.checked_pow(v as u32)
+            };
+            return res.context(format!("invalid pcapng timestamp resolution {:#x}", v));
+        }
+        let padded = (len + 3) & !3;
+        options = options.get(4 + padded..).unwrap_or(&[]);
+    }
+    Ok(1_000_000)
+}
+
+fn read_u32(b: &[u8], big_endian: bool) -> u32 {
+    let b = [b[0], b[1], b[2], b[3]];
+    if big_endian {
+        u32::from_be_bytes(b)
+    } else {
+        u32::from_le_bytes(b)
+    }
+}
+fn read_u16(b: &[u8], big_endian: bool) -> u16 {
+    let b = [b[0], b[1]];
+    if big_endian {
+        u16::from_be_bytes(b)
+    } else {
+        u16::from_le_bytes(b)
+    }
+}
+fn skip(reader: &mut impl Read, n: usize) -> Result<()> {
+    std::io::copy(&mut reader.take(n as u64), &mut std::io::sink())?;
+    Ok(())
+}
+// read_exact, but a clean EOF before the first byte returns false
+pub(crate) fn read_or_eof(reader: &mut impl Read, buf: &mut [u8]) -> Result<bool> {
+    let mut read = 0;
+    while read < buf.len() {
+        let n = reader.read(&mut buf[read..])?;
+        if n == 0 {
+            if read == 0 {
+                return Ok(false);
+            }
+            bail!("capture file ends in the middle of a record");
+        }
+        read += n;
+    }
+    Ok(true)
+}
+
+pub struct CapturedMessage {
+    pub timestamp: Duration,
+    pub direction: Direction,
+    // numbered in the order connections were first seen, or as the proxy accepted them
+    pub connection: u32,
+    // as it was on the wire
+    pub raw: Vec<u8>,
+    // the same message after decryption, when it was encrypted and could be decrypted
+    pub decrypted: Option<Vec<u8>>,
+    pub message: Result<Message>,
+}
+impl CapturedMessage {
+    // the bytes that were decoded into `message`
+    pub fn message_bytes(&self) -> &[u8] {
+        self.decrypted.as_deref().unwrap_or(&self.raw)
+    }
+}
+#[cfg(test)]
+impl CapturedMessage {
+    // what the pipeline makes of an unencrypted message on connection 0 at time 0, tests change the rest
+    pub(crate) fn decoded(direction: Direction, raw: Vec<u8>) -> Self {
+        Self {
+            timestamp: Duration::ZERO,
+            direction,
+            connection: 0,
+            message: crate::message::decode_message(&raw),
+            raw,
+            decrypted: None,
+        }
+    }
+}
+// anyhow errors can't be cloned, the copy gets one with the same message
+impl Clone for CapturedMessage {
+    fn clone(&self) -> Self {
+        Self {
+            timestamp: self.timestamp,
+            direction: self.direction,
+            connection: self.connection,
+            raw: self.raw.clone(),
+            decrypted: self.decrypted.clone(),
+            message: match &self.message {
+                Ok(m) => Ok(m.clone()),
+                Err(e) => Err(anyhow!("{:#}", e)),
+            },
+        }
+    }
+}
+
+// Runs every packet of a capture through the same dissect -> frame -> decode steps as live sniffing.
+// Native recordings are already framed and only get decoded
+pub fn read_messages(path: impl AsRef<Path>, port: u16) -> Result<Vec<CapturedMessage>> {
+    let messages = Arc::new(Mutex::new(Vec::new()));
+    let mut pipeline = Pipeline::new(port);
+    pipeline.add_sink(messages.clone());
+    pipeline.read_file(path)?;
+    let messages = std::mem::take(&mut *messages.lock().unwrap());
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::PcapReader;
+
+    fn next_error(file: Vec<u8>) -> String {
+        let mut reader = PcapReader::new(Cursor::new(file)).unwrap();
+        format!("{:#}", reader.next_packet().err().unwrap())
+    }
+    fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+        let len = (body.len() + 12) as u32;
+        [
+            &block_type.to_le_bytes(),
+            &len.to_le_bytes(),
+            body,
+            &len.to_le_bytes(),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn test_corrupt_captures() {
+        // pcap with a 1500 byte snaplen and a record claiming more than that
+        let mut pcap = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0];
+        pcap.extend([0; 8]);
+        pcap.extend(1500u32.to_le_bytes());
+        pcap.extend(1u32.to_le_bytes());
+        let mut record = pcap.clone();
+        record.extend([0; 8]);
+        record.extend(u32::MAX.to_le_bytes());
+        record.extend(u32::MAX.to_le_bytes());
+        assert!(next_error(record).contains("snapshot length"));
+        // and one that's cut off
+        pcap.extend([0; 8]);
+        pcap.extend(100u32.to_le_bytes());
+        pcap.extend(100u32.to_le_bytes());
+        pcap.extend([0; 10]);
+        assert!(next_error(pcap).contains("ends in the middle"));
+
+        let section = block(0x0a0d0d0a, &[0x4d, 0x3c, 0x2b, 0x1a, 1, 0, 0, 0]);
+        let interface = block(1, &[1, 0, 0, 0, 0, 0, 0, 0]);
+        let pcapng = |blocks: &[Vec<u8>]| [section.as_slice(), &blocks.concat()].concat();
+        for (blocks, error) in [
+            (
+                vec![block(1, &[1, 0])],
+                "interface description block too short",
+            ),
+            (
+                vec![interface.clone(), block(6, &[0; 8])],
+                "enhanced packet block too short",
+            ),
+            (
+                vec![interface.clone(), block(3, &[])],
+                "simple packet block too short",
+            ),
+            (vec![block(6, &[0; 20])], "unknown interface"),
+            (
+                vec![
+                    interface.clone(),
+                    block(
+                        6,
+                        &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 40, 0, 0, 0, 40, 0, 0, 0],
+                    ),
+                ],
+                "shorter than its captured length",
+            ),
+            (
+                vec![block(
+                    1,
+                    &[1, 0, 0, 0, 0, 0, 0, 0, 9, 0, 1, 0, 0xff, 0, 0, 0],
+                )],
+                "timestamp resolution",
+            ),
+        ] {
+            let e = next_error(pcapng(&blocks));
+            assert!(e.contains(error), "{}", e);
+        }
+        // a simple packet is cut at the interface's snaplen
+        let interface = block(1, &[1, 0, 0, 0, 4, 0, 0, 0]);
+        let simple = block(3, &[10, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8]);
+        let mut reader = PcapReader::new(Cursor::new(pcapng(&[interface, simple]))).unwrap();
+        assert_eq!(reader.next_packet().unwrap().unwrap().data, [1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,291 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{tcp::OwnedWriteHalf, TcpStream, ToSocketAddrs},
+    spawn,
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    task::JoinHandle,
+    time::{interval, timeout},
+};
+
+use crate::{
+    encryption::{Encryption, KEY_PARAMETER},
+    framing::{frame_message, FrameReader},
+    message::{decode_message, encode_message, is_encrypted, Direction, Message},
+    output::opcode_name,
+    pairing::Pairing,
+    serialization::{
+        op_code::InternalOperation, EventData, OperationRequest, OperationResponse, Value,
+    },
+};
+
+// Acting as a photon client: connect, init, optionally exchange keys, then send requests and wait for their
+// responses, which are matched up with the requests waiting for them by crate::pairing.
+
+// Protocol version 1.6 and nothing else. Real servers also want the client version and app id that follow, copy
+// the init message out of a recording for those
+pub const DEFAULT_INIT: &[u8] = &[0xF3, 0, 1, 6];
+
+#[derive(Debug, Clone)]
+pub struct ClientOptions {
+    // the init message, starting at 0xF3
+    pub init: Vec<u8>,
+    // how long to wait for a response
+    pub timeout: Duration,
+    // how often to ping the server, None to never ping
+    pub ping_interval: Option<Duration>,
+}
+impl Default for ClientOptions {
+    fn default() -> Self {
+        Self {
+            init: DEFAULT_INIT.to_vec(),
+            timeout: Duration::from_secs(10),
+            ping_interval: Some(Duration::from_secs(1)),
+        }
+    }
+}
+
+// What a sent message is waiting for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Awaiting {
+    Init,
+    Operation(u8),
+    InternalOperation(u8),
+}
+
+// Waiters are tagged with the id of their call so a call that gives up can take its own one out again
+type Pending = Arc<Mutex<Pairing<Awaiting, (u64, oneshot::Sender<Message>)>>>;
+
+// Takes the waiter out of the queue when the call returns or is dropped without its answer. Otherwise the next
+// answer to the same opcode goes to the dead waiter, and every call after it waits for an answer that's one behind
+struct Waiter<'a> {
+    pending: &'a Pending,
+    awaiting: Awaiting,
+    id: u64,
+}
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        self.pending
+            .lock()
+            .unwrap()
+            .retain(&self.awaiting, |(id, _)| *id != self.id);
+    }
+}
+
+pub struct PhotonClient {
+    writer: Arc<tokio::sync::Mutex<OwnedWriteHalf>>,
+    pending: Pending,
+    next_call: AtomicU64,
+    encryption: Arc<OnceLock<Encryption>>,
+    events: tokio::sync::Mutex<UnboundedReceiver<EventData>>,
+    timeout: Duration,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl PhotonClient {
+    // Connects and waits for the server to answer the init message
+    pub async fn connect(addr: impl ToSocketAddrs, options: ClientOptions) -> Result<Self> {
+        let stream = TcpStream::connect(addr)
+            .await
+            .context("could not connect")?;
+        let (read, write) = stream.into_split();
+        let (events_tx, events_rx) = unbounded_channel();
+        let mut client = Self {
+            writer: Arc::new(tokio::sync::Mutex::new(write)),
+            pending: Pending::default(),
+            next_call: AtomicU64::new(0),
+            encryption: Arc::new(OnceLock::new()),
+            events: tokio::sync::Mutex::new(events_rx),
+            timeout: options.timeout,
+            tasks: Vec::new(),
+        };
+        client.tasks.push(spawn(read_messages(
+            read,
+            client.pending.clone(),
+            client.encryption.clone(),
+            events_tx,
+        )));
+        client
+            .call(Awaiting::Init, options.init, false)
+            .await
+            .context("init")?;
+        if let Some(every) = options.ping_interval {
+            client.tasks.push(spawn(ping(client.writer.clone(), every)));
+        }
+        Ok(client)
+    }
+    // Diffie-Hellman key exchange, needed before anything can be sent encrypted
+    pub async fn exchange_keys(&self) -> Result<()> {
+        if self.encryption.get().is_some() {
+            bail!("keys were already exchanged");
+        }
+        let mut encryption = Encryption::new();
+        let opcode = InternalOperation::InitEncryption as u8;
+        let request = OperationRequest::new(
+            opcode,
+            HashMap::from([(
+                KEY_PARAMETER,
+                Value::ByteArray(encryption.public_key.to_bytes_be()),
+            )])
+            .into(),
+        );
+        let response = self
+            .call(
+                Awaiting::InternalOperation(opcode),
+                encode_message(&Message::InternalOperationRequest(request))?,
+                false,
+            )
+            .await
+            .context("key exchange")?;
+        let Message::InternalOperationResponse(response) = response else {
+            bail!("unexpected answer to the key exchange");
+        };
+        let Some(Value::ByteArray(server_key)) = response.parameters().get(&KEY_PARAMETER) else {
+            bail!(
+                "the server didn't send its key (return code {})",
+                response.return_code()
+            );
+        };
+        encryption.make_shared_key(server_key);
+        // can't be set already, exchange_keys is the only place that sets it and checked above
+        let _ = self.encryption.set(encryption);
+        Ok(())
+    }
+    pub async fn request(&self, request: OperationRequest) -> Result<OperationResponse> {
+        self.request_inner(request, false).await
+    }
+    // needs exchange_keys first. The response comes back encrypted too
+    pub async fn request_encrypted(&self, request: OperationRequest) -> Result<OperationResponse> {
+        self.request_inner(request, true).await
+    }
+    async fn request_inner(
+        &self,
+        request: OperationRequest,
+        encrypt: bool,
+    ) -> Result<OperationResponse> {
+        let opcode = request.opcode();
+        let response = self
+            .call(
+                Awaiting::Operation(opcode),
+                encode_message(&Message::OperationRequest(request))?,
+                encrypt,
+            )
+            .await
+            .with_context(|| format!("request {}", opcode_name(opcode)))?;
+        match response {
+            Message::OperationResponse(r) => Ok(r),
+            m => bail!("unexpected {:?} message", m.message_type()),
+        }
+    }
+    // Events in the order the server sent them, None once the connection is closed
+    pub async fn next_event(&self) -> Option<EventData> {
+        self.events.lock().await.recv().await
+    }
+    // Sends a message and waits for the answer to it
+    async fn call(&self, awaiting: Awaiting, message: Vec<u8>, encrypt: bool) -> Result<Message> {
+        let message = if encrypt {
+            self.encryption
+                .get()
+                .context("exchange_keys has to be called before sending encrypted messages")?
+                .encrypt_message(&message)
+                .context("could not encrypt message")?
+        } else {
+            message
+        };
+        let (tx, rx) = oneshot::channel();
+        let id = self.next_call.fetch_add(1, Ordering::Relaxed);
+        let _waiter = Waiter {
+            pending: &self.pending,
+            awaiting,
+            id,
+        };
+        {
+            // registered while holding the writer, so waiters are queued in the order their messages are sent
+            let mut writer = self.writer.lock().await;
+            self.pending.lock().unwrap().request(awaiting, (id, tx));
+            writer.write_all(&frame_message(&message)).await?;
+        }
+        match timeout(self.timeout, rx).await {
+            Ok(Ok(answer)) => Ok(answer),
+            Ok(Err(_)) => bail!("connection closed"),
+            Err(_) => bail!("no answer within {:?}", self.timeout),
+        }
+    }
+}
+
+impl Drop for PhotonClient {
+    fn drop(&mut self) {
+        for t in &self.tasks {
+            t.abort();
+        }
+    }
+}
+
+async fn read_messages(
+    mut read: tokio::net::tcp::OwnedReadHalf,
+    pending: Pending,
+    encryption: Arc<OnceLock<Encryption>>,
+    events: UnboundedSender<EventData>,
+) {
+    let mut framer = FrameReader::new(Direction::Incoming);
+    let mut buf = vec![0; 65536];
+    loop {
+        let n = match read.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        framer.push(&buf[..n]);
+        while let Some(raw) = framer.next_message() {
+            let plain = match encryption.get() {
+                Some(e) if is_encrypted(&raw) => e.decrypt_message(&raw).ok(),
+                _ => Some(raw),
+            };
+            // nothing is waiting on messages that can't be decoded, so they're dropped
+            let Some(Ok(message)) = plain.map(|p| decode_message(&p)) else {
+                continue;
+            };
+            let awaiting = match &message {
+                Message::InitResponse => Awaiting::Init,
+                Message::OperationResponse(r) => Awaiting::Operation(r.opcode()),
+                Message::InternalOperationResponse(r) => Awaiting::InternalOperation(r.opcode()),
+                Message::Event(e) => {
+                    let _ = events.send(e.clone());
+                    continue;
+                }
+                _ => continue,
+            };
+            let waiter = pending.lock().unwrap().response(&awaiting);
+            if let Some((_, w)) = waiter {
+                let _ = w.send(message);
+            }
+        }
+    }
+    // dropping the waiters tells them the connection is gone
+    pending.lock().unwrap().clear();
+}
+
+// Photon TCP pings are 0xF0 and the client's time in ms
+async fn ping(writer: Arc<tokio::sync::Mutex<OwnedWriteHalf>>, every: Duration) {
+    let started = Instant::now();
+    let mut ticks = interval(every);
+    loop {
+        ticks.tick().await;
+        let mut ping = vec![0xF0];
+        ping.extend_from_slice(&(started.elapsed().as_millis() as i32).to_be_bytes());
+        if writer.lock().await.write_all(&ping).await.is_err() {
+            return;
+        }
+    }
+}
@@ -2,11 +2,17 @@ use aes::{
     cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit},
     Aes256,
 };
+use anyhow::{anyhow, Context, Result};
 use num_bigint::{BigUint, RandBigInt};
 use rand::thread_rng;
 use sha2::{Digest, Sha256};
-//Currently unused, this pile of jank should interoperate with Photon message encryption, it's just not implemented since true fake client is
+
+// Parameter of the InitEncryption internal operation holding the public key, in both directions
+pub const KEY_PARAMETER: u8 = 1;
+
+// This pile of jank should interoperate with Photon message encryption. The fake server uses it, a true fake client is
 // blocked on EAC reversing and we obviously can't decrypt messages from the real client without its secret
+
 pub struct Encryption {
     pub prime: BigUint,
     pub secret: BigUint,
@@ -45,27 +51,42 @@ impl Encryption {
             cbc::Decryptor::new(&key_arr, &[0u8; 16].into()),
         ));
     }
-    pub fn decrypt<'a>(&self, buf: &'a [u8]) -> Option<Vec<u8>> {
-        let dec = &self.cipher.as_ref()?.1;
+    pub fn decrypt(&self, buf: &[u8]) -> Result<Vec<u8>> {
+        let dec = &self.cipher.as_ref().context("no shared key yet")?.1;
         let mut v = Vec::from(buf);
         let val = dec
             .clone()
             .decrypt_padded_mut::<block_padding::Pkcs7>(&mut v)
-            .map_err(|_| println!("ERROR: Crypto Unpad Error"))
-            .ok()?;
-        Some(Vec::from(val))
+            .map_err(|_| anyhow!("crypto unpad error"))?;
+        Ok(Vec::from(val))
     }
-    pub fn encrypt<'a>(&self, buf: &'a [u8]) -> Option<Vec<u8>> {
-        let dec = &self.cipher.as_ref()?.0;
+    pub fn encrypt(&self, buf: &[u8]) -> Result<Vec<u8>> {
+        let dec = &self.cipher.as_ref().context("no shared key yet")?.0;
         let mut v = Vec::from(buf);
         let l = v.len();
         v.resize(l + 16, 0);
         let s = dec
             .clone()
             .encrypt_padded_mut::<block_padding::Pkcs7>(&mut v, l)
-            .map_err(|_| println!("ERROR: Crypto Pad Error"))
-            .ok()?;
-        Some(Vec::from(s))
+            .map_err(|_| anyhow!("crypto pad error"))?;
+        Ok(Vec::from(s))
+    }
+    // Only what follows the 2 byte message header is encrypted, the header gets the 128 flag
+    pub fn encrypt_message(&self, message: &[u8]) -> Result<Vec<u8>> {
+        let [first, second, rest @ ..] = message else {
+            anyhow::bail!("message too short to encrypt");
+        };
+        let mut out = vec![*first, second | 128];
+        out.extend(self.encrypt(rest)?);
+        Ok(out)
+    }
+    pub fn decrypt_message(&self, message: &[u8]) -> Result<Vec<u8>> {
+        let [first, second, rest @ ..] = message else {
+            anyhow::bail!("message too short to decrypt");
+        };
+        let mut out = vec![*first, second & 127];
+        out.extend(self.decrypt(rest)?);
+        Ok(out)
     }
 }
 
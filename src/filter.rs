@@ -0,0 +1,455 @@
+use std::{cmp::Ordering, str::FromStr};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    capture::CapturedMessage,
+    message::{Direction, Message},
+    query::Query,
+    registry,
+    schema::{schema_key, MessageKind},
+    serialization::{op_code::WebServicesOpCode, Value},
+};
+
+// Message filters for the command line, e.g.
+//   dir == out && opcode in [LoadWallet, SaveRobotRequest] && return_code != 0
+//
+// fields: dir (in/out), kind (request/response/event), opcode, event, return_code and parameter queries
+// (see query.rs). Opcode and event names are resolved through WebServicesOpCode/WebServicesEventCode.
+// A bare parameter query is true if it matches anything. Comparisons against a field the message doesn't
+// have (e.g. return_code on a request) are false, comparisons against a parameter query are true if any
+// matched value satisfies them.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    expr: Expr,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Field, CompareOp, Literal),
+    In(Field, Vec<Literal>),
+    Exists(Query),
+}
+
+#[derive(Debug, Clone)]
+enum Field {
+    Direction,
+    Kind,
+    Opcode,
+    Event,
+    ReturnCode,
+    Parameter(Query),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Literal {
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Bool(bool),
+    Direction(Direction),
+    Kind(MessageKind),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Path(String),
+    Int(i64),
+    Float(f64),
+    Str(String),
+    Op(&'static str),
+}
+
+const OPERATORS: [&str; 14] = [
+    "&&", "||", "==", "!=", "<=", ">=", "<", ">", "!", "(", ")", "[", "]", ",",
+];
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = s.trim_start();
+    while !rest.is_empty() {
+        if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        } else if let Some(r) = rest.strip_prefix('"') {
+            let end = r
+                .find('"')
+                .ok_or_else(|| anyhow!("unterminated string in {}", s))?;
+            tokens.push(Token::Str(r[..end].to_string()));
+            rest = &r[end + 1..];
+        } else if rest.starts_with("params") || rest.starts_with('$') {
+            let end = path_len(rest);
+            tokens.push(Token::Path(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.'))
+                .unwrap_or(rest.len());
+            if end == 0 {
+                bail!("unexpected {:?} in filter {}", rest, s);
+            }
+            let word = &rest[..end];
+            tokens.push(if let Ok(i) = word.parse() {
+                Token::Int(i)
+            } else if let Ok(f) = word.parse() {
+                Token::Float(f)
+            } else {
+                Token::Ident(word.to_string())
+            });
+            rest = &rest[end..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+// a query runs until the first character that can't be part of one outside of brackets
+fn path_len(s: &str) -> usize {
+    let mut depth = 0;
+    let mut in_string = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            '"' if depth > 0 => in_string = !in_string,
+            _ if in_string => {}
+            '[' => depth += 1,
+            ']' if depth > 0 => depth -= 1,
+            c if depth == 0 && !(c.is_ascii_alphanumeric() || "_.*$".contains(c)) => return i,
+            _ => {}
+        }
+    }
+    s.len()
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+    fn next(&mut self) -> Result<Token> {
+        let t = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| anyhow!("filter ended unexpectedly"))?;
+        self.pos += 1;
+        Ok(t)
+    }
+    fn eat(&mut self, op: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Op(o)) if *o == op) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+    fn expect(&mut self, op: &str) -> Result<()> {
+        if !self.eat(op) {
+            bail!("expected {} but found {:?}", op, self.peek());
+        }
+        Ok(())
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut left = self.and()?;
+        while self.eat("||") {
+            left = Expr::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+    fn and(&mut self) -> Result<Expr> {
+        let mut left = self.unary()?;
+        while self.eat("&&") {
+            left = Expr::And(Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+    fn unary(&mut self) -> Result<Expr> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let e = self.or()?;
+            self.expect(")")?;
+            return Ok(e);
+        }
+        self.comparison()
+    }
+    fn comparison(&mut self) -> Result<Expr> {
+        let field = match self.next()? {
+            Token::Path(p) => Field::Parameter(Query::parse(&p)?),
+            Token::Ident(i) => match i.as_str() {
+                "dir" | "direction" => Field::Direction,
+                "kind" => Field::Kind,
+                "opcode" | "op" => Field::Opcode,
+                "event" => Field::Event,
+                "return_code" => Field::ReturnCode,
+                _ => bail!("unknown field {}", i),
+            },
+            t => bail!("expected a field but found {:?}", t),
+        };
+        if let Some(Token::Ident(i)) = self.peek() {
+            if i == "in" {
+                self.pos += 1;
+                self.expect("[")?;
+                let mut list = Vec::new();
+                if !self.eat("]") {
+                    loop {
+                        list.push(self.literal(&field)?);
+                        if self.eat("]") {
+                            break;
+                        }
+                        self.expect(",")?;
+                    }
+                }
+                return Ok(Expr::In(field, list));
+            }
+        }
+        let op = match self.peek() {
+            Some(Token::Op("==")) => CompareOp::Eq,
+            Some(Token::Op("!=")) => CompareOp::Ne,
+            Some(Token::Op("<")) => CompareOp::Lt,
+            Some(Token::Op("<=")) => CompareOp::Le,
+            Some(Token::Op(">")) => CompareOp::Gt,
+            Some(Token::Op(">=")) => CompareOp::Ge,
+            _ => {
+                return match field {
+                    Field::Parameter(q) => Ok(Expr::Exists(q)),
+                    f => bail!("{:?} needs a comparison", f),
+                }
+            }
+        };
+        self.pos += 1;
+        let literal = self.literal(&field)?;
+        if matches!(literal, Literal::Direction(_) | Literal::Kind(_))
+            && !matches!(op, CompareOp::Eq | CompareOp::Ne)
+        {
+            bail!("{:?} can only be compared with == or !=", field);
+        }
+        Ok(Expr::Compare(field, op, literal))
+    }
+    // bare words mean different things depending on the field they're compared with
+    fn literal(&mut self, field: &Field) -> Result<Literal> {
+        let token = self.next()?;
+        Ok(match (field, token) {
+            (Field::Direction, Token::Ident(i)) => Literal::Direction(match i.as_str() {
+                "in" | "incoming" => Direction::Incoming,
+                "out" | "outgoing" => Direction::Outgoing,
+                _ => bail!("unknown direction {}", i),
+            }),
+            (Field::Kind, Token::Ident(i)) => Literal::Kind(match i.as_str() {
+                "request" => MessageKind::Request,
+                "response" => MessageKind::Response,
+                "event" => MessageKind::Event,
+                _ => bail!("unknown message kind {}", i),
+            }),
+            (Field::Opcode, Token::Ident(i)) => Literal::Int(
+                WebServicesOpCode::from_str(&i).map_err(|_| anyhow!("unknown opcode {}", i))?
+                    as i64,
+            ),
+            (Field::Event, Token::Ident(i)) => Literal::Int(
+                registry::event_code(&i).ok_or_else(|| anyhow!("unknown event {}", i))? as i64,
+            ),
+            (Field::Parameter(_), Token::Ident(i)) if i == "true" || i == "false" => {
+                Literal::Bool(i == "true")
+            }
+            (Field::Parameter(_), Token::Ident(i) | Token::Str(i)) => Literal::Str(i),
+            (Field::Direction | Field::Kind, t) => bail!("unexpected {:?}", t),
+            (_, Token::Int(i)) => Literal::Int(i),
+            (Field::Parameter(_), Token::Float(f)) => Literal::Float(f),
+            (f, t) => bail!("can't compare {:?} with {:?}", f, t),
+        })
+    }
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+        let expr = parser.or()?;
+        if let Some(t) = parser.peek() {
+            bail!("unexpected {:?} in filter {}", t, s);
+        }
+        Ok(Self { expr })
+    }
+}
+
+impl Filter {
+    pub fn parse(s: &str) -> Result<Self> {
+        s.parse()
+    }
+    pub fn matches(&self, direction: Direction, message: &Message) -> bool {
+        eval(&self.expr, direction, message)
+    }
+    // Whether a captured message gets past the filter. Decode errors always do, the filter can't say whether
+    // they'd have matched and a decoder bug shouldn't go unnoticed because of a filter
+    pub fn passes(&self, m: &CapturedMessage) -> bool {
+        match &m.message {
+            Ok(message) => self.matches(m.direction, message),
+            Err(_) => true,
+        }
+    }
+}
+
+fn eval(expr: &Expr, direction: Direction, message: &Message) -> bool {
+    match expr {
+        Expr::And(a, b) => eval(a, direction, message) && eval(b, direction, message),
+        Expr::Or(a, b) => eval(a, direction, message) || eval(b, direction, message),
+        Expr::Not(e) => !eval(e, direction, message),
+        Expr::Exists(q) => !q.select_message(message).is_empty(),
+        Expr::Compare(field, op, literal) => field_values(field, direction, message)
+            .iter()
+            .any(|v| compare(*op, v.compare(literal))),
+        Expr::In(field, list) => field_values(field, direction, message)
+            .iter()
+            .any(|v| list.iter().any(|l| v.compare(l) == Some(Ordering::Equal))),
+    }
+}
+
+fn compare(op: CompareOp, ordering: Option<Ordering>) -> bool {
+    let Some(o) = ordering else {
+        return false;
+    };
+    match op {
+        CompareOp::Eq => o.is_eq(),
+        CompareOp::Ne => o.is_ne(),
+        CompareOp::Lt => o.is_lt(),
+        CompareOp::Le => o.is_le(),
+        CompareOp::Gt => o.is_gt(),
+        CompareOp::Ge => o.is_ge(),
+    }
+}
+
+// What a field evaluates to for a message. Empty if the message doesn't have it
+enum FieldValue {
+    Literal(Literal),
+    Value(Value),
+}
+
+impl FieldValue {
+    fn compare(&self, literal: &Literal) -> Option<Ordering> {
+        match self {
+            FieldValue::Literal(l) => compare_literals(l, literal),
+            FieldValue::Value(v) => compare_literals(&value_literal(v)?, literal),
+        }
+    }
+}
+
+fn compare_literals(a: &Literal, b: &Literal) -> Option<Ordering> {
+    match (a, b) {
+        (Literal::Int(a), Literal::Int(b)) => Some(a.cmp(b)),
+        (Literal::Int(a), Literal::Float(b)) => (*a as f64).partial_cmp(b),
+        (Literal::Float(a), Literal::Int(b)) => a.partial_cmp(&(*b as f64)),
+        (Literal::Float(a), Literal::Float(b)) => a.partial_cmp(b),
+        (Literal::Str(a), Literal::Str(b)) => Some(a.cmp(b)),
+        (Literal::Bool(a), Literal::Bool(b)) => Some(a.cmp(b)),
+        (Literal::Direction(a), Literal::Direction(b)) => Some(a.cmp(b)),
+        (Literal::Kind(a), Literal::Kind(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+fn value_literal(v: &Value) -> Option<Literal> {
+    Some(match v {
+        Value::Byte(b) => Literal::Int(*b as i64),
+        Value::Short(s) => Literal::Int(*s as i64),
+        Value::Int(i) => Literal::Int(*i as i64),
+        Value::Long(l) => Literal::Int(*l),
+        Value::Float(f) => Literal::Float(f.0 as f64),
+        Value::Double(d) => Literal::Float(d.0),
+        Value::Bool(b) => Literal::Bool(*b),
+        Value::String(s) => Literal::Str(s.clone()),
+        _ => return None,
+    })
+}
+
+fn field_values(field: &Field, direction: Direction, message: &Message) -> Vec<FieldValue> {
+    let key = schema_key(message);
+    let literal = match field {
+        Field::Direction => Some(Literal::Direction(direction)),
+        Field::Kind => key.map(|(kind, _, _)| Literal::Kind(kind)),
+        Field::Opcode => match key {
+            Some((MessageKind::Request | MessageKind::Response, code, _)) => {
+                Some(Literal::Int(code as i64))
+            }
+            _ => None,
+        },
+        Field::Event => match key {
+            Some((MessageKind::Event, code, _)) => Some(Literal::Int(code as i64)),
+            _ => None,
+        },
+        Field::ReturnCode => match message {
+            Message::OperationResponse(r) => Some(Literal::Int(r.return_code() as i64)),
+            _ => None,
+        },
+        Field::Parameter(q) => {
+            return q
+                .select_message(message)
+                .into_iter()
+                .map(|s| FieldValue::Value(s.value))
+                .collect()
+        }
+    };
+    literal.into_iter().map(FieldValue::Literal).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::Filter;
+    use crate::{
+        message::{Direction, Message},
+        serialization::{OperationRequest, OperationResponse, Value},
+    };
+
+    #[test]
+    fn test_filter() {
+        let request = Message::OperationRequest(OperationRequest::new(66, HashMap::new().into()));
+        let response = Message::OperationResponse(OperationResponse::new(
+            230,
+            -1,
+            Value::Null(()),
+            HashMap::from([(221, Value::Long(500)), (2, Value::String("x".into()))]).into(),
+        ));
+        let matches = |f: &str, dir, m: &Message| Filter::parse(f).unwrap().matches(dir, m);
+        let f = "dir == out && opcode in [LoadWallet, SaveRobotRequest]";
+        assert!(matches(f, Direction::Outgoing, &request));
+        assert!(!matches(f, Direction::Incoming, &response));
+        assert!(matches(
+            "return_code != 0 && params.Secret >= 100 && params[2] == \"x\"",
+            Direction::Incoming,
+            &response
+        ));
+        assert!(!matches("return_code != 0", Direction::Outgoing, &request));
+        assert!(matches(
+            "!(kind == response) || params[3]",
+            Direction::Outgoing,
+            &request
+        ));
+        assert!(!matches("params[3]", Direction::Incoming, &response));
+        assert!(Filter::parse("opcode == NotAnOpcode").is_err());
+        assert!(Filter::parse("dir > out").is_err());
+    }
+}
@@ -0,0 +1,179 @@
+use crate::message::Direction;
+
+// Splits a reassembled photon TCP stream into messages.
+// Every message is sent in a frame with a 7 byte header: 0xFB, the i32 BE length of the whole frame (header included)
+// and two bytes for channel and reliability flags. A 0xF0 at a frame boundary starts a ping, which next_message skips
+pub struct FrameReader {
+    buf: Vec<u8>,
+    skip_len: usize,
+    gaps: u64,
+    // false while scanning through something that isn't frames or pings, so a gap is only counted once. Pings are
+    // only recognised in step, out of step a 0xF0 is as likely to be part of the junk
+    in_step: bool,
+}
+pub const FRAME_HEADER_LEN: usize = 7;
+// Nothing the game sends comes close, a header claiming more is corrupt or out of step and would otherwise have the
+// reader buffer the rest of the connection waiting for it
+pub const MAX_FRAME_LEN: usize = 4 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    // starting at the 0xF3 byte
+    Message(Vec<u8>),
+    // 0xF0 and the sender's time. Client pings are answered with the server's time followed by the client's
+    Ping(Vec<u8>),
+}
+
+impl FrameReader {
+    pub fn new(direction: Direction) -> Self {
+        Self {
+            buf: Vec::with_capacity(1200),
+            gaps: 0,
+            in_step: true,
+            // server pings are 9 bytes, client pings 5
+            skip_len: match direction {
+                Direction::Incoming => 9,
+                Direction::Outgoing => 5,
+            },
+        }
+    }
+    // How often the stream had something other than a frame or ping where one should have started. The bytes are
+    // skipped up to the next 0xFB that looks like a frame header, which is how the reader gets back in step if
+    // the game itself doesn't
+    pub fn gaps(&self) -> u64 {
+        self.gaps
+    }
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+    // Returns the next complete message (starting at the 0xF3 byte), or None if more data is needed
+    pub fn next_message(&mut self) -> Option<Vec<u8>> {
+        loop {
+            if let Frame::Message(message) = self.next_frame()? {
+                return Some(message);
+            }
+        }
+    }
+    // Like next_message, but also returns pings for whoever has to answer them
+    pub fn next_frame(&mut self) -> Option<Frame> {
+        loop {
+            if self.buf.is_empty() {
+                return None;
+            }
+            if self.in_step && self.buf[0] == 0xF0 {
+                if self.buf.len() < self.skip_len {
+                    return None;
+                }
+                return Some(Frame::Ping(self.buf.drain(..self.skip_len).collect()));
+            }
+            match header(&self.buf) {
+                Header::Partial => return None,
+                Header::Frame(len) => {
+                    if self.buf.len() < len {
+                        return None;
+                    }
+                    self.in_step = true;
+                    let frame: Vec<u8> = self.buf.drain(..len).collect();
+                    return Some(Frame::Message(frame[FRAME_HEADER_LEN..].to_vec()));
+                }
+                Header::Not => {
+                    if self.in_step {
+                        self.gaps += 1;
+                    }
+                    self.in_step = false;
+                    let next = (1..self.buf.len())
+                        .find(|i| header(&self.buf[*i..]) != Header::Not)
+                        .unwrap_or(self.buf.len());
+                    self.buf.drain(..next);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Header {
+    // a frame of this length, header included
+    Frame(usize),
+    // could still be one, more bytes are needed to tell
+    Partial,
+    Not,
+}
+
+// Whether buf starts with a frame header: 0xFB, a length a frame could have and the 0xF3 starting the message.
+// Checking the 0xF3 as well makes it unlikely that junk passes for a header while out of step
+fn header(buf: &[u8]) -> Header {
+    if buf[0] != 0xFB {
+        return Header::Not;
+    }
+    let Some(len) = buf.get(1..5) else {
+        return Header::Partial;
+    };
+    let len = i32::from_be_bytes([len[0], len[1], len[2], len[3]]);
+    let Some(len) = usize::try_from(len)
+        .ok()
+        .filter(|len| (FRAME_HEADER_LEN..=MAX_FRAME_LEN).contains(len))
+    else {
+        return Header::Not;
+    };
+    match buf.get(FRAME_HEADER_LEN) {
+        _ if len == FRAME_HEADER_LEN => Header::Frame(len),
+        Some(0xF3) => Header::Frame(len),
+        Some(_) => Header::Not,
+        None => Header::Partial,
+    }
+}
+
+// Wraps a message (starting at 0xF3) in a frame header, as a reliable message on channel 0
+pub fn frame_message(message: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + message.len());
+    frame.push(0xFB);
+    frame.extend_from_slice(&((FRAME_HEADER_LEN + message.len()) as i32).to_be_bytes());
+    frame.extend_from_slice(&[0, 1]);
+    frame.extend_from_slice(message);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Frame, FrameReader};
+    use crate::message::Direction;
+
+    #[test]
+    fn test_split_frames_and_pings() {
+        let mut r = FrameReader::new(Direction::Incoming);
+        // ping, then a frame split over two pushes, then a second frame in the same push
+        r.push(&[0xF0, 0, 0, 0, 1, 0, 0, 0, 2]);
+        r.push(&[0xFB, 0, 0, 0, 10, 0, 1, 0xF3]);
+        assert_eq!(r.next_message(), None);
+        r.push(&[4, 1, 0xFB, 0, 0, 0, 9, 0, 1, 0xF3, 3]);
+        assert_eq!(r.next_message(), Some(vec![0xF3, 4, 1]));
+        assert_eq!(r.next_message(), Some(vec![0xF3, 3]));
+        assert_eq!(r.next_message(), None);
+        assert_eq!(r.gaps(), 0);
+        // a lost packet: the rest of a frame is one gap
+        r.push(&[0x11; 36]);
+        r.push(&[0xFB, 0, 0, 0, 9, 0, 1, 0xF3, 5]);
+        assert_eq!(r.next_message(), Some(vec![0xF3, 5]));
+        assert_eq!(r.gaps(), 1);
+        r.push(&[0x22; 9]);
+        r.push(&[0xFB, 0, 0, 0, 9, 0, 1, 0xF3, 6]);
+        assert_eq!(r.next_message(), Some(vec![0xF3, 6]));
+        assert_eq!(r.gaps(), 2);
+        // a header claiming far more than any frame is a gap too, not something to wait for
+        r.push(&[0xFB, 0x7F, 0xFF, 0xFF, 0xFF, 0, 1, 0xF3, 0]);
+        r.push(&[0xFB, 0, 0, 0, 9, 0, 1, 0xF3, 7]);
+        assert_eq!(r.next_message(), Some(vec![0xF3, 7]));
+        assert_eq!(r.gaps(), 3);
+        // junk that isn't a multiple of a ping's length, with a stray 0xF0 and 0xFB in it that don't get the reader
+        // back in step. The frame right after it isn't lost
+        let mut junk = vec![0x33; 35];
+        junk[9] = 0xF0;
+        junk[20] = 0xFB;
+        r.push(&junk);
+        r.push(&[0xFB, 0, 0, 0, 9, 0, 1, 0xF3, 8]);
+        assert_eq!(r.next_frame(), Some(Frame::Message(vec![0xF3, 8])));
+        assert_eq!(r.gaps(), 4);
+        assert_eq!(r.next_frame(), None);
+    }
+}
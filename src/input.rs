@@ -0,0 +1,140 @@
+use anyhow::{bail, Context, Result};
+use base64::Engine;
+
+use crate::framing::FRAME_HEADER_LEN;
+
+// Turning pasted bytes back into messages, e.g. an "Erroring Request" dump from the live output
+
+// Accepts "f3 02 42", "f30242", "0xf3, 0x02" and the `{:x?}` output this tool prints ("[f3, 2, 42]")
+pub fn parse_hex(text: &str) -> Result<Vec<u8>> {
+    let text = text.trim().trim_start_matches('[').trim_end_matches(']');
+    let mut bytes = Vec::new();
+    for token in text.split(|c: char| c == ',' || c.is_whitespace()) {
+        let digits = token.trim_start_matches("0x");
+        // a lone digit is a whole byte in `{:x?}` output, longer runs are pairs of digits
+        if digits.len() == 1 {
+            bytes.push(
+                u8::from_str_radix(digits, 16)
+                    .with_context(|| format!("{:?} is not hex", token))?,
+            );
+            continue;
+        }
+        if digits.len() % 2 != 0 {
+            bail!("odd number of hex digits in {:?}", token);
+        }
+        for i in (0..digits.len()).step_by(2) {
+            let pair = digits
+                .get(i..i + 2)
+                .context("hex digits have to be ascii")?;
+            bytes.push(
+                u8::from_str_radix(pair, 16).with_context(|| format!("{:?} is not hex", pair))?,
+            );
+        }
+    }
+    Ok(bytes)
+}
+
+pub fn parse_base64(text: &str) -> Result<Vec<u8>> {
+    let text: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    base64::engine::general_purpose::STANDARD
+        .decode(text)
+        .context("invalid base64")
+}
+
+// What the live output puts in front of the bytes it dumps, pasting the whole line (or the whole error) works
+const LABELS: [&str; 2] = ["Erroring Request:", "Raw:"];
+
+fn strip_label(text: &str) -> &str {
+    for line in text.lines() {
+        for label in LABELS {
+            if let Some(bytes) = line.trim().strip_prefix(label) {
+                return bytes;
+            }
+        }
+    }
+    text
+}
+
+// Hex if it only has hex digits and separators in it, base64 otherwise
+pub fn parse_text(text: &str) -> Result<Vec<u8>> {
+    let text = strip_label(text);
+    let looks_like_hex = text
+        .chars()
+        .all(|c| c.is_ascii_hexdigit() || c.is_whitespace() || ",[]x".contains(c));
+    if looks_like_hex {
+        parse_hex(text)
+    } else {
+        parse_base64(text)
+    }
+}
+
+// Splits bytes into messages starting at 0xF3. Either one bare message, or one or more frames with the 0xFB header
+pub fn split_messages(bytes: &[u8]) -> Result<Vec<Vec<u8>>> {
+    match bytes.first() {
+        None => bail!("no bytes to decode"),
+        Some(0xF3) => return Ok(vec![bytes.to_vec()]),
+        Some(0xFB) => {}
+        Some(b) => bail!(
+            "expected a 0xfb frame header or a message starting with 0xf3, found {:#04x}",
+            b
+        ),
+    }
+    let mut messages = Vec::new();
+    let mut rest = bytes;
+    let mut offset = 0;
+    while !rest.is_empty() {
+        if rest[0] != 0xFB {
+            bail!(
+                "expected a 0xfb frame header at byte {}, found {:#04x}",
+                offset,
+                rest[0]
+            );
+        }
+        if rest.len() < FRAME_HEADER_LEN {
+            bail!("frame header at byte {} is cut off", offset);
+        }
+        let len = i32::from_be_bytes([rest[1], rest[2], rest[3], rest[4]]);
+        if len < FRAME_HEADER_LEN as i32 {
+            bail!("frame at byte {} has an invalid length {}", offset, len);
+        }
+        let len = len as usize;
+        if rest.len() < len {
+            bail!(
+                "frame at byte {} says it is {} bytes long but only {} are left",
+                offset,
+                len,
+                rest.len()
+            );
+        }
+        messages.push(rest[FRAME_HEADER_LEN..len].to_vec());
+        rest = &rest[len..];
+        offset += len;
+    }
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::{parse_text, split_messages};
+    use crate::output::{format_error, OutputFormat};
+
+    #[test]
+    fn test_pasted_frames() {
+        let bytes =
+            parse_text("[fb, 0, 0, 0, 9, 0, 1, f3, 2, fb, 0, 0, 0, 9, 0, 1, f3, 3]").unwrap();
+        assert_eq!(
+            split_messages(&bytes).unwrap(),
+            vec![vec![0xF3, 2], vec![0xF3, 3]]
+        );
+        assert_eq!(parse_text("f302 4200").unwrap(), vec![0xF3, 2, 0x42, 0]);
+        assert_eq!(parse_text("8wJCAAA=").unwrap(), vec![0xF3, 2, 0x42, 0, 0]);
+        assert!(split_messages(&[0xFB, 0, 0, 0, 20, 0, 1, 0xF3]).is_err());
+        // an error the way the live output prints it, both lines or just the second one
+        let raw = [0xF3, 2, 0x42, 0x0a, 0xff];
+        let error = format_error(&anyhow!("bad"), &raw, OutputFormat::Pretty);
+        assert_eq!(parse_text(&error).unwrap(), raw);
+        assert_eq!(parse_text(error.lines().last().unwrap()).unwrap(), raw);
+    }
+}
@@ -1,3 +1,29 @@
+pub mod api;
+pub mod capture;
+pub mod client;
 pub mod encryption;
+pub mod filter;
+pub mod framing;
+pub mod input;
+pub mod message;
+pub mod metrics;
+pub mod output;
+pub mod pairing;
+pub mod pcapng;
+pub mod pipeline;
+pub mod query;
+pub mod reassembly;
+pub mod recording;
+pub mod registry;
+pub mod replay;
+pub mod schema;
+pub mod script;
 pub mod serialization;
+pub mod server;
+pub mod spans;
+pub mod sqlite;
+pub mod stats;
+pub mod tui;
+pub mod typed;
 pub mod util;
+pub mod value_diff;
@@ -1,238 +1,729 @@
-use std::io::{self, Cursor, Seek, Write};
-use std::task::Poll;
-use std::{env, process};
-
-use pnet::datalink::{self, Channel};
-use pnet::packet::ethernet::EthernetPacket;
-
-use pnet::packet::ipv4::Ipv4Packet;
-use pnet::packet::tcp::TcpPacket;
-use pnet::packet::Packet;
-use rcsniff2::serialization::op_code::MessageType;
-use rcsniff2::serialization::StreamDeserializer;
-use tokio::io::{AsyncRead, AsyncReadExt};
-use tokio::spawn;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use std::fs::File;
+use std::future::{pending, Future};
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::pin::pin;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use clap::{Args, Parser, Subcommand};
+use pnet::datalink;
+
+use rcsniff2::api::{self, Api};
+use rcsniff2::capture::{self, WEBSERVICES_PORT};
+use rcsniff2::filter::Filter;
+use rcsniff2::input;
+use rcsniff2::message::{decode_message_with_len, Direction, Message};
+use rcsniff2::metrics::{self, Metrics};
+use rcsniff2::output::{format_error, format_message, OutputFormat};
+use rcsniff2::pcapng::PcapngWriter;
+use rcsniff2::pipeline::{
+    annotated, print_response_diff, Filtered, Input, Pipeline, PipelineSender, PrintOptions,
+    Printer,
+};
+use rcsniff2::query::Query;
+use rcsniff2::recording::RecordingWriter;
+use rcsniff2::registry::{self, ParameterRegistry};
+use rcsniff2::replay::{self, ReplayOptions, Substitution};
+use rcsniff2::schema::validate::SchemaValidator;
+use rcsniff2::schema::{codegen, diff::diff_schemas, infer::infer_capture, Schema};
+use rcsniff2::script::Scripts;
+use rcsniff2::server::{self, CannedResponses};
+use rcsniff2::sqlite::SqliteWriter;
+use rcsniff2::stats::Stats;
+use rcsniff2::tui;
+use rcsniff2::value_diff::ResponseDiffer;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::oneshot;
+use tokio::task::spawn_blocking;
+use tokio::time::{interval_at, Instant};
+use tokio::{select, signal, spawn};
+
+// doc comments on the clap types below are the --help text
+/// sniff and deserialize (most) RC WebServicesServer requests
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(flatten)]
+    global: GlobalArgs,
+    // no subcommand is the same as `live` on the default interface
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Args, Clone)]
+struct GlobalArgs {
+    /// How messages are printed: pretty, compact or json
+    #[arg(long, global = true, default_value = "pretty")]
+    format: OutputFormat,
+    /// Only show messages matching this filter, e.g. 'dir == out && opcode in [LoadWallet]'
+    #[arg(long, global = true)]
+    filter: Option<String>,
+    /// Only print the parts of each message matching this query, e.g. 'params[245].robots[*].name'
+    #[arg(long, global = true)]
+    select: Option<String>,
+    /// WebServices server port
+    #[arg(long, global = true, default_value_t = WEBSERVICES_PORT)]
+    port: u16,
+    /// Extra parameter names (toml or json), loaded on top of the builtin ones and ./parameters.toml
+    #[arg(long, global = true)]
+    parameters: Option<PathBuf>,
+    /// Also print the raw bytes of every message, -vv as a hex dump labelled with what each byte was decoded as
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// Don't print decode errors
+    #[arg(short, long, global = true)]
+    quiet: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Sniff traffic on a network interface
+    Live {
+        /// Network interface, defaults to the system's default interface
+        interface: Option<String>,
+        #[command(flatten)]
+        decode: DecodeArgs,
+    },
+    /// Decode a pcap/pcapng capture or a recording
+    Read {
+        capture: PathBuf,
+        #[command(flatten)]
+        decode: DecodeArgs,
+    },
+    /// Sit between the game and the server, forwarding and decoding everything
+    Proxy {
+        /// Address the game connects to
+        #[arg(long, default_value = "127.0.0.1:4533")]
+        listen: String,
+        /// The real WebServices server
+        #[arg(long)]
+        upstream: String,
+        #[command(flatten)]
+        decode: DecodeArgs,
+    },
+    /// Pretend to be the WebServices server, answering requests with canned responses
+    Serve {
+        /// Recording or capture to take the responses from, or a .toml/.json response rule file
+        responses: PathBuf,
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:4533")]
+        listen: String,
+        #[command(flatten)]
+        decode: DecodeArgs,
+    },
+    /// Infer, generate code from or validate against schemas
+    #[command(subcommand)]
+    Schema(SchemaCommand),
+    /// Compare two captures or schemas, or consecutive responses within one capture
+    Diff {
+        /// Old capture or schema
+        #[arg(required_unless_present = "responses")]
+        old: Option<PathBuf>,
+        /// New capture or schema
+        #[arg(required_unless_present = "responses")]
+        new: Option<PathBuf>,
+        /// Diff consecutive responses to the same opcode in this capture instead
+        #[arg(long, conflicts_with_all = ["old", "new"])]
+        responses: Option<PathBuf>,
+    },
+    /// Decode messages pasted as hex (e.g. an "Erroring Request" line) or base64, or read from a file
+    Decode {
+        /// Hex or base64, read from stdin if not given
+        input: Option<String>,
+        /// Read raw bytes from this file instead
+        #[arg(long, conflicts_with = "input")]
+        file: Option<PathBuf>,
+        /// Treat the input as base64 even if it looks like hex
+        #[arg(long)]
+        base64: bool,
+    },
+    /// Send the client's requests from a recording or capture to a server again and compare the responses
+    Replay {
+        capture: PathBuf,
+        /// Server to send them to, host:port
+        #[arg(long)]
+        target: String,
+        /// Replace a request parameter before sending, as [Opcode.]Parameter=value, e.g. 'LoadWallet.Token=abc'.
+        /// Can be given more than once
+        #[arg(long = "set")]
+        substitutions: Vec<String>,
+        /// Send requests as fast as possible instead of at the recorded pace
+        #[arg(long)]
+        fast: bool,
+        /// Recorded connection to replay, defaults to the first one
+        #[arg(long)]
+        connection: Option<u32>,
+        /// Seconds to wait for a response before giving up on the rest
+        #[arg(long, default_value_t = 5)]
+        timeout: u64,
+    },
+    /// List network interfaces
+    ListInterfaces,
+}
+
+#[derive(Subcommand)]
+enum SchemaCommand {
+    /// Infer a schema from a capture
+    Infer {
+        capture: PathBuf,
+        /// Where to write the schema, stdout if not given
+        out: Option<PathBuf>,
+    },
+    /// Generate Rust bindings from a schema
+    Codegen {
+        schema: PathBuf,
+        /// Where to write the code, stdout if not given
+        out: Option<PathBuf>,
+        /// Path the generated code uses to refer to this crate
+        #[arg(long, default_value = "rcsniff2")]
+        crate_path: String,
+    },
+    /// Check every message of a capture against a schema
+    Validate { schema: PathBuf, capture: PathBuf },
+}
+
+#[derive(Args, Clone, Default)]
+struct DecodeArgs {
+    /// Print a `Schema Drift:` record whenever a message doesn't match this schema
+    #[arg(long)]
+    schema: Option<PathBuf>,
+    /// Print what changed between consecutive responses to the same opcode
+    #[arg(long)]
+    diff_responses: bool,
+    /// Save every message to a recording that `read` and the other capture commands can open
+    #[arg(long)]
+    record: Option<PathBuf>,
+    /// Export connections, frames, messages and their parameters to this SQLite database, replacing its tables
+    #[arg(long)]
+    sqlite: Option<PathBuf>,
+    /// Write the messages to a pcapng for Wireshark, each with a comment saying what it decoded to
+    #[arg(long)]
+    pcapng: Option<PathBuf>,
+    /// Run this Rhai script's message handlers on every message, can be given more than once
+    #[arg(long)]
+    script: Vec<PathBuf>,
+    /// Browse the messages in an interactive terminal UI instead of printing them
+    #[arg(long)]
+    tui: bool,
+    /// Serve Prometheus metrics at /metrics on this address, 127.0.0.1:9184 if none is given
+    #[arg(long, num_args = 0..=1, default_missing_value = "127.0.0.1:9184")]
+    metrics: Option<String>,
+    /// Serve decoded messages as JSON over a websocket or server-sent events, and the recent messages and
+    /// connections, on this address (127.0.0.1:9185 if none is given)
+    #[arg(long, num_args = 0..=1, default_missing_value = "127.0.0.1:9185")]
+    api: Option<String>,
+    /// Print traffic statistics when done (or on ctrl-c) instead of the messages
+    #[arg(long, conflicts_with = "tui")]
+    stats: bool,
+    /// Also print the statistics every this many seconds
+    #[arg(long, requires = "stats")]
+    stats_interval: Option<u64>,
+    /// Write the statistics to this file as JSON whenever they're printed
+    #[arg(long, requires = "stats")]
+    stats_json: Option<PathBuf>,
+}
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 3)]
 async fn main() {
-    {
-        let mut l = io::stdout().lock();
-        writeln!(l, "rcsniff2 [optional network interface name]").unwrap();
-        writeln!(
-            l,
-            "valid interfaces: {:#?}",
-            datalink::interfaces()
-                .into_iter()
-                .map(|i| i.name)
-                .collect::<Vec<_>>()
-        )
-        .unwrap();
-    }
-
-    let Some(iface_name) = env::args()
-        .nth(1)
-        .or(netdev::get_default_interface().ok().map(|iface| iface.name))
-    else {
+    let cli = Cli::parse();
+    if let Err(e) = run(cli).await {
         let mut l = io::stderr().lock();
-        writeln!(l, "failed to get default interface name and you haven't specified one. Please specify the network interface to use").unwrap();
+        writeln!(l, "error: {:#}", e).unwrap();
         process::exit(1);
-    };
+    }
+}
 
-    let incoming_packet_tx = make_incoming_handler();
-    let outgoing_packet_tx = make_outgoing_handler();
-    let int = datalink::interfaces()
-        .into_iter()
-        .filter(|s| s.name == iface_name)
-        .next()
-        .unwrap();
-    let Ok(Channel::Ethernet(_, mut rx)) = datalink::channel(&int, Default::default()) else {
-        panic!("could not create channel listener")
-    };
-    loop {
-        if let Ok(packet) = rx.next() {
-            if let Some(ipv4) = Ipv4Packet::new(EthernetPacket::new(packet).unwrap().payload()) {
-                if let Some(tcp) = TcpPacket::new(ipv4.payload()) {
-                    if tcp.get_source() == 4533 {
-                        let v = tcp.payload().to_vec();
-                        incoming_packet_tx.send(v).unwrap();
-                    }
-                    if tcp.get_destination() == 4533 {
-                        let v = tcp.payload().to_vec();
-                        outgoing_packet_tx.send(v).unwrap();
-                    }
+async fn run(cli: Cli) -> anyhow::Result<()> {
+    let global = cli.global;
+    let mut registry = ParameterRegistry::builtin();
+    for extra in ["parameters.toml", "parameters.json"] {
+        if Path::new(extra).exists() {
+            registry.load_file(extra)?;
+        }
+    }
+    if let Some(path) = &global.parameters {
+        registry.load_file(path)?;
+    }
+    registry::install(registry);
+
+    let command = cli.command.unwrap_or(Command::Live {
+        interface: None,
+        decode: DecodeArgs::default(),
+    });
+    match command {
+        Command::Live { interface, decode } => {
+            decoding(&global, &decode, |sender| async move {
+                // the pnet loop blocks forever, it gets a thread of its own so the ui can still quit
+                let (tx, rx) = oneshot::channel();
+                thread::spawn(move || tx.send(sender.sniff(interface)));
+                rx.await?
+            })
+            .await
+        }
+        Command::Read { capture, decode } => {
+            decoding(&global, &decode, |sender| async move {
+                sender.read_file(&capture)
+            })
+            .await
+        }
+        Command::Proxy {
+            listen,
+            upstream,
+            decode,
+        } => {
+            decoding(&global, &decode, |sender| async move {
+                proxy(&listen, &upstream, sender).await
+            })
+            .await
+        }
+        Command::Serve {
+            responses,
+            listen,
+            decode,
+        } => {
+            let port = global.port;
+            decoding(&global, &decode, move |sender| async move {
+                serve(&responses, &listen, port, sender).await
+            })
+            .await
+        }
+        Command::Schema(command) => schema_command(command, &global),
+        Command::Diff {
+            old,
+            new,
+            responses,
+        } => diff_command(old, new, responses, &global),
+        Command::Decode {
+            input,
+            file,
+            base64,
+        } => decode_command(input, file, base64, &global),
+        Command::Replay {
+            capture,
+            target,
+            substitutions,
+            fast,
+            connection,
+            timeout,
+        } => {
+            let options = ReplayOptions {
+                substitutions: substitutions
+                    .iter()
+                    .map(|s| s.parse::<Substitution>())
+                    .collect::<anyhow::Result<_>>()
+                    .context("invalid --set")?,
+                fast,
+                connection,
+                timeout: Duration::from_secs(timeout),
+            };
+            let messages = capture::read_messages(&capture, global.port)?;
+            let report = replay::replay(&messages, &target, &options).await?;
+            print!("{}", report.render(io::stdout().is_terminal()));
+            if report.differences() > 0 {
+                bail!(
+                    "{} of {} responses differ from the recording",
+                    report.differences(),
+                    report.responses.len()
+                );
+            }
+            Ok(())
+        }
+        Command::ListInterfaces => {
+            let default = netdev::get_default_interface().ok().map(|i| i.name);
+            for i in datalink::interfaces() {
+                if Some(&i.name) == default.as_ref() {
+                    println!("{} (default)", i.name);
+                } else {
+                    println!("{}", i.name);
                 }
             }
+            Ok(())
         }
     }
 }
 
-fn make_incoming_handler() -> UnboundedSender<Vec<u8>> {
-    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
-    let (packet_tx, packet_rx) = unbounded_channel();
-    spawn(incoming_reciever_thread(tx, ByteReciever::new(packet_rx)));
-    spawn(handler_thread(rx));
-    packet_tx
+fn print_options(global: &GlobalArgs, decode: &DecodeArgs) -> anyhow::Result<PrintOptions> {
+    let validator = match &decode.schema {
+        Some(path) => Some(SchemaValidator::new(Schema::load(path)?)),
+        None => None,
+    };
+    Ok(PrintOptions {
+        validator,
+        diff_responses: decode.diff_responses,
+        select: global
+            .select
+            .as_deref()
+            .map(Query::parse)
+            .transpose()
+            .context("invalid --select")?,
+        format: global.format,
+        verbose: global.verbose > 0,
+        annotate: global.verbose > 1,
+        quiet: global.quiet,
+    })
 }
 
-fn make_outgoing_handler() -> UnboundedSender<Vec<u8>> {
-    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
-    let (packet_tx, packet_rx) = unbounded_channel();
-    spawn(outgoing_reciever_thread(tx, ByteReciever::new(packet_rx)));
-    spawn(handler_thread(rx));
-    packet_tx
-}
-pub struct ByteReciever {
-    buf: Vec<u8>,
-    rx: UnboundedReceiver<Vec<u8>>,
+// Runs a command that decodes messages, with a pipeline sending them wherever the options say. With --tui they go
+// to the terminal ui instead of being printed, and the command runs until the ui is closed
+async fn decoding<F, Fut>(
+    global: &GlobalArgs,
+    decode: &DecodeArgs,
+    command: F,
+) -> anyhow::Result<()>
+where
+    F: FnOnce(PipelineSender) -> Fut + Send + 'static,
+    Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+{
+    let filter = global
+        .filter
+        .as_deref()
+        .map(Filter::parse)
+        .transpose()
+        .context("invalid --filter")?;
+    let mut pipeline = Pipeline::new(global.port);
+    // everything that keeps or passes on the whole session comes first and ignores the filter
+    if let Some(path) = &decode.record {
+        pipeline.add_sink(RecordingWriter::create(path)?);
+    }
+    if let Some(path) = &decode.sqlite {
+        pipeline.add_sink(SqliteWriter::create(path)?);
+    }
+    if let Some(path) = &decode.pcapng {
+        pipeline.add_sink(PcapngWriter::create(path, global.port)?);
+    }
+    if let Some(addr) = &decode.metrics {
+        let metrics = Arc::new(Metrics::new());
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("could not listen on {} for metrics", addr))?;
+        let serving = metrics::serve(listener, metrics.clone());
+        spawn(async move {
+            if let Err(e) = serving.await {
+                eprintln!("metrics server stopped: {:#}", e);
+            }
+        });
+        pipeline.set_metrics(metrics.clone());
+        pipeline.add_sink(metrics);
+    }
+    if let Some(addr) = &decode.api {
+        let api = Arc::new(Api::new());
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("could not listen on {} for the api", addr))?;
+        let serving = api::serve(listener, api.clone());
+        spawn(async move {
+            if let Err(e) = serving.await {
+                eprintln!("api server stopped: {:#}", e);
+            }
+        });
+        pipeline.add_sink(api);
+    }
+    // handlers see every message, they do their own filtering
+    if !decode.script.is_empty() {
+        pipeline.add_sink(Scripts::load(&decode.script)?);
+    }
+    // with --stats messages are only counted
+    let stats = decode.stats.then(|| Arc::new(Mutex::new(Stats::default())));
+    let mut tui = None;
+    if let Some(stats) = &stats {
+        pipeline.add_sink(Filtered::new(filter.clone(), stats.clone()));
+    } else if decode.tui {
+        let (tx, rx) = unbounded_channel();
+        pipeline.add_sink(tx);
+        tui = Some(rx);
+    } else {
+        let printer = Printer::new(print_options(global, decode)?);
+        pipeline.add_sink(Filtered::new(filter.clone(), printer));
+    }
+    let (sender, pipeline) = pipeline.spawn();
+    // done once everything the command sent has been through the pipeline
+    let job = async move {
+        command(sender).await?;
+        pipeline.await?;
+        Ok(())
+    };
+    if let Some(stats) = stats {
+        return with_stats(stats, decode, job).await;
+    }
+    let Some(rx) = tui else {
+        return job.await;
+    };
+    // the ui does the filtering itself so the filter can be changed while it's running
+    let job = spawn(job);
+    spawn_blocking(move || tui::run(rx, filter)).await??;
+    if job.is_finished() {
+        job.await??;
+    } else {
+        job.abort();
+    }
+    Ok(())
 }
-impl ByteReciever {
-    pub fn new(rx: UnboundedReceiver<Vec<u8>>) -> Self {
-        Self {
-            buf: Vec::with_capacity(100),
-            rx,
-        }
+
+async fn proxy(listen: &str, upstream: &str, sender: PipelineSender) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(listen)
+        .await
+        .with_context(|| format!("could not listen on {}", listen))?;
+    let mut next_connection = 0u32;
+    loop {
+        let (client, addr) = listener.accept().await?;
+        let connection = next_connection;
+        next_connection = next_connection.wrapping_add(1);
+        let server = match TcpStream::connect(upstream).await {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("could not connect to {} for {}: {}", upstream, addr, e);
+                continue;
+            }
+        };
+        let (client_read, client_write) = client.into_split();
+        let (server_read, server_write) = server.into_split();
+        let outgoing = (sender.clone(), connection, Direction::Outgoing);
+        let incoming = (sender.clone(), connection, Direction::Incoming);
+        let sending = spawn(forward(client_read, server_write, outgoing));
+        let receiving = spawn(forward(server_read, client_write, incoming));
+        let sender = sender.clone();
+        spawn(async move {
+            let _ = sending.await;
+            let _ = receiving.await;
+            let _ = sender.send(Input::Closed(connection));
+        });
     }
 }
-impl AsyncRead for ByteReciever {
-    fn poll_read(
-        mut self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-        buf: &mut tokio::io::ReadBuf<'_>,
-    ) -> std::task::Poll<io::Result<()>> {
-        let mut p = self.rx.poll_recv(cx);
-        loop {
-            match p {
-                Poll::Ready(v) => {
-                    if v.is_some() && v.as_ref().map(|v| v.len()).unwrap() != 0 {
-                        self.buf.extend(v.unwrap().into_iter());
-                        break;
-                    } else {
-                        p = self.rx.poll_recv(cx);
-                    }
+
+// Runs the command until it's done or ctrl-c is pressed, printing the statistics every --stats-interval and at the end
+async fn with_stats(
+    stats: Arc<Mutex<Stats>>,
+    decode: &DecodeArgs,
+    command: impl Future<Output = anyhow::Result<()>>,
+) -> anyhow::Result<()> {
+    let report = || -> anyhow::Result<()> {
+        let report = stats.lock().unwrap().report();
+        print!("{}", report.render());
+        if let Some(path) = &decode.stats_json {
+            std::fs::write(path, serde_json::to_string_pretty(&report)?)
+                .with_context(|| format!("could not write {}", path.display()))?;
+        }
+        Ok(())
+    };
+    let mut ticks = decode.stats_interval.map(|secs| {
+        let every = Duration::from_secs(secs.max(1));
+        interval_at(Instant::now() + every, every)
+    });
+    let mut command = pin!(command);
+    let res = loop {
+        select! {
+            res = &mut command => break res,
+            _ = signal::ctrl_c() => break Ok(()),
+            _ = async {
+                match &mut ticks {
+                    Some(t) => t.tick().await,
+                    None => pending().await,
                 }
-                Poll::Pending => return Poll::Pending,
+            } => {
+                report()?;
+                println!();
             }
         }
-        if self.buf.len() != 0 {
-            let to_copy = buf.remaining().min(self.buf.len());
-            let buf2 = self.buf.split_off(to_copy);
-            buf.put_slice(&self.buf);
-            self.buf = buf2;
-            cx.waker().wake_by_ref();
-            Poll::Ready(Ok(()))
-        } else {
-            Poll::Pending
+    };
+    report()?;
+    res
+}
+
+async fn serve(
+    responses: &Path,
+    listen: &str,
+    port: u16,
+    sender: PipelineSender,
+) -> anyhow::Result<()> {
+    let responses = if responses
+        .extension()
+        .is_some_and(|e| e == "toml" || e == "json")
+    {
+        CannedResponses::load_rules(responses)?
+    } else {
+        CannedResponses::from_session(&capture::read_messages(responses, port)?)
+    };
+    let listener = TcpListener::bind(listen)
+        .await
+        .with_context(|| format!("could not listen on {}", listen))?;
+    // the server decodes (and decrypts) everything itself
+    let (tx, mut rx) = unbounded_channel();
+    spawn(async move {
+        while let Some(m) = rx.recv().await {
+            if sender.send(Input::Message(m)).is_err() {
+                return;
+            }
         }
-    }
+    });
+    server::serve(listener, Arc::new(responses), Some(tx)).await
 }
-async fn incoming_reciever_thread(
-    sender: UnboundedSender<Vec<u8>>,
-    mut recv: impl AsyncReadExt + Unpin,
-) {
-    let mut buf = Vec::with_capacity(1200);
 
+async fn forward(
+    mut from: tokio::net::tcp::OwnedReadHalf,
+    mut to: tokio::net::tcp::OwnedWriteHalf,
+    (sender, connection, direction): (PipelineSender, u32, Direction),
+) {
+    let mut buf = vec![0; 65536];
     loop {
-        buf.clear();
-        let mut arr1 = [0u8; 9];
-        let Ok(_) = recv.read_exact(&mut arr1).await else {
-            break;
+        let n = match from.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
         };
-        let arr2 = [arr1[1], arr1[2], arr1[3], arr1[4]];
-        if arr1[0] == 0xFB {
-            let mut total_packet_len = i32::from_be_bytes(arr2);
-            buf.extend_from_slice(&arr1[7..]);
-            total_packet_len -= 9;
-            let mut buf2 = vec![0u8; total_packet_len as usize];
-            recv.read_exact(&mut buf2).await.unwrap();
-            buf.extend_from_slice(&buf2);
-            sender.send(buf.clone()).unwrap();
+        if to.write_all(&buf[..n]).await.is_err() {
+            break;
         }
+        let _ = sender.stream(connection, direction, buf[..n].to_vec());
     }
+    let _ = to.shutdown().await;
 }
-async fn outgoing_reciever_thread(
-    sender: UnboundedSender<Vec<u8>>,
-    mut recv: impl AsyncReadExt + Unpin,
-) {
-    let mut buf = Vec::with_capacity(1200);
 
-    loop {
-        buf.clear();
-        let mut arr1 = [0u8; 5];
-        let Ok(_) = recv.read_exact(&mut arr1).await else {
-            break;
-        };
-        let arr2 = [arr1[1], arr1[2], arr1[3], arr1[4]];
-
-        if arr1[0] == 0xFB {
-            let mut total_packet_len = i32::from_be_bytes(arr2);
-            total_packet_len -= 5;
-            let mut buf2 = vec![0u8; total_packet_len as usize];
-            recv.read_exact(&mut buf2).await.unwrap();
-            buf.extend_from_slice(&buf2[2..]);
-            sender.send(buf.clone()).unwrap();
-        }
-    }
-}
-pub async fn handler_thread(mut rec: UnboundedReceiver<Vec<u8>>) {
-    while let Some(buf) = rec.recv().await {
-        if buf[0] != 0xF3 {
-            continue;
-        }
-        let code = buf[1];
-        let encflag = (code & 128) != 0;
-        if encflag {
-            println!("ERROR: encountered encrypted packet. This program cannot decrypt encrypted packets");
-            continue;
-        }
-        let b2 = buf.clone();
-        let mut buf = Cursor::new(&buf[..]);
-        buf.seek(std::io::SeekFrom::Start(2)).unwrap();
-
-        let msg_type = code & 127;
-        let mut des = StreamDeserializer::new(buf);
-        match MessageType::from_repr(msg_type).unwrap() {
-            MessageType::Init => {
-                // init request
+fn schema_command(command: SchemaCommand, global: &GlobalArgs) -> anyhow::Result<()> {
+    match command {
+        SchemaCommand::Infer { capture, out } => {
+            let schema = infer_capture(capture, global.port)?;
+            match out {
+                Some(path) => schema.save(path)?,
+                None => println!("{}", schema.to_json()?),
             }
-            MessageType::InitResponse => { //Init response
-            }
-            MessageType::Operation => {
-                //Operation Request
-                let res = des.deserialize_operation_request();
-                println!("Operation Request: {:#?}", res);
-                if res.is_err() {
-                    println!("Erroring Request: {:x?}", b2);
-                }
+        }
+        SchemaCommand::Codegen {
+            schema,
+            out,
+            crate_path,
+        } => {
+            let code = codegen::generate(&Schema::load(schema)?, &crate_path);
+            match out {
+                Some(path) => std::fs::write(&path, code)
+                    .with_context(|| format!("could not write {}", path.display()))?,
+                None => print!("{}", code),
             }
-            MessageType::OperationResponse => {
-                //Operation response
-                let res = des.deserialize_operation_response();
-                println!("Operation Response: {:#?}", res);
-                if res.is_err() {
-                    println!("Erroring Request: {:x?}", b2);
+        }
+        SchemaCommand::Validate { schema, capture } => {
+            let validator = SchemaValidator::new(Schema::load(schema)?);
+            for m in capture::read_messages(capture, global.port)? {
+                if let Ok(message) = &m.message {
+                    for drift in validator.validate(m.direction, message) {
+                        println!("{}", serde_json::to_string(&drift)?);
+                    }
                 }
             }
-            MessageType::Event => {
-                //EventData
-                println!("Event Data: {:#?}", des.deserialize_event_data());
+        }
+    }
+    Ok(())
+}
+
+fn diff_command(
+    old: Option<PathBuf>,
+    new: Option<PathBuf>,
+    responses: Option<PathBuf>,
+    global: &GlobalArgs,
+) -> anyhow::Result<()> {
+    if let Some(capture_path) = responses {
+        let mut differ = ResponseDiffer::new();
+        for m in capture::read_messages(capture_path, global.port)? {
+            if let Ok(Message::OperationResponse(response)) = &m.message {
+                print_response_diff(&mut differ, m.connection, response, global.format);
             }
-            MessageType::InternalOperationRequest => {
-                let res = des.deserialize_operation_request();
-                println!("Internal Operation Request: {:#?}", res);
-                //Internal operation request
+        }
+        return Ok(());
+    }
+    let (Some(old), Some(new)) = (old, new) else {
+        bail!("diff needs an old and a new capture or schema");
+    };
+    let load = |path: &Path| {
+        if is_schema(path)? {
+            Schema::load(path)
+        } else {
+            infer_capture(path, global.port)
+        }
+    };
+    let diff = diff_schemas(&load(&old)?, &load(&new)?);
+    if global.format == OutputFormat::Json {
+        println!("{}", serde_json::to_string_pretty(&diff)?);
+    } else {
+        print!("{}", diff);
+    }
+    Ok(())
+}
+
+// Schemas are JSON, anything else is taken to be a capture or recording
+fn is_schema(path: &Path) -> anyhow::Result<bool> {
+    if path.extension().is_some_and(|e| e == "json") {
+        return Ok(true);
+    }
+    let mut start = [0u8; 64];
+    let n = File::open(path)
+        .and_then(|mut f| f.read(&mut start))
+        .with_context(|| format!("could not read {}", path.display()))?;
+    Ok(start[..n]
+        .iter()
+        .find(|b| !b.is_ascii_whitespace())
+        .is_some_and(|b| *b == b'{'))
+}
+
+fn decode_command(
+    input: Option<String>,
+    file: Option<PathBuf>,
+    base64: bool,
+    global: &GlobalArgs,
+) -> anyhow::Result<()> {
+    let bytes = match (input, file) {
+        (_, Some(path)) => {
+            std::fs::read(&path).with_context(|| format!("could not read {}", path.display()))?
+        }
+        (text, None) => {
+            let text = match text {
+                Some(text) => text,
+                None => io::read_to_string(io::stdin())?,
+            };
+            if base64 {
+                input::parse_base64(&text)?
+            } else {
+                input::parse_text(&text)?
             }
-            MessageType::InternalOperationResponse => {
-                //Internal operation response
-                let res = des.deserialize_operation_response();
-                println!("Internal Operation Response: {:#?}", res);
+        }
+    };
+    let messages = input::split_messages(&bytes)?;
+    let mut failed = 0;
+    for raw in &messages {
+        // pasted messages don't say which way they went
+        let res = decode_message_with_len(raw).and_then(|(message, len)| {
+            if len < raw.len() {
+                bail!(
+                    "decoded {:?} but {} bytes were left over after byte {}",
+                    message.message_type(),
+                    raw.len() - len,
+                    len
+                );
             }
-            MessageType::Message => {
-                //Message
+            Ok(message)
+        });
+        match &res {
+            Ok(message) => {
+                if let Some(s) = format_message(Direction::Incoming, message, global.format) {
+                    println!("{}", s);
+                } else {
+                    println!("{:?}", message.message_type());
+                }
             }
-            MessageType::RawMessage => {
-                //Raw message
+            Err(e) => {
+                failed += 1;
+                println!("{}", format_error(e, raw, global.format));
             }
         }
+        // the bytes were pasted, so -v goes straight to the annotated dump
+        if global.verbose > 0 {
+            print!("{}", annotated(raw));
+        }
+    }
+    if failed > 0 {
+        bail!("{} of {} messages failed to decode", failed, messages.len());
     }
+    Ok(())
 }
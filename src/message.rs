@@ -0,0 +1,230 @@
+use std::io::Cursor;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    serialization::{
+        op_code::MessageType, EventData, OperationRequest, OperationResponse, StreamDeserializer,
+        StreamSerializer,
+    },
+    spans::{Span, SpanRecorder},
+};
+
+// Direction relative to the game client. Outgoing is client -> server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Direction {
+    #[serde(rename = "in")]
+    Incoming,
+    #[serde(rename = "out")]
+    Outgoing,
+}
+
+impl Direction {
+    // the same as it's written in filters and json
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Incoming => "in",
+            Direction::Outgoing => "out",
+        }
+    }
+}
+
+// A decoded photon message. Message types we don't decode (yet) are kept as unit variants
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Init,
+    InitResponse,
+    OperationRequest(OperationRequest),
+    OperationResponse(OperationResponse),
+    Event(EventData),
+    InternalOperationRequest(OperationRequest),
+    InternalOperationResponse(OperationResponse),
+    Message,
+    RawMessage,
+}
+impl Message {
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            Message::Init => MessageType::Init,
+            Message::InitResponse => MessageType::InitResponse,
+            Message::OperationRequest(_) => MessageType::Operation,
+            Message::OperationResponse(_) => MessageType::OperationResponse,
+            Message::Event(_) => MessageType::Event,
+            Message::InternalOperationRequest(_) => MessageType::InternalOperationRequest,
+            Message::InternalOperationResponse(_) => MessageType::InternalOperationResponse,
+            Message::Message => MessageType::Message,
+            Message::RawMessage => MessageType::RawMessage,
+        }
+    }
+}
+
+pub fn is_encrypted(buf: &[u8]) -> bool {
+    buf.len() > 1 && (buf[1] & 128) != 0
+}
+
+// Decodes a message as produced by the framing layer, starting at the 0xF3 magic byte
+pub fn decode_message(buf: &[u8]) -> Result<Message> {
+    decode_message_with_len(buf).map(|(message, _)| message)
+}
+
+// Also returns how many bytes of buf the message took up, anything after that is trailing garbage.
+// Errors say at which byte decoding stopped
+pub fn decode_message_with_len(buf: &[u8]) -> Result<(Message, usize)> {
+    decode(buf, None).0
+}
+
+// Also returns which bytes every value was read from. The spans are there even if decoding fails, up to where it
+// stopped
+pub fn decode_message_with_spans(buf: &[u8]) -> (Result<(Message, usize)>, Span) {
+    let (res, spans) = decode(buf, Some(SpanRecorder::new(2)));
+    // only None if the recorder wasn't passed in
+    let mut spans = spans.unwrap();
+    if buf.len() >= 2 {
+        spans.children.insert(
+            0,
+            Span {
+                range: 0..2,
+                type_code: None,
+                path: Default::default(),
+                field: "header",
+                children: Vec::new(),
+            },
+        );
+    }
+    (res, spans)
+}
+
+fn decode(buf: &[u8], spans: Option<SpanRecorder>) -> (Result<(Message, usize)>, Option<Span>) {
+    if buf.len() < 2 || buf[0] != 0xF3 {
+        return (
+            Err(anyhow!("message does not start with f3")),
+            spans.map(|s| s.finish(0)),
+        );
+    }
+    if is_encrypted(buf) {
+        return (
+            Err(anyhow!(
+                "encountered encrypted packet. This program cannot decrypt encrypted packets"
+            )),
+            spans.map(|s| s.finish(0)),
+        );
+    }
+    let msg_type = match MessageType::from_repr(buf[1] & 127) {
+        Some(t) => t,
+        None => {
+            return (
+                Err(anyhow!("unknown message type {}", buf[1] & 127)),
+                spans.map(|s| s.finish(0)),
+            )
+        }
+    };
+    let mut des = StreamDeserializer::new(Cursor::new(&buf[2..]));
+    des.spans = spans;
+    let res = (|| -> Result<Message> {
+        Ok(match msg_type {
+            MessageType::Init => Message::Init,
+            MessageType::InitResponse => Message::InitResponse,
+            MessageType::Operation => {
+                Message::OperationRequest(des.deserialize_operation_request()?)
+            }
+            MessageType::OperationResponse => {
+                Message::OperationResponse(des.deserialize_operation_response()?)
+            }
+            MessageType::Event => Message::Event(des.deserialize_event_data()?),
+            MessageType::InternalOperationRequest => {
+                Message::InternalOperationRequest(des.deserialize_operation_request()?)
+            }
+            MessageType::InternalOperationResponse => {
+                Message::InternalOperationResponse(des.deserialize_operation_response()?)
+            }
+            MessageType::Message => Message::Message,
+            MessageType::RawMessage => Message::RawMessage,
+        })
+    })();
+    let len = match msg_type {
+        // these aren't decoded, so they use up the whole buffer
+        MessageType::Init
+        | MessageType::InitResponse
+        | MessageType::Message
+        | MessageType::RawMessage => buf.len(),
+        _ => 2 + des.position(),
+    };
+    let spans = des.spans.take().map(|s| s.finish(des.position()));
+    let res = res
+        .map(|message| (message, len))
+        .with_context(|| format!("at byte {} of {}", len, buf.len()));
+    (res, spans)
+}
+
+// The inverse of decode_message, without encryption. Message types that aren't decoded can't be encoded either
+pub fn encode_message(message: &Message) -> Result<Vec<u8>> {
+    let mut ser = StreamSerializer::new(vec![0xF3, message.message_type() as u8]);
+    match message {
+        Message::OperationRequest(r) | Message::InternalOperationRequest(r) => {
+            ser.serialize_operation_request(r)?
+        }
+        Message::OperationResponse(r) | Message::InternalOperationResponse(r) => {
+            ser.serialize_operation_response(r)?
+        }
+        Message::Event(e) => ser.serialize_event_data(e)?,
+        m => bail!("can't encode {:?} messages", m.message_type()),
+    }
+    Ok(ser.writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{decode_message, encode_message, Message};
+    use crate::serialization::{EventData, OperationResponse, Value};
+
+    #[test]
+    fn test_encode_roundtrip() {
+        let dict: HashMap<Value, Value> = [
+            ("a".to_string().into(), Value::Int(1)),
+            ("b".to_string().into(), Value::String("x".into())),
+        ]
+        .into();
+        let params: HashMap<u8, Value> = [
+            (1, Value::Long(-5)),
+            (
+                2,
+                Value::Array(vec![Value::Dictionary(dict.clone().into()); 2]),
+            ),
+            (3, Value::Dictionary(dict.into())),
+            (4, Value::StringArray(vec!["s".into()])),
+            (5, Value::ByteArray(vec![1, 2, 3])),
+            (6, Value::Array(vec![Value::Array(vec![Value::Short(7)])])),
+            (7, Value::Null(())),
+        ]
+        .into();
+        let message = Message::OperationResponse(OperationResponse::new(
+            66,
+            -1,
+            Value::String("oops".into()),
+            params.into(),
+        ));
+        let bytes = encode_message(&message).unwrap();
+        assert_eq!(&bytes[..3], &[0xF3, 3, 66]);
+        assert_eq!(decode_message(&bytes).unwrap(), message);
+    }
+
+    #[test]
+    fn test_event_names() {
+        let params: HashMap<u8, Value> = [(254, Value::Int(7))].into();
+        let bytes = encode_message(&Message::Event(EventData::new(255, params.into()))).unwrap();
+        let Message::Event(event) = decode_message(&bytes).unwrap() else {
+            panic!("not an event");
+        };
+        let debug = format!("{:?}", event);
+        assert!(debug.contains("event_code: Join"), "{}", debug);
+        assert!(debug.contains("ActorNr"), "{}", debug);
+        // codes that aren't in the table stay numbers
+        let bytes =
+            encode_message(&Message::Event(EventData::new(199, HashMap::new().into()))).unwrap();
+        let debug = format!("{:?}", decode_message(&bytes).unwrap());
+        assert!(debug.contains("event_code: 199"), "{}", debug);
+    }
+}
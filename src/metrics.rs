@@ -0,0 +1,199 @@
+use std::{io, string::FromUtf8Error, sync::Arc};
+
+use anyhow::Result;
+use axum::{extract::State, http::header, response::IntoResponse, routing::get, Router};
+use prometheus::{IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use tokio::net::TcpListener;
+
+use crate::{
+    capture::CapturedMessage,
+    message::{is_encrypted, Direction, Message},
+    output::{event_name, opcode_name},
+};
+
+// Prometheus metrics for keeping an eye on long sniffing sessions. The pipeline counts packets, reassembly gaps and
+// what's waiting in its queue, and everything about messages is counted from the decoded CapturedMessage.
+
+pub struct Metrics {
+    registry: Registry,
+    pub packets: IntCounterVec,
+    pub frames: IntCounterVec,
+    pub decode_errors: IntCounterVec,
+    pub messages: IntCounterVec,
+    pub encrypted: IntCounterVec,
+    pub gaps: IntCounterVec,
+    pub queue_depth: IntGaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("rcsniff2".to_string()), None)
+            // only fails for a prefix that isn't a valid metric name
+            .unwrap();
+        let counter = |name: &str, help: &str, labels: &[&str]| {
+            let c = IntCounterVec::new(Opts::new(name, help), labels).unwrap();
+            registry.register(Box::new(c.clone())).unwrap();
+            c
+        };
+        let packets = counter(
+            "packets_captured_total",
+            "TCP packets to or from the WebServices port",
+            &["direction"],
+        );
+        let frames = counter(
+            "frames_decoded_total",
+            "photon messages split out of the stream, whether they decoded or not",
+            &["direction"],
+        );
+        let decode_errors = counter(
+            "decode_errors_total",
+            "messages that failed to decode, by what went wrong",
+            &["direction", "kind"],
+        );
+        let messages = counter(
+            "messages_total",
+            "decoded messages by type and opcode or event",
+            &["direction", "type", "code"],
+        );
+        let encrypted = counter(
+            "encrypted_frames_total",
+            "messages that couldn't be decoded because they're encrypted",
+            &["direction"],
+        );
+        let gaps = counter(
+            "reassembly_gaps_total",
+            "times the stream didn't continue with a frame or ping where one should start, usually from lost packets",
+            &["direction"],
+        );
+        let queue_depth = IntGaugeVec::new(
+            Opts::new(
+                "queue_depth",
+                "packets and messages waiting to go through the decoding pipeline",
+            ),
+            &["queue", "direction"],
+        )
+        .unwrap();
+        registry.register(Box::new(queue_depth.clone())).unwrap();
+        Self {
+            registry,
+            packets,
+            frames,
+            decode_errors,
+            messages,
+            encrypted,
+            gaps,
+            queue_depth,
+        }
+    }
+    pub fn observe(&self, m: &CapturedMessage) {
+        let direction = m.direction.as_str();
+        self.frames.with_label_values(&[direction]).inc();
+        let message = match &m.message {
+            Ok(message) => message,
+            Err(_) if is_encrypted(&m.raw) => {
+                self.encrypted.with_label_values(&[direction]).inc();
+                return;
+            }
+            Err(e) => {
+                self.decode_errors
+                    .with_label_values(&[direction, error_kind(e)])
+                    .inc();
+                return;
+            }
+        };
+        let code = match message {
+            Message::OperationRequest(r) => opcode_name(r.opcode()),
+            Message::OperationResponse(r) => opcode_name(r.opcode()),
+            Message::Event(e) => event_name(e.event_code()),
+            Message::InternalOperationRequest(r) => r.opcode().to_string(),
+            Message::InternalOperationResponse(r) => r.opcode().to_string(),
+            _ => String::new(),
+        };
+        self.messages
+            .with_label_values(&[direction, &format!("{:?}", message.message_type()), &code])
+            .inc();
+    }
+    pub fn queue(&self, queue: &str, direction: Direction) -> IntGauge {
+        self.queue_depth
+            .with_label_values(&[queue, direction.as_str()])
+    }
+    // Prometheus text format
+    pub fn render(&self) -> String {
+        TextEncoder::new()
+            .encode_to_string(&self.registry.gather())
+            .unwrap_or_default()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Coarse, so the number of label values stays small
+pub fn error_kind(e: &anyhow::Error) -> &'static str {
+    for cause in e.chain() {
+        if let Some(e) = cause.downcast_ref::<io::Error>() {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                return "truncated";
+            }
+        }
+        if cause.is::<FromUtf8Error>() {
+            return "invalid_string";
+        }
+    }
+    let message = format!("{:#}", e);
+    if message.contains("type code") {
+        "unknown_type_code"
+    } else if message.contains("custom type") {
+        "custom_type"
+    } else if message.contains("message type") || message.contains("start with f3") {
+        "not_a_message"
+    } else {
+        "other"
+    }
+}
+
+// Serves GET /metrics until the listener fails
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) -> Result<()> {
+    let app = Router::new()
+        .route("/metrics", get(scrape))
+        .with_state(metrics);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn scrape(State(metrics): State<Arc<Metrics>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+        metrics.render(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Metrics;
+    use crate::{capture::CapturedMessage, message::Direction};
+
+    #[test]
+    fn test_metrics() {
+        let metrics = Metrics::new();
+        for raw in [
+            vec![0xF3, 2, 66, 0, 0],
+            vec![0xF3, 2, 66, 0, 1, 5],
+            vec![0xF3, 0x83, 1],
+        ] {
+            metrics.observe(&CapturedMessage::decoded(Direction::Outgoing, raw));
+        }
+        let text = metrics.render();
+        assert!(text.contains("rcsniff2_frames_decoded_total{direction=\"out\"} 3"));
+        assert!(text.contains(
+            "rcsniff2_messages_total{code=\"LoadWallet(66)\",direction=\"out\",type=\"Operation\"} 1"
+        ));
+        assert!(
+            text.contains("rcsniff2_decode_errors_total{direction=\"out\",kind=\"truncated\"} 1")
+        );
+        assert!(text.contains("rcsniff2_encrypted_frames_total{direction=\"out\"} 1"));
+    }
+}
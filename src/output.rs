@@ -0,0 +1,257 @@
+use std::fmt::Write;
+
+use serde_json::{json, Map};
+
+use crate::{
+    capture::CapturedMessage,
+    message::{Direction, Message},
+    registry::{self, ParameterOwner},
+    serialization::{op_code::WebServicesOpCode, ParameterTable, Value},
+    value_diff::{Change, ValueChange},
+};
+
+// How decoded messages are printed
+#[derive(strum::EnumString, strum::AsRefStr, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[strum(serialize_all = "lowercase")]
+pub enum OutputFormat {
+    // multi-line Debug output
+    #[default]
+    Pretty,
+    // one line per message
+    Compact,
+    // one JSON object per line
+    Json,
+}
+
+// None for message types that aren't decoded (Init, InitResponse, Message, RawMessage) unless printing JSON
+pub fn format_message(
+    direction: Direction,
+    message: &Message,
+    format: OutputFormat,
+) -> Option<String> {
+    let label = match message {
+        Message::OperationRequest(_) => "Operation Request",
+        Message::OperationResponse(_) => "Operation Response",
+        Message::Event(_) => "Event Data",
+        Message::InternalOperationRequest(_) => "Internal Operation Request",
+        Message::InternalOperationResponse(_) => "Internal Operation Response",
+        _ if format == OutputFormat::Json => {
+            return Some(message_json(direction, message).to_string())
+        }
+        _ => return None,
+    };
+    Some(match format {
+        OutputFormat::Pretty => format!("{}: {:#?}", label, DebugMessage(message)),
+        OutputFormat::Compact => format!("{}: {:?}", label, DebugMessage(message)),
+        OutputFormat::Json => message_json(direction, message).to_string(),
+    })
+}
+
+// Debug of the message's contents without the Message enum around it
+struct DebugMessage<'a>(&'a Message);
+impl std::fmt::Debug for DebugMessage<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Message::OperationRequest(m) | Message::InternalOperationRequest(m) => m.fmt(f),
+            Message::OperationResponse(m) | Message::InternalOperationResponse(m) => m.fmt(f),
+            Message::Event(m) => m.fmt(f),
+            m => m.fmt(f),
+        }
+    }
+}
+
+pub fn format_error(error: &anyhow::Error, raw: &[u8], format: OutputFormat) -> String {
+    match format {
+        OutputFormat::Json => {
+            json!({ "error": format!("{:#}", error), "raw": hex(raw) }).to_string()
+        }
+        _ => format!("Error: {:#}\nErroring Request: {:x?}", error, raw),
+    }
+}
+
+// e.g. "Operation Response LoadWallet(66)"
+pub fn message_label(message: &Message) -> String {
+    match message {
+        Message::OperationRequest(m) => format!("Operation Request {}", opcode_name(m.opcode())),
+        Message::OperationResponse(m) => format!("Operation Response {}", opcode_name(m.opcode())),
+        Message::Event(m) => format!("Event Data {}", event_name(m.event_code())),
+        Message::InternalOperationRequest(m) => {
+            format!("Internal Operation Request {}", m.opcode())
+        }
+        Message::InternalOperationResponse(m) => {
+            format!("Internal Operation Response {}", m.opcode())
+        }
+        m => format!("{:?}", m.message_type()),
+    }
+}
+
+pub fn opcode_name(opcode: u8) -> String {
+    match WebServicesOpCode::from_repr(opcode) {
+        Some(op) => format!("{:?}({})", op, opcode),
+        None => opcode.to_string(),
+    }
+}
+
+pub fn event_name(code: u8) -> String {
+    match registry::event_name(code) {
+        Some(e) => format!("{}({})", e, code),
+        None => code.to_string(),
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        write!(s, "{:02x}", b).unwrap();
+    }
+    s
+}
+
+pub fn message_json(direction: Direction, message: &Message) -> serde_json::Value {
+    let mut out = match message {
+        Message::OperationRequest(m) => json!({
+            "type": "operation_request",
+            "opcode": m.opcode(),
+            "name": WebServicesOpCode::from_repr(m.opcode()).map(|o| format!("{:?}", o)),
+            "parameters": parameters_json(m.parameters(), Some(ParameterOwner::Operation(m.opcode()))),
+        }),
+        Message::OperationResponse(m) => json!({
+            "type": "operation_response",
+            "opcode": m.opcode(),
+            "name": WebServicesOpCode::from_repr(m.opcode()).map(|o| format!("{:?}", o)),
+            "return_code": m.return_code(),
+            "debug_message": value_json(m.debug_message()),
+            "parameters": parameters_json(m.parameters(), Some(ParameterOwner::Operation(m.opcode()))),
+        }),
+        Message::Event(m) => json!({
+            "type": "event",
+            "event_code": m.event_code(),
+            "name": registry::event_name(m.event_code()),
+            "parameters": parameters_json(m.params(), Some(ParameterOwner::Event(m.event_code()))),
+        }),
+        Message::InternalOperationRequest(m) => json!({
+            "type": "internal_operation_request",
+            "opcode": m.opcode(),
+            "parameters": parameters_json(m.parameters(), None),
+        }),
+        Message::InternalOperationResponse(m) => json!({
+            "type": "internal_operation_response",
+            "opcode": m.opcode(),
+            "return_code": m.return_code(),
+            "debug_message": value_json(m.debug_message()),
+            "parameters": parameters_json(m.parameters(), None),
+        }),
+        m => json!({ "type": format!("{:?}", m.message_type()) }),
+    };
+    out["direction"] = json!(direction);
+    out
+}
+
+// message_json plus where and when the message was seen. Decode errors are {"error", "raw", "direction", ...}, with
+// "decrypted" too when it was decrypted before failing
+pub fn captured_json(m: &CapturedMessage) -> serde_json::Value {
+    let mut json = match &m.message {
+        Ok(message) => message_json(m.direction, message),
+        Err(e) => json!({
+            "error": format!("{:#}", e),
+            "raw": hex(&m.raw),
+            "direction": m.direction,
+        }),
+    };
+    if let (Err(_), Some(decrypted)) = (&m.message, &m.decrypted) {
+        json["decrypted"] = json!(hex(decrypted));
+    }
+    json["connection"] = json!(m.connection);
+    json["timestamp"] = json!(m.timestamp.as_secs_f64());
+    json
+}
+
+// {"<code>": {"name": .., "value": ..}}, name left out when the registry doesn't know it
+pub fn parameters_json(table: &ParameterTable, owner: Option<ParameterOwner>) -> serde_json::Value {
+    let mut codes: Vec<_> = table.iter().collect();
+    codes.sort_by_key(|(k, _)| **k);
+    let mut map = Map::new();
+    for (code, value) in codes {
+        let mut p = json!({ "value": value_json(value) });
+        if let Some(name) = registry::parameter_name(owner, *code) {
+            p["name"] = json!(name);
+        }
+        map.insert(code.to_string(), p);
+    }
+    serde_json::Value::Object(map)
+}
+
+// e.g. {"change": "changed", "path": "params[1]", "path_in": "new", "old": 1, "new": 2}
+pub fn value_change_json(c: &ValueChange) -> serde_json::Value {
+    let mut json = json!({
+        "path": c.path.to_string(),
+        "path_in": if c.in_old() { "old" } else { "new" },
+    });
+    let fields = match &c.change {
+        Change::Added(v) => json!({ "change": "added", "value": value_json(v) }),
+        Change::Removed(v) => json!({ "change": "removed", "value": value_json(v) }),
+        Change::Changed { old, new } => {
+            json!({ "change": "changed", "old": value_json(old), "new": value_json(new) })
+        }
+        Change::TypeChanged { old, new } => {
+            json!({ "change": "type_changed", "old": value_json(old), "new": value_json(new) })
+        }
+    };
+    if let (Some(json), serde_json::Value::Object(fields)) = (json.as_object_mut(), fields) {
+        json.extend(fields);
+    }
+    json
+}
+
+// Type information is lost, byte arrays become hex strings and dictionaries with non-string keys
+// become lists of [key, value] pairs
+pub fn value_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Byte(b) => json!(b),
+        Value::Bool(b) => json!(b),
+        Value::Short(s) => json!(s),
+        Value::Int(i) => json!(i),
+        Value::Long(l) => json!(l),
+        Value::Float(f) => json!(f.0),
+        Value::Double(d) => json!(d.0),
+        Value::String(s) => json!(s),
+        Value::Array(a) => a.iter().map(value_json).collect(),
+        Value::ObjectArray(a) => a.iter().map(value_json).collect(),
+        Value::StringArray(a) => json!(a),
+        Value::IntegerArray(a) => json!(a),
+        Value::ByteArray(a) => json!(hex(a)),
+        Value::Dictionary(d) => map_json(d),
+        Value::HashTable(h) => map_json(h),
+        Value::EventData(e) => message_json_inner(&Message::Event(e.clone())),
+        Value::OperationRequest(r) => message_json_inner(&Message::OperationRequest(r.clone())),
+        Value::OperationResponse(r) => message_json_inner(&Message::OperationResponse(r.clone())),
+        Value::Null(_) => serde_json::Value::Null,
+    }
+}
+
+fn message_json_inner(message: &Message) -> serde_json::Value {
+    let mut v = message_json(Direction::Incoming, message);
+    if let Some(o) = v.as_object_mut() {
+        o.remove("direction");
+    }
+    v
+}
+
+fn map_json(map: &std::collections::HashMap<Value, Value>) -> serde_json::Value {
+    let mut entries: Vec<_> = map.iter().collect();
+    entries.sort_by_cached_key(|(k, _)| format!("{:?}", k));
+    if entries.iter().all(|(k, _)| matches!(k, Value::String(_))) {
+        let mut out = Map::new();
+        for (k, v) in entries {
+            if let Value::String(k) = k {
+                out.insert(k.clone(), value_json(v));
+            }
+        }
+        serde_json::Value::Object(out)
+    } else {
+        entries
+            .into_iter()
+            .map(|(k, v)| json!([value_json(k), value_json(v)]))
+            .collect()
+    }
+}
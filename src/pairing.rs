@@ -0,0 +1,63 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+};
+
+// Photon responses don't carry a request id, the server answers the requests to an opcode in the order they were
+// sent. So a response goes with the oldest unanswered request with the same key, usually (connection, opcode).
+// Whatever is worth remembering about the request until then (when it was sent, its row id, a waiting channel)
+// is queued with it
+#[derive(Debug, Clone)]
+pub struct Pairing<K, T> {
+    waiting: HashMap<K, VecDeque<T>>,
+}
+
+impl<K, T> Default for Pairing<K, T> {
+    fn default() -> Self {
+        Self {
+            waiting: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Hash + Eq, T> Pairing<K, T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn request(&mut self, key: K, request: T) {
+        self.waiting.entry(key).or_default().push_back(request);
+    }
+    // the request this response answers, if it was seen
+    pub fn response(&mut self, key: &K) -> Option<T> {
+        self.waiting.get_mut(key).and_then(VecDeque::pop_front)
+    }
+    // drops requests that won't be answered after all, e.g. a call that timed out
+    pub fn retain(&mut self, key: &K, keep: impl FnMut(&T) -> bool) {
+        if let Some(queue) = self.waiting.get_mut(key) {
+            queue.retain(keep);
+        }
+    }
+    pub fn clear(&mut self) {
+        self.waiting.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pairing;
+
+    #[test]
+    fn test_pairing() {
+        let mut pairing = Pairing::new();
+        pairing.request((0, 66), "first");
+        pairing.request((1, 66), "other connection");
+        pairing.request((0, 66), "second");
+        pairing.request((0, 67), "other opcode");
+        pairing.retain(&(0, 67), |r| *r != "other opcode");
+        assert_eq!(pairing.response(&(0, 66)), Some("first"));
+        assert_eq!(pairing.response(&(0, 66)), Some("second"));
+        assert_eq!(pairing.response(&(0, 66)), None);
+        assert_eq!(pairing.response(&(0, 67)), None);
+        assert_eq!(pairing.response(&(1, 66)), Some("other connection"));
+    }
+}
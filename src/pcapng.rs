@@ -0,0 +1,308 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    capture::CapturedMessage,
+    framing::frame_message,
+    message::{is_encrypted, Direction, Message},
+    output::message_label,
+    registry::{self, ParameterOwner},
+    serialization::ParameterTable,
+};
+
+// Decoded sessions as a pcapng Wireshark can open. Every message is written back in its frame as TCP between a
+// made-up client (10.0.0.1, port 50000 + connection id) and server (10.0.0.2, the WebServices port), so Wireshark
+// follows the streams as usual, and the first packet of each message gets a comment with what rcsniff2 decoded it
+// to. Pings aren't written, they're dropped before decoding.
+//
+// Segments are at most MSS bytes long like on the wire. Sequence numbers start at 1 per connection and direction.
+
+const MSS: usize = 1460;
+const CLIENT_IP: [u8; 4] = [10, 0, 0, 1];
+const SERVER_IP: [u8; 4] = [10, 0, 0, 2];
+const CLIENT_PORT: u16 = 50000;
+// comments longer than this are cut off, Wireshark only shows the first line of a comment in the packet list
+const MAX_COMMENT: usize = 400;
+
+pub struct PcapngWriter<W: Write> {
+    writer: W,
+    port: u16,
+    // next sequence number per connection and direction
+    sequence: HashMap<(u32, Direction), u32>,
+}
+
+impl PcapngWriter<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>, port: u16) -> Result<Self> {
+        let path = path.as_ref();
+        let f = File::create(path).context(format!("creating pcapng {}", path.display()))?;
+        Self::new(BufWriter::new(f), port)
+    }
+}
+
+impl<W: Write> PcapngWriter<W> {
+    pub fn new(mut writer: W, port: u16) -> Result<Self> {
+        // section header: byte order magic, version 1.0, unknown section length, shb_userappl
+        let mut shb = Vec::new();
+        shb.extend_from_slice(&0x1A2B3C4Du32.to_le_bytes());
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        shb.extend_from_slice(&(-1i64).to_le_bytes());
+        option(&mut shb, 4, b"rcsniff2");
+        option(&mut shb, 0, b"");
+        write_block(&mut writer, 0x0A0D0D0A, &shb)?;
+        // interface description: ethernet, no snap length, microsecond timestamps by default
+        let mut idb = Vec::new();
+        idb.extend_from_slice(&1u16.to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes());
+        idb.extend_from_slice(&0u32.to_le_bytes());
+        write_block(&mut writer, 1, &idb)?;
+        Ok(Self {
+            writer,
+            port,
+            sequence: HashMap::new(),
+        })
+    }
+    pub fn write(&mut self, m: &CapturedMessage) -> Result<()> {
+        let payload = frame_message(&m.raw);
+        let sent = *self
+            .sequence
+            .get(&(m.connection, m.direction))
+            .unwrap_or(&1);
+        let acked = *self
+            .sequence
+            .get(&(m.connection, other(m.direction)))
+            .unwrap_or(&1);
+        self.sequence.insert(
+            (m.connection, m.direction),
+            sent.wrapping_add(payload.len() as u32),
+        );
+        let client_port = CLIENT_PORT.wrapping_add(m.connection as u16);
+        let (source, destination) = match m.direction {
+            Direction::Outgoing => ((CLIENT_IP, client_port), (SERVER_IP, self.port)),
+            Direction::Incoming => ((SERVER_IP, self.port), (CLIENT_IP, client_port)),
+        };
+        let micros = m.timestamp.as_micros() as u64;
+        let mut comment = Some(comment(m));
+        for (i, segment) in payload.chunks(MSS).enumerate() {
+            let packet = tcp_packet(
+                source,
+                destination,
+                sent.wrapping_add((i * MSS) as u32),
+                acked,
+                segment,
+            );
+            // enhanced packet: interface 0, timestamp, captured and original length, data, opt_comment
+            let mut epb = Vec::with_capacity(packet.len() + 64);
+            epb.extend_from_slice(&0u32.to_le_bytes());
+            epb.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+            epb.extend_from_slice(&(micros as u32).to_le_bytes());
+            epb.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            epb.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            epb.extend_from_slice(&packet);
+            pad(&mut epb);
+            if let Some(comment) = comment.take() {
+                option(&mut epb, 1, comment.as_bytes());
+                option(&mut epb, 0, b"");
+            }
+            write_block(&mut self.writer, 6, &epb)?;
+        }
+        Ok(())
+    }
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+// e.g. "rcsniff2: Operation Response LoadWallet(66) return code 0: 1=Long(12345), 2=Long(7)"
+fn comment(m: &CapturedMessage) -> String {
+    let message = match &m.message {
+        Ok(message) => message,
+        Err(_) if is_encrypted(&m.raw) => return "rcsniff2: encrypted".to_string(),
+        Err(e) => return format!("rcsniff2: decode error: {:#}", e),
+    };
+    let mut s = format!("rcsniff2: {}", message_label(message));
+    let (table, owner) = match message {
+        Message::OperationRequest(r) => {
+            (r.parameters(), Some(ParameterOwner::Operation(r.opcode())))
+        }
+        Message::OperationResponse(r) => {
+            s.push_str(&format!(" return code {}", r.return_code()));
+            (r.parameters(), Some(ParameterOwner::Operation(r.opcode())))
+        }
+        Message::Event(e) => (e.params(), Some(ParameterOwner::Event(e.event_code()))),
+        Message::InternalOperationRequest(r) => (r.parameters(), None),
+        Message::InternalOperationResponse(r) => {
+            s.push_str(&format!(" return code {}", r.return_code()));
+            (r.parameters(), None)
+        }
+        _ => return s,
+    };
+    let summary = parameter_summary(table, owner);
+    if !summary.is_empty() {
+        s.push_str(": ");
+        s.push_str(&summary);
+    }
+    if s.len() > MAX_COMMENT {
+        let mut end = MAX_COMMENT;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        s.truncate(end);
+        s.push_str("...");
+    }
+    s
+}
+
+fn parameter_summary(table: &ParameterTable, owner: Option<ParameterOwner>) -> String {
+    let mut params: Vec<_> = table.iter().collect();
+    params.sort_by_key(|(k, _)| **k);
+    params
+        .into_iter()
+        .map(|(code, value)| {
+            let name = registry::parameter_name(owner, *code)
+                .map_or_else(|| code.to_string(), str::to_string);
+            format!("{}={:?}", name, value)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn other(direction: Direction) -> Direction {
+    match direction {
+        Direction::Incoming => Direction::Outgoing,
+        Direction::Outgoing => Direction::Incoming,
+    }
+}
+
+// Ethernet + IPv4 + TCP (ACK|PSH) around `payload`, with valid checksums
+fn tcp_packet(
+    (source_ip, source_port): ([u8; 4], u16),
+    (destination_ip, destination_port): ([u8; 4], u16),
+    sequence: u32,
+    ack: u32,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut p = Vec::with_capacity(54 + payload.len());
+    // ethernet: locally administered addresses, last byte tells the ends apart
+    p.extend_from_slice(&[0x02, 0, 0, 0, 0, destination_ip[3]]);
+    p.extend_from_slice(&[0x02, 0, 0, 0, 0, source_ip[3]]);
+    p.extend_from_slice(&0x0800u16.to_be_bytes());
+
+    let ip_start = p.len();
+    p.extend_from_slice(&[0x45, 0]);
+    p.extend_from_slice(&((40 + payload.len()) as u16).to_be_bytes());
+    // id, don't fragment, ttl 64, tcp, checksum filled in below
+    p.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+    p.extend_from_slice(&source_ip);
+    p.extend_from_slice(&destination_ip);
+    let ip_checksum = checksum(&[&p[ip_start..]]);
+    p[ip_start + 10..ip_start + 12].copy_from_slice(&ip_checksum.to_be_bytes());
+
+    let tcp_start = p.len();
+    p.extend_from_slice(&source_port.to_be_bytes());
+    p.extend_from_slice(&destination_port.to_be_bytes());
+    p.extend_from_slice(&sequence.to_be_bytes());
+    p.extend_from_slice(&ack.to_be_bytes());
+    // 20 byte header, ACK|PSH, window, checksum, urgent pointer
+    p.extend_from_slice(&[0x50, 0x18, 0xFF, 0xFF, 0, 0, 0, 0]);
+    p.extend_from_slice(payload);
+    let mut pseudo = Vec::with_capacity(12);
+    pseudo.extend_from_slice(&source_ip);
+    pseudo.extend_from_slice(&destination_ip);
+    pseudo.extend_from_slice(&[0, 6]);
+    pseudo.extend_from_slice(&((20 + payload.len()) as u16).to_be_bytes());
+    let tcp_checksum = checksum(&[&pseudo, &p[tcp_start..]]);
+    p[tcp_start + 16..tcp_start + 18].copy_from_slice(&tcp_checksum.to_be_bytes());
+    p
+}
+
+// Internet checksum over the parts as if they were one buffer, every part but the last has to be of even length
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for part in parts {
+        for pair in part.chunks(2) {
+            let word = match pair {
+                [a, b] => u16::from_be_bytes([*a, *b]),
+                [a] => u16::from_be_bytes([*a, 0]),
+                _ => 0,
+            };
+            sum += word as u32;
+        }
+    }
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn option(block: &mut Vec<u8>, code: u16, value: &[u8]) {
+    block.extend_from_slice(&code.to_le_bytes());
+    block.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    block.extend_from_slice(value);
+    pad(block);
+}
+
+fn pad(block: &mut Vec<u8>) {
+    block.resize((block.len() + 3) & !3, 0);
+}
+
+fn write_block(writer: &mut impl Write, block_type: u32, body: &[u8]) -> Result<()> {
+    let len = (body.len() + 12) as u32;
+    writer.write_all(&block_type.to_le_bytes())?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(body)?;
+    writer.write_all(&len.to_le_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, time::Duration};
+
+    use super::PcapngWriter;
+    use crate::{
+        capture::{dissect, CapturedMessage, PcapReader, WEBSERVICES_PORT},
+        framing::FrameReader,
+        message::Direction,
+    };
+
+    #[test]
+    fn test_pcapng_round_trip() {
+        let mut writer = PcapngWriter::new(Vec::new(), WEBSERVICES_PORT).unwrap();
+        // a LoadWallet request with a string long enough to need two segments
+        let mut raw = vec![0xF3, 2, 66, 0, 1, 5, 115, 0x07, 0xD0];
+        raw.extend([b'a'; 2000]);
+        writer
+            .write(&CapturedMessage {
+                timestamp: Duration::from_secs(1_700_000_000),
+                ..CapturedMessage::decoded(Direction::Outgoing, raw.clone())
+            })
+            .unwrap();
+        let file = writer.into_inner();
+        let comment = b"rcsniff2: Operation Request LoadWallet(66): ";
+        assert!(file.windows(comment.len()).any(|w| w == comment));
+
+        let mut reader = PcapReader::new(Cursor::new(file)).unwrap();
+        let mut framer = FrameReader::new(Direction::Outgoing);
+        let mut packets = 0;
+        while let Some(packet) = reader.next_packet().unwrap() {
+            assert_eq!(packet.timestamp, Duration::from_secs(1_700_000_000));
+            let segment = dissect(&packet.data, WEBSERVICES_PORT).unwrap();
+            assert_eq!(segment.direction, Direction::Outgoing);
+            framer.push(&segment.payload);
+            packets += 1;
+        }
+        assert_eq!(packets, 2);
+        assert_eq!(framer.next_message(), Some(raw));
+    }
+}
@@ -0,0 +1,786 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufWriter, IsTerminal, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use pnet::datalink::{self, Channel};
+use prometheus::IntGauge;
+use serde_json::json;
+use tokio::{
+    spawn,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+};
+
+use crate::{
+    api::Api,
+    capture::{dissect, CapturedMessage, PcapReader, Segment},
+    encryption::Encryption,
+    filter::Filter,
+    framing::FrameReader,
+    message::{decode_message, decode_message_with_spans, is_encrypted, Direction, Message},
+    metrics::Metrics,
+    output::{self, captured_json, format_error, format_message, message_label, OutputFormat},
+    pcapng::PcapngWriter,
+    query::Query,
+    reassembly::{Reassembled, Reassembler},
+    recording::{self, RecordedFrame, RecordingWriter},
+    schema::validate::SchemaValidator,
+    script::Scripts,
+    serialization::OperationResponse,
+    spans::render_hex,
+    sqlite::SqliteWriter,
+    stats::Stats,
+    value_diff::{self, ResponseDiffer},
+};
+
+// The decoding pipeline every command that reads traffic goes through:
+//
+//   source -> dissect -> reassemble -> frame -> decrypt -> decode -> sinks
+//
+// Sources push Inputs at whatever stage they start at: ethernet frames from an interface or a pcap get dissected
+// down to the WebServices TCP segments, which are put back in order into one byte stream per connection (by
+// addresses and ports) and direction. That TCP payload, or the proxy's, is split into frames, recordings start
+// with frames and the fake server with messages it already decoded. Encrypted frames are decrypted when there's a
+// Decrypt that can, then decoded, and every message goes to every sink in the order they were added. Messages
+// keep the bytes as they were on the wire next to the decrypted ones, each sink picks the ones it needs.
+//
+// `Pipeline` runs the stages in place for code that has all its input at hand (read_messages does this), or
+// `spawn` runs it in a task that any number of sources can feed through a PipelineSender.
+
+pub enum Input {
+    // a link layer frame as captured, anything that isn't WebServices TCP is dropped
+    Packet {
+        timestamp: Duration,
+        data: Vec<u8>,
+    },
+    // what dissect makes of a packet, not yet in order
+    Segment {
+        timestamp: Duration,
+        segment: Segment,
+    },
+    // TCP payload of one connection
+    Stream {
+        timestamp: Duration,
+        connection: u32,
+        direction: Direction,
+        bytes: Vec<u8>,
+    },
+    // one message out of its frame, starting at 0xF3
+    Frame {
+        timestamp: Duration,
+        connection: u32,
+        direction: Direction,
+        raw: Vec<u8>,
+        // when the source already has it decrypted (recordings)
+        decrypted: Option<Vec<u8>>,
+    },
+    Message(CapturedMessage),
+    // the connection is gone, its framing state is dropped and sinks are told
+    Closed(u32),
+}
+
+// Where decoded messages go. Errors are reported and the pipeline carries on, a sink that fails on one message
+// still gets the next
+pub trait Sink: Send {
+    fn handle(&mut self, m: &CapturedMessage) -> Result<()>;
+    fn closed(&mut self, _connection: u32) {}
+    // the pipeline ran out of input
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Turns an encrypted message (starting at 0xF3, with the encrypted flag set) into the plain one. None leaves it
+// encrypted, sinks then get it as a decode error
+pub trait Decrypt: Send {
+    fn decrypt(&mut self, connection: u32, direction: Direction, raw: &[u8]) -> Option<Vec<u8>>;
+}
+
+pub struct Pipeline {
+    port: u16,
+    reassembler: Reassembler,
+    streams: HashMap<(u32, Direction), FrameReader>,
+    decrypt: Option<Box<dyn Decrypt>>,
+    sinks: Vec<Box<dyn Sink>>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl Pipeline {
+    pub fn new(port: u16) -> Self {
+        Self {
+            port,
+            reassembler: Reassembler::new(),
+            streams: HashMap::new(),
+            decrypt: None,
+            sinks: Vec::new(),
+            metrics: None,
+        }
+    }
+    pub fn add_sink(&mut self, sink: impl Sink + 'static) {
+        self.sinks.push(Box::new(sink));
+    }
+    pub fn set_decrypt(&mut self, decrypt: impl Decrypt + 'static) {
+        self.decrypt = Some(Box::new(decrypt));
+    }
+    // Counts packets and reassembly gaps. Messages are only counted if `metrics` is also a sink
+    pub fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = Some(metrics);
+    }
+    pub fn push(&mut self, input: Input) {
+        match input {
+            Input::Packet { timestamp, data } => {
+                if let Some(segment) = dissect(&data, self.port) {
+                    self.segment(timestamp, segment);
+                }
+            }
+            Input::Segment { timestamp, segment } => self.segment(timestamp, segment),
+            Input::Stream {
+                timestamp,
+                connection,
+                direction,
+                bytes,
+            } => {
+                self.count_packet(direction);
+                self.stream(timestamp, connection, direction, &bytes);
+            }
+            Input::Frame {
+                timestamp,
+                connection,
+                direction,
+                raw,
+                decrypted,
+            } => self.frame(timestamp, connection, direction, raw, decrypted),
+            Input::Message(m) => self.message(&m),
+            Input::Closed(connection) => self.close(connection),
+        }
+    }
+    // Everything in a capture or recording
+    pub fn read_file(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        read_file(path.as_ref(), |input| self.push(input))
+    }
+    pub fn finish(&mut self) {
+        for sink in &mut self.sinks {
+            if let Err(e) = sink.finish() {
+                eprintln!("{:#}", e);
+            }
+        }
+    }
+    // Runs the pipeline in a task until every sender is dropped, then finishes the sinks
+    pub fn spawn(mut self) -> (PipelineSender, JoinHandle<()>) {
+        let (tx, mut rx): (_, UnboundedReceiver<Queued>) = unbounded_channel();
+        let sender = PipelineSender {
+            tx,
+            port: self.port,
+            metrics: self.metrics.clone(),
+        };
+        let task = spawn(async move {
+            while let Some((input, queue)) = rx.recv().await {
+                self.push(input);
+                if let Some(queue) = queue {
+                    queue.dec();
+                }
+            }
+            self.finish();
+        });
+        (sender, task)
+    }
+
+    fn segment(&mut self, timestamp: Duration, segment: Segment) {
+        self.count_packet(segment.direction);
+        for r in self.reassembler.push(segment) {
+            match r {
+                Reassembled::Data {
+                    connection,
+                    direction,
+                    bytes,
+                } => self.stream(timestamp, connection, direction, &bytes),
+                Reassembled::Closed(connection) => self.close(connection),
+            }
+        }
+    }
+    fn count_packet(&self, direction: Direction) {
+        if let Some(m) = &self.metrics {
+            m.packets.with_label_values(&[direction.as_str()]).inc();
+        }
+    }
+    fn stream(&mut self, timestamp: Duration, connection: u32, direction: Direction, bytes: &[u8]) {
+        let framer = self
+            .streams
+            .entry((connection, direction))
+            .or_insert_with(|| FrameReader::new(direction));
+        let gaps = framer.gaps();
+        framer.push(bytes);
+        let mut frames = Vec::new();
+        while let Some(raw) = framer.next_message() {
+            frames.push(raw);
+        }
+        if let Some(m) = &self.metrics {
+            m.gaps
+                .with_label_values(&[direction.as_str()])
+                .inc_by(framer.gaps() - gaps);
+        }
+        for raw in frames {
+            // anything else in a frame isn't a photon message
+            if raw.first() == Some(&0xF3) {
+                self.frame(timestamp, connection, direction, raw, None);
+            }
+        }
+    }
+    fn frame(
+        &mut self,
+        timestamp: Duration,
+        connection: u32,
+        direction: Direction,
+        raw: Vec<u8>,
+        decrypted: Option<Vec<u8>>,
+    ) {
+        let decrypted = match (decrypted, &mut self.decrypt) {
+            (Some(decrypted), _) => Some(decrypted),
+            (None, Some(d)) if is_encrypted(&raw) => d.decrypt(connection, direction, &raw),
+            _ => None,
+        };
+        self.message(&CapturedMessage {
+            timestamp,
+            direction,
+            connection,
+            message: decode_message(decrypted.as_deref().unwrap_or(&raw)),
+            raw,
+            decrypted,
+        });
+    }
+    fn close(&mut self, connection: u32) {
+        self.streams.retain(|(c, _), _| *c != connection);
+        for sink in &mut self.sinks {
+            sink.closed(connection);
+        }
+    }
+    fn message(&mut self, m: &CapturedMessage) {
+        for sink in &mut self.sinks {
+            if let Err(e) = sink.handle(m) {
+                eprintln!("{:#}", e);
+            }
+        }
+    }
+}
+
+type Queued = (Input, Option<IntGauge>);
+
+// Feeds a spawned pipeline. Cloning it is cheap, every source gets its own
+#[derive(Clone)]
+pub struct PipelineSender {
+    tx: UnboundedSender<Queued>,
+    port: u16,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl PipelineSender {
+    // Fails once the pipeline has stopped
+    pub fn send(&self, input: Input) -> Result<()> {
+        // what's waiting is counted per direction, so packets are dissected before they're queued
+        let input = match input {
+            Input::Packet { timestamp, data } => match dissect(&data, self.port) {
+                Some(segment) => Input::Segment { timestamp, segment },
+                None => return Ok(()),
+            },
+            input => input,
+        };
+        let queue = match (&self.metrics, &input) {
+            (Some(m), Input::Segment { segment, .. }) => {
+                Some(m.queue("packets", segment.direction))
+            }
+            (Some(m), Input::Stream { direction, .. }) => Some(m.queue("packets", *direction)),
+            (Some(m), Input::Frame { direction, .. }) => Some(m.queue("messages", *direction)),
+            (Some(m), Input::Message(message)) => Some(m.queue("messages", message.direction)),
+            _ => None,
+        };
+        if let Some(queue) = &queue {
+            queue.inc();
+        }
+        self.tx
+            .send((input, queue))
+            .map_err(|_| anyhow::anyhow!("the pipeline stopped"))
+    }
+    // TCP payload received just now
+    pub fn stream(&self, connection: u32, direction: Direction, bytes: Vec<u8>) -> Result<()> {
+        self.send(Input::Stream {
+            timestamp: now(),
+            connection,
+            direction,
+            bytes,
+        })
+    }
+    // Sniffs an interface, the system's default one if there's no name, until the pipeline stops. Blocks the thread
+    pub fn sniff(&self, interface: Option<String>) -> Result<()> {
+        let iface_name = match interface {
+            Some(name) => name,
+            None => netdev::get_default_interface()
+                .map_err(|e| anyhow::anyhow!("{}", e))
+                .context("failed to get default interface name and you haven't specified one. Please specify the network interface to use")?
+                .name,
+        };
+        let interfaces = datalink::interfaces();
+        let Some(int) = interfaces.iter().find(|i| i.name == iface_name) else {
+            bail!(
+                "no interface named {}. valid interfaces: {}",
+                iface_name,
+                interfaces
+                    .iter()
+                    .map(|i| i.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        };
+        let mut rx = match datalink::channel(int, Default::default()) {
+            Ok(Channel::Ethernet(_, rx)) => rx,
+            Ok(_) => bail!("unsupported channel type on {}", iface_name),
+            Err(e) => return Err(e).context(format!("could not listen on {}", iface_name)),
+        };
+        loop {
+            if let Ok(packet) = rx.next() {
+                self.send(Input::Packet {
+                    timestamp: now(),
+                    data: packet.to_vec(),
+                })?;
+            }
+        }
+    }
+    pub fn read_file(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut res = Ok(());
+        read_file(path.as_ref(), |input| {
+            if res.is_ok() {
+                res = self.send(input);
+            }
+        })?;
+        res
+    }
+}
+
+// since the unix epoch, what live sources timestamp things with
+pub fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+// Native recordings are already framed, they start at the frame stage with whatever was decrypted when recording
+fn read_file(path: &Path, mut push: impl FnMut(Input)) -> Result<()> {
+    if recording::is_recording(path) {
+        for f in recording::read_recording(path)? {
+            push(Input::Frame {
+                timestamp: f.timestamp,
+                direction: f.direction,
+                connection: f.connection,
+                raw: f.raw,
+                decrypted: f.decrypted,
+            });
+        }
+        return Ok(());
+    }
+    let mut reader = PcapReader::open(path)?;
+    while let Some(packet) = reader.next_packet()? {
+        push(Input::Packet {
+            timestamp: packet.timestamp,
+            data: packet.data,
+        });
+    }
+    Ok(())
+}
+
+// Only messages that pass the filter get through, see Filter::passes
+pub struct Filtered<S> {
+    filter: Option<Filter>,
+    sink: S,
+}
+
+impl<S> Filtered<S> {
+    pub fn new(filter: Option<Filter>, sink: S) -> Self {
+        Self { filter, sink }
+    }
+}
+
+impl<S: Sink> Sink for Filtered<S> {
+    fn handle(&mut self, m: &CapturedMessage) -> Result<()> {
+        match self.filter.iter().all(|f| f.passes(m)) {
+            true => self.sink.handle(m),
+            false => Ok(()),
+        }
+    }
+    fn closed(&mut self, connection: u32) {
+        self.sink.closed(connection)
+    }
+    fn finish(&mut self) -> Result<()> {
+        self.sink.finish()
+    }
+}
+
+// One JSON object per line, as the api sends them (without the id)
+pub struct JsonLines<W> {
+    writer: W,
+}
+
+impl<W: Write> JsonLines<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+}
+
+impl<W: Write + Send> Sink for JsonLines<W> {
+    fn handle(&mut self, m: &CapturedMessage) -> Result<()> {
+        writeln!(self.writer, "{}", captured_json(m))?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// What's printed and how. Filtering is left to Filtered
+#[derive(Default)]
+pub struct PrintOptions {
+    // print a schema drift record for every difference from the schema
+    pub validator: Option<SchemaValidator>,
+    // print what changed since the last response to the same opcode on the same connection
+    pub diff_responses: bool,
+    // print only the parts of each message matching this query
+    pub select: Option<Query>,
+    pub format: OutputFormat,
+    // also print the raw bytes
+    pub verbose: bool,
+    // print the raw bytes as an annotated hex dump
+    pub annotate: bool,
+    // don't print decode errors
+    pub quiet: bool,
+}
+
+// Prints decoded messages to stdout, the way the command line tool does
+pub struct Printer {
+    options: PrintOptions,
+    differ: ResponseDiffer,
+}
+
+impl Printer {
+    pub fn new(options: PrintOptions) -> Self {
+        Self {
+            options,
+            differ: ResponseDiffer::new(),
+        }
+    }
+}
+
+impl Sink for Printer {
+    fn handle(&mut self, m: &CapturedMessage) -> Result<()> {
+        // annotations and dumps are of what was decoded, after decryption
+        let (direction, raw, res) = (m.direction, m.message_bytes(), &m.message);
+        let options = &self.options;
+        // in json mode stdout stays one json object per line, the hex dump goes to stderr
+        let json = options.format == OutputFormat::Json;
+        if options.annotate && json {
+            eprint!(
+                "{}",
+                render_hex(raw, &decode_message_with_spans(raw).1, false)
+            );
+        } else if options.annotate {
+            print!("{}", annotated(raw));
+        } else if options.verbose && json {
+            println!("{}", json!({ "raw": output::hex(raw) }));
+        } else if options.verbose {
+            println!("Raw: {:x?}", raw);
+        }
+        match (res, &options.select) {
+            (Ok(message), Some(query)) => {
+                for selected in query.select_message(message) {
+                    if options.format == OutputFormat::Json {
+                        println!(
+                            "{}",
+                            json!({
+                                "message": message_label(message),
+                                "path": selected.path.to_string(),
+                                "value": output::value_json(&selected.value),
+                            })
+                        );
+                    } else {
+                        println!(
+                            "{}: {} = {:?}",
+                            message_label(message),
+                            selected.path,
+                            selected.value
+                        );
+                    }
+                }
+            }
+            (Ok(message), None) => {
+                if let Some(s) = format_message(direction, message, options.format) {
+                    println!("{}", s);
+                }
+            }
+            (Err(e), _) => {
+                if !options.quiet {
+                    println!("{}", format_error(e, raw, options.format));
+                }
+            }
+        }
+        if let (Some(validator), Ok(message)) = (&options.validator, res) {
+            for drift in validator.validate(direction, message) {
+                match json {
+                    true => println!("{}", json!({ "schema_drift": drift })),
+                    false => println!("Schema Drift: {}", serde_json::to_string(&drift).unwrap()),
+                }
+            }
+        }
+        if let (true, Ok(Message::OperationResponse(response))) = (options.diff_responses, res) {
+            print_response_diff(&mut self.differ, m.connection, response, options.format);
+        }
+        Ok(())
+    }
+}
+
+// Hex dump labelled with what each byte was decoded as, coloured if stdout is a terminal
+pub fn annotated(raw: &[u8]) -> String {
+    let (_, spans) = decode_message_with_spans(raw);
+    render_hex(raw, &spans, io::stdout().is_terminal())
+}
+
+// Prints what changed since the last response to the same opcode on the connection, if anything did
+pub fn print_response_diff(
+    differ: &mut ResponseDiffer,
+    connection: u32,
+    response: &OperationResponse,
+    format: OutputFormat,
+) {
+    let Some(changes) = differ.observe(connection, response) else {
+        return;
+    };
+    if changes.is_empty() {
+        return;
+    }
+    if format == OutputFormat::Json {
+        let changes: Vec<_> = changes.iter().map(output::value_change_json).collect();
+        println!(
+            "{}",
+            json!({
+                "response_diff": output::opcode_name(response.opcode()),
+                "connection": connection,
+                "changes": changes,
+            })
+        );
+        return;
+    }
+    println!("Response Diff {}:", output::opcode_name(response.opcode()));
+    print!(
+        "{}",
+        value_diff::render(&changes, io::stdout().is_terminal())
+    );
+}
+
+// Collects everything, for read_messages and tests
+impl Sink for Arc<Mutex<Vec<CapturedMessage>>> {
+    fn handle(&mut self, m: &CapturedMessage) -> Result<()> {
+        self.lock().unwrap().push(m.clone());
+        Ok(())
+    }
+}
+
+impl Sink for UnboundedSender<CapturedMessage> {
+    fn handle(&mut self, m: &CapturedMessage) -> Result<()> {
+        // whoever was listening is gone, that's their business
+        let _ = self.send(m.clone());
+        Ok(())
+    }
+}
+
+// Flushed after every message so a recording of a session that's killed is still complete
+impl Sink for RecordingWriter<BufWriter<File>> {
+    fn handle(&mut self, m: &CapturedMessage) -> Result<()> {
+        self.write(&RecordedFrame {
+            timestamp: m.timestamp,
+            direction: m.direction,
+            connection: m.connection,
+            raw: m.raw.clone(),
+            decrypted: m.decrypted.clone(),
+        })
+        .and_then(|_| self.flush())
+        .context("failed to record message")
+    }
+}
+
+impl Sink for SqliteWriter {
+    fn handle(&mut self, m: &CapturedMessage) -> Result<()> {
+        self.write(m)
+            .map(|_| ())
+            .context("failed to write message to the database")
+    }
+}
+
+impl<W: Write + Send> Sink for PcapngWriter<W> {
+    fn handle(&mut self, m: &CapturedMessage) -> Result<()> {
+        self.write(m)
+            .and_then(|_| self.flush())
+            .context("failed to write message to the pcapng")
+    }
+}
+
+impl Sink for Arc<Metrics> {
+    fn handle(&mut self, m: &CapturedMessage) -> Result<()> {
+        self.observe(m);
+        Ok(())
+    }
+}
+
+impl Sink for Arc<Api> {
+    fn handle(&mut self, m: &CapturedMessage) -> Result<()> {
+        self.publish(m);
+        Ok(())
+    }
+    fn closed(&mut self, connection: u32) {
+        self.close(connection);
+    }
+}
+
+impl Sink for Scripts {
+    fn handle(&mut self, m: &CapturedMessage) -> Result<()> {
+        Scripts::handle(self, m);
+        Ok(())
+    }
+}
+
+// Shared so the totals can be looked at while it runs
+impl Sink for Arc<Mutex<Stats>> {
+    fn handle(&mut self, m: &CapturedMessage) -> Result<()> {
+        self.lock().unwrap().observe(m);
+        Ok(())
+    }
+}
+
+// The whole session shares one key, as when it's the fake server's end of the exchange
+impl Decrypt for Encryption {
+    fn decrypt(&mut self, _connection: u32, _direction: Direction, raw: &[u8]) -> Option<Vec<u8>> {
+        self.decrypt_message(raw).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddrV4},
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use anyhow::Result;
+
+    use super::{Decrypt, Filtered, Input, Pipeline, Sink};
+    use crate::{
+        capture::{CapturedMessage, Segment},
+        filter::Filter,
+        framing::frame_message,
+        message::Direction,
+    };
+
+    struct Unscramble;
+    impl Decrypt for Unscramble {
+        fn decrypt(&mut self, _: u32, _: Direction, raw: &[u8]) -> Option<Vec<u8>> {
+            let mut raw = raw.to_vec();
+            raw[1] &= 127;
+            Some(raw)
+        }
+    }
+
+    struct Closed(Arc<Mutex<Vec<u32>>>);
+    impl Sink for Closed {
+        fn handle(&mut self, _: &CapturedMessage) -> Result<()> {
+            Ok(())
+        }
+        fn closed(&mut self, connection: u32) {
+            self.0.lock().unwrap().push(connection);
+        }
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let all = Arc::new(Mutex::new(Vec::new()));
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let closed = Arc::new(Mutex::new(Vec::new()));
+        let mut pipeline = Pipeline::new(4533);
+        pipeline.add_sink(all.clone());
+        pipeline.add_sink(Filtered::new(
+            Some(Filter::parse("kind == request").unwrap()),
+            requests.clone(),
+        ));
+        pipeline.add_sink(Closed(closed.clone()));
+        pipeline.set_decrypt(Unscramble);
+
+        // a request split over two reads, and an "encrypted" event on another connection
+        let mut bytes = frame_message(&[0xF3, 2, 66, 0, 0]);
+        bytes.extend(frame_message(&[0xF3, 0x84, 255, 0, 0]));
+        let stream = |connection, bytes: &[u8]| Input::Stream {
+            timestamp: Duration::ZERO,
+            connection,
+            direction: Direction::Outgoing,
+            bytes: bytes.to_vec(),
+        };
+        pipeline.push(stream(1, &bytes[..9]));
+        pipeline.push(stream(2, &bytes[12..]));
+        pipeline.push(stream(1, &bytes[9..12]));
+        pipeline.push(Input::Closed(2));
+
+        let all = all.lock().unwrap();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].connection, 2);
+        // sinks get the bytes off the wire as well as the decrypted ones
+        assert_eq!(all[0].raw, vec![0xF3, 0x84, 255, 0, 0]);
+        assert_eq!(all[0].decrypted, Some(vec![0xF3, 4, 255, 0, 0]));
+        assert!(all[0].message.is_ok());
+        assert_eq!(all[1].connection, 1);
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        assert_eq!(*closed.lock().unwrap(), vec![2]);
+    }
+
+    #[test]
+    fn test_captured_connections() {
+        let all = Arc::new(Mutex::new(Vec::new()));
+        let closed = Arc::new(Mutex::new(Vec::new()));
+        let mut pipeline = Pipeline::new(4533);
+        pipeline.add_sink(all.clone());
+        pipeline.add_sink(Closed(closed.clone()));
+
+        // two connections sending a request each, interleaved and out of order
+        let bytes = frame_message(&[0xF3, 2, 66, 0, 0]);
+        let segment = |port, seq: u32, payload: &[u8]| Segment {
+            direction: Direction::Outgoing,
+            client: SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), port),
+            server: SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 4533),
+            seq,
+            syn: false,
+            fin: false,
+            rst: false,
+            payload: payload.to_vec(),
+        };
+        let rst = Segment {
+            rst: true,
+            ..segment(50001, 712, &[])
+        };
+        for segment in [
+            segment(50000, 100, &bytes[..4]),
+            segment(50001, 700, &bytes[..6]),
+            segment(50000, 108, &bytes[8..]),
+            segment(50001, 706, &bytes[6..]),
+            segment(50000, 104, &bytes[4..8]),
+            rst,
+        ] {
+            pipeline.push(Input::Segment {
+                timestamp: Duration::ZERO,
+                segment,
+            });
+        }
+
+        let all = all.lock().unwrap();
+        assert_eq!(all.len(), 2);
+        assert_eq!((all[0].connection, all[1].connection), (1, 0));
+        assert!(all.iter().all(|m| m.raw == bytes[7..] && m.message.is_ok()));
+        assert_eq!(*closed.lock().unwrap(), vec![1]);
+    }
+}
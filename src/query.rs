@@ -0,0 +1,373 @@
+use std::{collections::HashMap, str::FromStr};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::{
+    message::Message,
+    registry::{self, ParameterOwner},
+    serialization::{op_code::Service, ParameterTable, Value},
+    value_diff::{PathSegment, ValuePath},
+};
+
+// A small JSONPath-like query over parameter tables and values, e.g. `params[245].robots[*].name`
+//
+// `params` (or `$`) is the thing being queried. After that:
+//   .name / ["name"]  dictionary key, or a parameter by its registry name on a parameter table
+//   [3]               array index, integer dictionary key or parameter code
+//   [*] / .*          every element / value / parameter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query {
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Name(String),
+    Index(i64),
+    Wildcard,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Selected {
+    pub path: ValuePath,
+    pub value: Value,
+}
+
+impl FromStr for Query {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let rest = s.trim();
+        let mut rest = rest
+            .strip_prefix("params")
+            .or_else(|| rest.strip_prefix('$'))
+            .ok_or_else(|| anyhow!("query has to start with params or $: {}", s))?;
+        let mut segments = Vec::new();
+        while !rest.is_empty() {
+            if let Some(r) = rest.strip_prefix('.') {
+                if let Some(r) = r.strip_prefix('*') {
+                    segments.push(Segment::Wildcard);
+                    rest = r;
+                    continue;
+                }
+                let end = r
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(r.len());
+                if end == 0 {
+                    bail!("expected a name after '.' in {}", s);
+                }
+                segments.push(Segment::Name(r[..end].to_string()));
+                rest = &r[end..];
+            } else if let Some(r) = rest.strip_prefix('[') {
+                let (segment, r) = parse_bracket(r).ok_or_else(|| anyhow!("bad [...] in {}", s))?;
+                segments.push(segment);
+                rest = r;
+            } else {
+                bail!("unexpected {:?} in query {}", rest, s);
+            }
+        }
+        Ok(Self { segments })
+    }
+}
+
+fn parse_bracket(s: &str) -> Option<(Segment, &str)> {
+    if let Some(r) = s.strip_prefix('"') {
+        let mut name = String::new();
+        let mut chars = r.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => name.push(chars.next()?.1),
+                '"' => return Some((Segment::Name(name), r[i + 1..].strip_prefix(']')?)),
+                c => name.push(c),
+            }
+        }
+        return None;
+    }
+    let end = s.find(']')?;
+    let inner = s[..end].trim();
+    let segment = if inner == "*" {
+        Segment::Wildcard
+    } else if let Ok(i) = inner.parse() {
+        Segment::Index(i)
+    } else if !inner.is_empty() {
+        Segment::Name(inner.to_string())
+    } else {
+        return None;
+    };
+    Some((segment, &s[end + 1..]))
+}
+
+// Something a segment can be applied to. Parameter tables know who they belong to so names can be looked up.
+// Nodes borrow from the root, only elements of typed arrays (strings, ints, bytes) aren't Values to borrow and
+// are made into one. Those have nothing below them, so they can't be stepped into anyway
+enum Node<'a> {
+    Table(&'a ParameterTable, Option<ParameterOwner>),
+    Value(&'a Value),
+    Element(Value),
+}
+
+impl Query {
+    pub fn parse(s: &str) -> Result<Self> {
+        s.parse()
+    }
+    pub fn select_value(&self, value: &Value) -> Vec<Selected> {
+        self.select(Node::Value(value))
+    }
+    // owner is used to resolve parameter names, without it only codes work
+    pub fn select_parameters(
+        &self,
+        table: &ParameterTable,
+        owner: Option<ParameterOwner>,
+    ) -> Vec<Selected> {
+        self.select(Node::Table(table, owner))
+    }
+    pub fn select_message(&self, message: &Message) -> Vec<Selected> {
+        match message {
+            Message::OperationRequest(r) => {
+                self.select_parameters(r.parameters(), Some(ParameterOwner::Operation(r.opcode())))
+            }
+            Message::OperationResponse(r) => {
+                self.select_parameters(r.parameters(), Some(ParameterOwner::Operation(r.opcode())))
+            }
+            Message::Event(e) => {
+                self.select_parameters(e.params(), Some(ParameterOwner::Event(e.event_code())))
+            }
+            Message::InternalOperationRequest(r) => self.select_parameters(r.parameters(), None),
+            Message::InternalOperationResponse(r) => self.select_parameters(r.parameters(), None),
+            _ => Vec::new(),
+        }
+    }
+
+    fn select(&self, root: Node) -> Vec<Selected> {
+        let mut current = vec![(ValuePath::default(), root)];
+        for segment in &self.segments {
+            let mut next = Vec::new();
+            for (path, node) in &current {
+                step(path, node, segment, &mut next);
+            }
+            current = next;
+        }
+        current
+            .into_iter()
+            .filter_map(|(path, node)| {
+                let value = match node {
+                    Node::Value(v) => v.clone(),
+                    Node::Element(v) => v,
+                    // selecting the whole table isn't useful enough to convert it back into a Value
+                    Node::Table(..) => return None,
+                };
+                Some(Selected { path, value })
+            })
+            .collect()
+    }
+}
+
+fn child(path: &ValuePath, segment: PathSegment) -> ValuePath {
+    let mut v = path.0.clone();
+    v.push(segment);
+    ValuePath(v)
+}
+
+fn step<'a>(
+    path: &ValuePath,
+    node: &Node<'a>,
+    segment: &Segment,
+    out: &mut Vec<(ValuePath, Node<'a>)>,
+) {
+    let value = match node {
+        Node::Table(table, owner) => return step_table(path, table, *owner, segment, out),
+        Node::Value(v) => *v,
+        Node::Element(_) => return,
+    };
+    match value {
+        Value::OperationRequest(r) => step_table(
+            path,
+            r.parameters(),
+            Some(ParameterOwner::Operation(r.opcode())),
+            segment,
+            out,
+        ),
+        Value::OperationResponse(r) => step_table(
+            path,
+            r.parameters(),
+            Some(ParameterOwner::Operation(r.opcode())),
+            segment,
+            out,
+        ),
+        Value::EventData(e) => step_table(
+            path,
+            e.params(),
+            Some(ParameterOwner::Event(e.event_code())),
+            segment,
+            out,
+        ),
+        Value::Dictionary(d) => step_map(path, d, segment, out),
+        Value::HashTable(h) => step_map(path, h, segment, out),
+        Value::Array(a) => step_sequence(path, a.iter().map(Node::Value), segment, out),
+        Value::ObjectArray(a) => step_sequence(path, a.iter().map(Node::Value), segment, out),
+        Value::StringArray(a) => step_sequence(
+            path,
+            a.iter().map(|s| Node::Element(Value::String(s.clone()))),
+            segment,
+            out,
+        ),
+        Value::IntegerArray(a) => step_sequence(
+            path,
+            a.iter().map(|i| Node::Element(Value::Int(*i))),
+            segment,
+            out,
+        ),
+        Value::ByteArray(a) => step_sequence(
+            path,
+            a.iter().map(|b| Node::Element(Value::Byte(*b))),
+            segment,
+            out,
+        ),
+        _ => {}
+    }
+}
+
+fn step_table<'a>(
+    path: &ValuePath,
+    table: &'a ParameterTable,
+    owner: Option<ParameterOwner>,
+    segment: &Segment,
+    out: &mut Vec<(ValuePath, Node<'a>)>,
+) {
+    let codes: Vec<u8> = match segment {
+        Segment::Wildcard => {
+            let mut codes: Vec<u8> = table.keys().copied().collect();
+            codes.sort();
+            codes
+        }
+        Segment::Index(i) => u8::try_from(*i).into_iter().collect(),
+        Segment::Name(name) => owner
+            .and_then(|o| registry::global().code(Service::WebServices, o, name))
+            .into_iter()
+            .collect(),
+    };
+    for code in codes {
+        if let Some(v) = table.get(&code) {
+            out.push((child(path, PathSegment::Parameter(code)), Node::Value(v)));
+        }
+    }
+}
+
+fn step_map<'a>(
+    path: &ValuePath,
+    map: &'a HashMap<Value, Value>,
+    segment: &Segment,
+    out: &mut Vec<(ValuePath, Node<'a>)>,
+) {
+    let mut found: Vec<(&Value, &'a Value)> = match segment {
+        Segment::Wildcard => map.iter().collect(),
+        Segment::Name(name) => map
+            .iter()
+            .filter(|(k, _)| matches!(k, Value::String(s) if s == name))
+            .collect(),
+        Segment::Index(i) => map
+            .iter()
+            .filter(|(k, _)| integer_key(k) == Some(*i))
+            .collect(),
+    };
+    found.sort_by_cached_key(|(k, _)| format!("{:?}", k));
+    for (k, v) in found {
+        out.push((child(path, PathSegment::Key(k.clone())), Node::Value(v)));
+    }
+}
+
+fn integer_key(v: &Value) -> Option<i64> {
+    match v {
+        Value::Byte(b) => Some(*b as i64),
+        Value::Short(s) => Some(*s as i64),
+        Value::Int(i) => Some(*i as i64),
+        Value::Long(l) => Some(*l),
+        _ => None,
+    }
+}
+
+fn step_sequence<'a>(
+    path: &ValuePath,
+    mut elements: impl ExactSizeIterator<Item = Node<'a>>,
+    segment: &Segment,
+    out: &mut Vec<(ValuePath, Node<'a>)>,
+) {
+    let len = elements.len() as i64;
+    match segment {
+        Segment::Wildcard => {
+            for (i, v) in elements.enumerate() {
+                out.push((child(path, PathSegment::Index(i)), v));
+            }
+        }
+        // negative indices count from the end
+        Segment::Index(i) => {
+            let i = if *i < 0 { len + i } else { *i };
+            if let Ok(i) = usize::try_from(i) {
+                if let Some(v) = elements.nth(i) {
+                    out.push((child(path, PathSegment::Index(i)), v));
+                }
+            }
+        }
+        Segment::Name(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::Query;
+    use crate::{
+        message::Message,
+        serialization::{OperationResponse, Value},
+        util::HashableHashmap,
+    };
+
+    #[test]
+    fn test_select() {
+        let robot = |name: &str| {
+            Value::Dictionary(HashableHashmap(HashMap::from([(
+                Value::String("name".into()),
+                Value::String(name.into()),
+            )])))
+        };
+        let robots = Value::Dictionary(HashableHashmap(HashMap::from([(
+            Value::String("robots".into()),
+            Value::Array(vec![robot("a"), robot("b")]),
+        )])));
+        let message = Message::OperationResponse(OperationResponse::new(
+            230,
+            0,
+            Value::Null(()),
+            HashMap::from([(221, Value::Long(5)), (245, robots)]).into(),
+        ));
+        let select = |q: &str| {
+            Query::parse(q)
+                .unwrap()
+                .select_message(&message)
+                .into_iter()
+                .map(|s| (s.path.to_string(), s.value))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            select("params[245].robots[*].name"),
+            vec![
+                (
+                    "params[245].robots[0].name".to_string(),
+                    Value::String("a".into())
+                ),
+                (
+                    "params[245].robots[1].name".to_string(),
+                    Value::String("b".into())
+                ),
+            ]
+        );
+        assert_eq!(
+            select("params.Secret"),
+            vec![("params[221]".to_string(), Value::Long(5))]
+        );
+        assert_eq!(select("params[245][\"robots\"][-1].name").len(), 1);
+        assert!(select("params[2]").is_empty());
+        assert!(Query::parse("robots[").is_err());
+    }
+}
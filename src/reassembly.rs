@@ -0,0 +1,225 @@
+use std::{collections::HashMap, net::SocketAddrV4};
+
+use crate::{capture::Segment, message::Direction};
+
+// Putting the TCP segments of a capture back into one byte stream per connection and direction. Captures have
+// the segments of every connection interleaved, and any of them can come out of order, twice or overlapping;
+// the framer needs each stream on its own and in order.
+
+// Out of order data held back waiting for a segment that never comes (the capture dropped it). Past this much
+// the hole is skipped and the framer resyncs after it
+const MAX_BUFFERED: usize = 1024 * 1024;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reassembled {
+    // the next bytes of one direction of a connection
+    Data {
+        connection: u32,
+        direction: Direction,
+        bytes: Vec<u8>,
+    },
+    // both ends sent FIN and everything before it was handed out, or one sent RST
+    Closed(u32),
+}
+
+// Connections are told apart by their addresses and ports and numbered in the order they're first seen
+#[derive(Default)]
+pub struct Reassembler {
+    connections: HashMap<(SocketAddrV4, SocketAddrV4), Connection>,
+    next_connection: u32,
+}
+
+struct Connection {
+    id: u32,
+    outgoing: Stream,
+    incoming: Stream,
+}
+
+#[derive(Default)]
+struct Stream {
+    // sequence number of the next byte to hand out, None until the first segment. A capture that starts in the
+    // middle of a connection starts the stream at whatever segment comes first
+    next: Option<u32>,
+    // segments that can't be handed out yet, with their sequence numbers
+    pending: Vec<(u32, Vec<u8>)>,
+    buffered: usize,
+    // the sequence number of the FIN, the stream is done once everything before it was handed out
+    fin: Option<u32>,
+}
+
+// how far `seq` is past `next`, negative when it's before. Sequence numbers wrap around
+fn offset(seq: u32, next: u32) -> i32 {
+    seq.wrapping_sub(next) as i32
+}
+
+impl Stream {
+    // Returns the bytes that are now in order, if any
+    fn push(&mut self, seq: u32, payload: &[u8]) -> Vec<u8> {
+        let mut next = *self.next.get_or_insert(seq);
+        if !payload.is_empty() {
+            self.pending.push((seq, payload.to_vec()));
+            self.buffered += payload.len();
+        }
+        let mut out = Vec::new();
+        loop {
+            // anything starting at or before `next` can go, minus what was already handed out
+            while let Some(i) = self.pending.iter().position(|(s, _)| offset(*s, next) <= 0) {
+                let (seq, data) = self.pending.swap_remove(i);
+                self.buffered -= data.len();
+                let seen = offset(next, seq) as usize;
+                if seen < data.len() {
+                    out.extend_from_slice(&data[seen..]);
+                    next = next.wrapping_add((data.len() - seen) as u32);
+                }
+            }
+            if self.buffered <= MAX_BUFFERED {
+                break;
+            }
+            let Some(closest) = self
+                .pending
+                .iter()
+                .map(|(s, _)| *s)
+                .min_by_key(|s| offset(*s, next))
+            else {
+                break;
+            };
+            next = closest;
+        }
+        self.next = Some(next);
+        out
+    }
+    fn done(&self) -> bool {
+        self.fin.is_some() && self.fin == self.next
+    }
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn push(&mut self, segment: Segment) -> Vec<Reassembled> {
+        let key = (segment.client, segment.server);
+        if segment.rst {
+            return self
+                .connections
+                .remove(&key)
+                .map(|c| Reassembled::Closed(c.id))
+                .into_iter()
+                .collect();
+        }
+        let next_connection = &mut self.next_connection;
+        let connection = self.connections.entry(key).or_insert_with(|| {
+            let id = *next_connection;
+            *next_connection = next_connection.wrapping_add(1);
+            Connection {
+                id,
+                outgoing: Stream::default(),
+                incoming: Stream::default(),
+            }
+        });
+        let stream = match segment.direction {
+            Direction::Outgoing => &mut connection.outgoing,
+            Direction::Incoming => &mut connection.incoming,
+        };
+        // the SYN takes up a sequence number of its own
+        let seq = match segment.syn {
+            true => segment.seq.wrapping_add(1),
+            false => segment.seq,
+        };
+        let bytes = stream.push(seq, &segment.payload);
+        if segment.fin {
+            stream.fin = Some(seq.wrapping_add(segment.payload.len() as u32));
+        }
+        let mut out = Vec::new();
+        if !bytes.is_empty() {
+            out.push(Reassembled::Data {
+                connection: connection.id,
+                direction: segment.direction,
+                bytes,
+            });
+        }
+        if connection.outgoing.done() && connection.incoming.done() {
+            out.push(Reassembled::Closed(connection.id));
+            self.connections.remove(&key);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use super::{Reassembled, Reassembler};
+    use crate::{capture::Segment, message::Direction};
+
+    fn segment(client_port: u16, direction: Direction, seq: u32, payload: &[u8]) -> Segment {
+        Segment {
+            direction,
+            client: SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), client_port),
+            server: SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 4533),
+            seq,
+            syn: false,
+            fin: false,
+            rst: false,
+            payload: payload.to_vec(),
+        }
+    }
+
+    fn data(connection: u32, direction: Direction, bytes: &[u8]) -> Reassembled {
+        Reassembled::Data {
+            connection,
+            direction,
+            bytes: bytes.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_reassembly() {
+        let mut r = Reassembler::new();
+        let out = Direction::Outgoing;
+        // a handshake, then data out of order, retransmitted and overlapping, across the sequence number wrap
+        let syn = Segment {
+            syn: true,
+            ..segment(1, out, u32::MAX - 2, b"")
+        };
+        assert_eq!(r.push(syn), vec![]);
+        assert_eq!(r.push(segment(1, out, 2, b"ef")), vec![]);
+        // another connection in between gets its own id
+        assert_eq!(
+            r.push(segment(2, out, 500, b"xy")),
+            vec![data(1, out, b"xy")]
+        );
+        assert_eq!(
+            r.push(segment(1, out, u32::MAX - 1, b"ab")),
+            vec![data(0, out, b"ab")]
+        );
+        assert_eq!(r.push(segment(1, out, u32::MAX - 1, b"ab")), vec![]);
+        assert_eq!(
+            r.push(segment(1, out, u32::MAX, b"bcde")),
+            vec![data(0, out, b"cdef")]
+        );
+
+        // closed once both ends' FINs are reached, or straight away by a RST
+        let fin = |direction, seq| Segment {
+            fin: true,
+            ..segment(1, direction, seq, b"")
+        };
+        assert_eq!(r.push(fin(out, 4)), vec![]);
+        assert_eq!(
+            r.push(segment(1, Direction::Incoming, 70, b"g")),
+            vec![data(0, Direction::Incoming, b"g")]
+        );
+        assert_eq!(
+            r.push(fin(Direction::Incoming, 71)),
+            vec![Reassembled::Closed(0)]
+        );
+        let rst = Segment {
+            rst: true,
+            ..segment(2, out, 502, b"")
+        };
+        assert_eq!(r.push(rst), vec![Reassembled::Closed(1)]);
+        // the same ports again are a new connection
+        assert_eq!(r.push(segment(1, out, 9, b"h")), vec![data(2, out, b"h")]);
+    }
+}
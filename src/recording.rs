@@ -0,0 +1,213 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    capture::read_or_eof,
+    message::{decode_message, Direction, Message},
+};
+
+// Native session recordings. Unlike a pcap these hold framed messages (no TCP/IP, no pings), so they can be
+// replayed through the decoder as-is.
+//
+// File layout: the 8 byte MAGIC, a u16 version, then records until EOF. All integers little endian.
+// record: u64 timestamp (µs since the unix epoch), u8 direction (0 in, 1 out), u32 connection id,
+//         u8 flags (1 = has decrypted bytes), u32 length + raw message bytes (starting at 0xF3),
+//         [u32 length + decrypted message bytes]
+pub const MAGIC: &[u8; 8] = b"RCSNIFF\0";
+const VERSION: u16 = 1;
+const FLAG_DECRYPTED: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecordedFrame {
+    // since the unix epoch
+    pub timestamp: Duration,
+    pub direction: Direction,
+    // tells apart connections that were recorded at the same time
+    pub connection: u32,
+    pub raw: Vec<u8>,
+    // the same message after decryption, when the recorder had the key
+    pub decrypted: Option<Vec<u8>>,
+}
+
+impl RecordedFrame {
+    // the bytes to feed to the decoder
+    pub fn message_bytes(&self) -> &[u8] {
+        self.decrypted.as_deref().unwrap_or(&self.raw)
+    }
+    pub fn decode(&self) -> Result<Message> {
+        decode_message(self.message_bytes())
+    }
+}
+
+pub struct RecordingWriter<W: Write> {
+    writer: W,
+}
+
+impl RecordingWriter<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let f = File::create(path).context(format!("creating recording {}", path.display()))?;
+        Self::new(BufWriter::new(f))
+    }
+}
+
+impl<W: Write> RecordingWriter<W> {
+    pub fn new(mut writer: W) -> Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        Ok(Self { writer })
+    }
+    pub fn write(&mut self, frame: &RecordedFrame) -> Result<()> {
+        let w = &mut self.writer;
+        w.write_all(&(frame.timestamp.as_micros() as u64).to_le_bytes())?;
+        w.write_all(&[match frame.direction {
+            Direction::Incoming => 0,
+            Direction::Outgoing => 1,
+        }])?;
+        w.write_all(&frame.connection.to_le_bytes())?;
+        w.write_all(&[if frame.decrypted.is_some() {
+            FLAG_DECRYPTED
+        } else {
+            0
+        }])?;
+        w.write_all(&(frame.raw.len() as u32).to_le_bytes())?;
+        w.write_all(&frame.raw)?;
+        if let Some(d) = &frame.decrypted {
+            w.write_all(&(d.len() as u32).to_le_bytes())?;
+            w.write_all(d)?;
+        }
+        Ok(())
+    }
+    pub fn flush(&mut self) -> Result<()> {
+        Ok(self.writer.flush()?)
+    }
+}
+
+pub struct RecordingReader<R: Read> {
+    reader: R,
+}
+
+impl RecordingReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let f = File::open(path).context(format!("opening recording {}", path.display()))?;
+        Self::new(BufReader::new(f))
+    }
+}
+
+impl<R: Read> RecordingReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut header = [0u8; 10];
+        reader
+            .read_exact(&mut header)
+            .context("reading recording header")?;
+        if &header[..8] != MAGIC {
+            bail!("not an rcsniff2 recording");
+        }
+        let version = u16::from_le_bytes([header[8], header[9]]);
+        if version != VERSION {
+            bail!("unsupported recording version {}", version);
+        }
+        Ok(Self { reader })
+    }
+    pub fn next_frame(&mut self) -> Result<Option<RecordedFrame>> {
+        let mut header = [0u8; 18];
+        if !read_or_eof(&mut self.reader, &mut header)? {
+            return Ok(None);
+        }
+        let timestamp = Duration::from_micros(u64::from_le_bytes(header[0..8].try_into()?));
+        let direction = match header[8] {
+            0 => Direction::Incoming,
+            1 => Direction::Outgoing,
+            d => bail!("invalid direction {} in recording", d),
+        };
+        let connection = u32::from_le_bytes(header[9..13].try_into()?);
+        let flags = header[13];
+        let raw = self.read_bytes(u32::from_le_bytes(header[14..18].try_into()?))?;
+        let decrypted = if flags & FLAG_DECRYPTED != 0 {
+            let mut len = [0u8; 4];
+            self.reader.read_exact(&mut len)?;
+            Some(self.read_bytes(u32::from_le_bytes(len))?)
+        } else {
+            None
+        };
+        Ok(Some(RecordedFrame {
+            timestamp,
+            direction,
+            connection,
+            raw,
+            decrypted,
+        }))
+    }
+    fn read_bytes(&mut self, len: u32) -> Result<Vec<u8>> {
+        let mut v = Vec::new();
+        (&mut self.reader).take(len as u64).read_to_end(&mut v)?;
+        if v.len() != len as usize {
+            bail!("recording ends in the middle of a frame");
+        }
+        Ok(v)
+    }
+}
+
+pub fn read_recording(path: impl AsRef<Path>) -> Result<Vec<RecordedFrame>> {
+    let mut reader = RecordingReader::open(path)?;
+    let mut frames = Vec::new();
+    while let Some(frame) = reader.next_frame()? {
+        frames.push(frame);
+    }
+    Ok(frames)
+}
+
+// So commands that take a capture can take either
+pub fn is_recording(path: impl AsRef<Path>) -> bool {
+    let mut magic = [0u8; 8];
+    File::open(path)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .is_ok()
+        && &magic == MAGIC
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{RecordedFrame, RecordingReader, RecordingWriter};
+    use crate::message::Direction;
+
+    #[test]
+    fn test_roundtrip() {
+        let frames = vec![
+            RecordedFrame {
+                timestamp: Duration::from_micros(1_700_000_000_123_456),
+                direction: Direction::Outgoing,
+                connection: 1,
+                raw: vec![0xF3, 2, 66, 0, 0],
+                decrypted: None,
+            },
+            RecordedFrame {
+                timestamp: Duration::from_micros(1_700_000_000_200_000),
+                direction: Direction::Incoming,
+                connection: 1,
+                raw: vec![0xF3, 0x83, 1, 2, 3],
+                decrypted: Some(vec![0xF3, 3, 66, 0, 0, 0x2A, 0, 0]),
+            },
+        ];
+        let mut w = RecordingWriter::new(Vec::new()).unwrap();
+        for f in &frames {
+            w.write(f).unwrap();
+        }
+        let mut r = RecordingReader::new(&w.writer[..]).unwrap();
+        let mut read = Vec::new();
+        while let Some(f) = r.next_frame().unwrap() {
+            read.push(f);
+        }
+        assert_eq!(read, frames);
+        assert!(read[1].decode().is_ok());
+    }
+}
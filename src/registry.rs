@@ -0,0 +1,291 @@
+use std::{collections::HashMap, fmt::Debug, path::Path, str::FromStr, sync::OnceLock};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::serialization::{
+    event_code::WebServicesEventCode, op_code::Service, op_code::WebServicesOpCode,
+    type_code::TypeCode, ParameterTable,
+};
+
+// The table that ships with the binary. Anything loaded from a file is layered on top of it
+const DEFAULT_PARAMETERS: &str = include_str!("../data/parameters.toml");
+
+static GLOBAL: OnceLock<ParameterRegistry> = OnceLock::new();
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParameterInfo {
+    pub name: String,
+    pub type_code: Option<TypeCode>,
+}
+
+// What a parameter table belongs to. Operation requests and responses share their parameter codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ParameterOwner {
+    Operation(u8),
+    Event(u8),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ParameterRegistry {
+    entries: HashMap<(Service, ParameterOwner, u8), ParameterInfo>,
+    // name -> the codes that have it in the order they got it, lookups take the last one
+    codes: HashMap<(Service, ParameterOwner, String), Vec<u8>>,
+    // the game's own events, the ones photon defines are in WebServicesEventCode
+    events: HashMap<(Service, u8), String>,
+}
+
+#[derive(Deserialize)]
+struct RegistryFile {
+    #[serde(default)]
+    event: Vec<EventEntry>,
+    #[serde(default)]
+    parameter: Vec<RegistryEntry>,
+}
+#[derive(Deserialize)]
+struct EventEntry {
+    service: String,
+    code: u8,
+    name: String,
+}
+#[derive(Deserialize)]
+struct RegistryEntry {
+    service: String,
+    operation: Option<CodeOrName>,
+    event: Option<CodeOrName>,
+    code: u8,
+    name: String,
+    #[serde(rename = "type")]
+    type_code: Option<String>,
+}
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum CodeOrName {
+    Code(u8),
+    Name(String),
+}
+
+impl ParameterRegistry {
+    pub fn builtin() -> Self {
+        let mut r = Self::default();
+        r.load_toml(DEFAULT_PARAMETERS)
+            .expect("builtin parameter table is invalid");
+        r
+    }
+    // picks the format from the file extension, everything that isn't .json is treated as toml
+    pub fn load_file(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .context(format!("reading parameter table {}", path.display()))?;
+        if path.extension().is_some_and(|e| e == "json") {
+            self.load_json(&text)
+        } else {
+            self.load_toml(&text)
+        }
+        .context(format!("loading parameter table {}", path.display()))
+    }
+    pub fn load_toml(&mut self, text: &str) -> Result<()> {
+        self.load(toml::from_str(text)?)
+    }
+    pub fn load_json(&mut self, text: &str) -> Result<()> {
+        self.load(serde_json::from_str(text)?)
+    }
+    fn load(&mut self, file: RegistryFile) -> Result<()> {
+        // events first, parameters can refer to them by name
+        for entry in file.event {
+            let service = Service::from_str(&entry.service)
+                .context(format!("unknown service {}", entry.service))?;
+            self.events.insert((service, entry.code), entry.name);
+        }
+        for entry in file.parameter {
+            let service = Service::from_str(&entry.service)
+                .context(format!("unknown service {}", entry.service))?;
+            let owner = match (&entry.operation, &entry.event) {
+                (Some(op), None) => ParameterOwner::Operation(resolve_opcode(service, op)?),
+                (None, Some(ev)) => ParameterOwner::Event(self.resolve_event_code(service, ev)?),
+                _ => bail!(
+                    "parameter {} ({}) needs exactly one of operation or event",
+                    entry.code,
+                    entry.name
+                ),
+            };
+            let type_code = match &entry.type_code {
+                Some(t) => Some(TypeCode::from_str(t).context(format!("unknown type code {}", t))?),
+                None => None,
+            };
+            self.insert(
+                service,
+                owner,
+                entry.code,
+                ParameterInfo {
+                    name: entry.name,
+                    type_code,
+                },
+            );
+        }
+        Ok(())
+    }
+    pub fn insert(
+        &mut self,
+        service: Service,
+        owner: ParameterOwner,
+        code: u8,
+        info: ParameterInfo,
+    ) {
+        // a renamed code can't be found by its old name anymore, other codes with that name still can
+        if let Some(old) = self.entries.get(&(service, owner, code)) {
+            let key = (service, owner, old.name.clone());
+            if let Some(codes) = self.codes.get_mut(&key) {
+                codes.retain(|c| *c != code);
+                if codes.is_empty() {
+                    self.codes.remove(&key);
+                }
+            }
+        }
+        self.codes
+            .entry((service, owner, info.name.clone()))
+            .or_default()
+            .push(code);
+        self.entries.insert((service, owner, code), info);
+    }
+    pub fn get(&self, service: Service, owner: ParameterOwner, code: u8) -> Option<&ParameterInfo> {
+        self.entries.get(&(service, owner, code))
+    }
+    pub fn name(&self, service: Service, owner: ParameterOwner, code: u8) -> Option<&str> {
+        self.get(service, owner, code).map(|i| i.name.as_str())
+    }
+    // reverse lookup, used when a parameter is referred to by name (queries, filters)
+    pub fn code(&self, service: Service, owner: ParameterOwner, name: &str) -> Option<u8> {
+        self.codes
+            .get(&(service, owner, name.to_string()))
+            .and_then(|codes| codes.last())
+            .copied()
+    }
+    // photon's name for its own events, the table's for the game's
+    pub fn event_name(&self, service: Service, code: u8) -> Option<String> {
+        match service {
+            Service::WebServices => WebServicesEventCode::from_repr(code)
+                .map(|e| format!("{:?}", e))
+                .or_else(|| self.events.get(&(service, code)).cloned()),
+        }
+    }
+    pub fn event_code(&self, service: Service, name: &str) -> Option<u8> {
+        match service {
+            Service::WebServices => WebServicesEventCode::from_str(name)
+                .ok()
+                .map(|e| e as u8)
+                .or_else(|| {
+                    self.events
+                        .iter()
+                        .find(|((s, _), n)| *s == service && *n == name)
+                        .map(|((_, code), _)| *code)
+                }),
+        }
+    }
+    fn resolve_event_code(&self, service: Service, ev: &CodeOrName) -> Result<u8> {
+        match ev {
+            CodeOrName::Code(c) => Ok(*c),
+            CodeOrName::Name(n) => self
+                .event_code(service, n)
+                .context(format!("unknown {:?} event code {}", service, n)),
+        }
+    }
+    pub fn parameters(
+        &self,
+        service: Service,
+        owner: ParameterOwner,
+    ) -> impl Iterator<Item = (u8, &ParameterInfo)> {
+        self.entries
+            .iter()
+            .filter(move |((s, o, _), _)| *s == service && *o == owner)
+            .map(|((_, _, code), info)| (*code, info))
+    }
+}
+
+pub(crate) fn resolve_opcode(service: Service, op: &CodeOrName) -> Result<u8> {
+    match (service, op) {
+        (_, CodeOrName::Code(c)) => Ok(*c),
+        (Service::WebServices, CodeOrName::Name(n)) => Ok(WebServicesOpCode::from_str(n)
+            .context(format!("unknown WebServices opcode {}", n))?
+            as u8),
+    }
+}
+pub(crate) fn resolve_event_code(service: Service, ev: &CodeOrName) -> Result<u8> {
+    global().resolve_event_code(service, ev)
+}
+
+// Install the registry used by Debug output. Must be called before anything gets printed, returns false if it was already set
+pub fn install(registry: ParameterRegistry) -> bool {
+    GLOBAL.set(registry).is_ok()
+}
+pub fn global() -> &'static ParameterRegistry {
+    GLOBAL.get_or_init(ParameterRegistry::builtin)
+}
+// The name output shows for a parameter, owner is None for tables that don't belong to a WebServices operation or
+// event (internal operations)
+pub fn parameter_name(owner: Option<ParameterOwner>, code: u8) -> Option<&'static str> {
+    owner.and_then(|o| global().name(Service::WebServices, o, code))
+}
+// What everything that prints or parses event names goes through, so the game's events from the table are named
+// everywhere photon's are
+pub fn event_name(code: u8) -> Option<String> {
+    global().event_name(Service::WebServices, code)
+}
+pub fn event_code(name: &str) -> Option<u8> {
+    global().event_code(Service::WebServices, name)
+}
+
+// Debug wrapper that prints a parameter table with the registered names next to the codes
+pub struct NamedParameters<'a> {
+    pub table: &'a ParameterTable,
+    pub service: Service,
+    pub owner: ParameterOwner,
+}
+impl Debug for NamedParameters<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let registry = global();
+        let mut m = f.debug_map();
+        for (code, value) in self.table.iter() {
+            match registry.name(self.service, self.owner, *code) {
+                Some(name) => m.entry(&format_args!("{}({})", name, code), value),
+                None => m.entry(code, value),
+            };
+        }
+        m.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ParameterOwner, ParameterRegistry};
+    use crate::serialization::{op_code::Service, op_code::WebServicesOpCode, type_code::TypeCode};
+
+    #[test]
+    fn test_builtin_and_override() {
+        let mut r = ParameterRegistry::builtin();
+        let auth = ParameterOwner::Operation(WebServicesOpCode::Authenticate as u8);
+        assert_eq!(r.name(Service::WebServices, auth, 220), Some("AppVersion"));
+        r.load_json(
+            r#"{"parameter": [{"service": "WebServices", "operation": 230, "code": 220, "name": "Version", "type": "String"}]}"#,
+        )
+        .unwrap();
+        let info = r.get(Service::WebServices, auth, 220).unwrap();
+        assert_eq!(info.name, "Version");
+        assert_eq!(info.type_code, Some(TypeCode::String));
+        assert_eq!(r.code(Service::WebServices, auth, "Version"), Some(220));
+        assert_eq!(r.code(Service::WebServices, auth, "AppVersion"), None);
+        // a later file giving another code the same name takes the name over
+        r.load_toml(
+            "[[parameter]]\nservice = \"WebServices\"\noperation = \"Authenticate\"\ncode = 221\nname = \"Version\"\n",
+        )
+        .unwrap();
+        assert_eq!(r.code(Service::WebServices, auth, "Version"), Some(221));
+        // renaming 221 gives the name back to 220, which still has it
+        r.load_toml(
+            "[[parameter]]\nservice = \"WebServices\"\noperation = \"Authenticate\"\ncode = 221\nname = \"Secret\"\n",
+        )
+        .unwrap();
+        assert_eq!(r.code(Service::WebServices, auth, "Version"), Some(220));
+        assert_eq!(r.code(Service::WebServices, auth, "Secret"), Some(221));
+    }
+}
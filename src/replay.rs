@@ -0,0 +1,483 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    spawn,
+    sync::mpsc::unbounded_channel,
+    time::{sleep, timeout},
+};
+
+use crate::{
+    capture::CapturedMessage,
+    framing::{frame_message, FrameReader},
+    input,
+    message::{decode_message, encode_message, is_encrypted, Direction, Message},
+    output::opcode_name,
+    registry::{self, ParameterOwner},
+    serialization::{
+        op_code::{Service, WebServicesOpCode},
+        OperationRequest, OperationResponse, ParameterTable, Value,
+    },
+    value_diff::{self, diff_parameters, ValueChange},
+};
+
+// Sends the client side of a recorded session to a server again and compares what comes back with what the
+// server answered in the recording. Responses are matched up by opcode and then by order, the server is free to
+// interleave different opcodes differently than it did before.
+
+// "[Opcode.]Parameter=value". Opcode and parameter can be codes or names, the value is parsed as the type the
+// parameter had in the recording
+#[derive(Debug, Clone)]
+pub struct Substitution {
+    opcode: Option<u8>,
+    parameter: ParameterRef,
+    value: String,
+}
+
+#[derive(Debug, Clone)]
+enum ParameterRef {
+    Code(u8),
+    Name(String),
+}
+
+impl FromStr for Substitution {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        let (target, value) = s
+            .split_once('=')
+            .context("expected [Opcode.]Parameter=value")?;
+        let (opcode, parameter) = match target.split_once('.') {
+            Some((op, p)) => (
+                Some(
+                    op.parse::<u8>()
+                        .or_else(|_| WebServicesOpCode::from_str(op).map(|o| o as u8))
+                        .map_err(|_| anyhow!("unknown opcode {}", op))?,
+                ),
+                p,
+            ),
+            None => (None, target),
+        };
+        if parameter.is_empty() {
+            bail!("no parameter given in {:?}", s);
+        }
+        Ok(Self {
+            opcode,
+            parameter: match parameter.parse() {
+                Ok(code) => ParameterRef::Code(code),
+                Err(_) => ParameterRef::Name(parameter.to_string()),
+            },
+            value: value.to_string(),
+        })
+    }
+}
+
+impl Substitution {
+    // None if the request doesn't have the parameter
+    pub fn apply(&self, request: &OperationRequest) -> Result<Option<OperationRequest>> {
+        if self.opcode.is_some_and(|o| o != request.opcode()) {
+            return Ok(None);
+        }
+        let code = match &self.parameter {
+            ParameterRef::Code(code) => *code,
+            ParameterRef::Name(name) => match registry::global().code(
+                Service::WebServices,
+                ParameterOwner::Operation(request.opcode()),
+                name,
+            ) {
+                Some(code) => code,
+                None => return Ok(None),
+            },
+        };
+        let Some(old) = request.parameters().get(&code) else {
+            return Ok(None);
+        };
+        let new = parse_like(old, &self.value).with_context(|| {
+            format!(
+                "substituting parameter {} of {}",
+                code,
+                opcode_name(request.opcode())
+            )
+        })?;
+        let mut params: HashMap<u8, Value> = request
+            .parameters()
+            .iter()
+            .map(|(k, v)| (*k, v.clone()))
+            .collect();
+        params.insert(code, new);
+        Ok(Some(OperationRequest::new(
+            request.opcode(),
+            ParameterTable::from(params),
+        )))
+    }
+}
+
+fn parse_like(old: &Value, text: &str) -> Result<Value> {
+    Ok(match old {
+        Value::Byte(_) => Value::Byte(text.parse()?),
+        Value::Bool(_) => Value::Bool(text.parse()?),
+        Value::Short(_) => Value::Short(text.parse()?),
+        Value::Int(_) => Value::Int(text.parse()?),
+        Value::Long(_) => Value::Long(text.parse()?),
+        Value::Float(_) => Value::Float(text.parse::<f32>()?.into()),
+        Value::Double(_) => Value::Double(text.parse::<f64>()?.into()),
+        Value::String(_) => Value::String(text.to_string()),
+        Value::ByteArray(_) => Value::ByteArray(input::parse_hex(text)?),
+        v => bail!("can't substitute a {} parameter", v.as_ref()),
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    pub substitutions: Vec<Substitution>,
+    // send everything at once instead of keeping the recorded gaps between requests
+    pub fast: bool,
+    // which recorded connection to replay, the first one the client sent something on if None
+    pub connection: Option<u32>,
+    // how long to wait for the next response once everything is sent
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Same,
+    Changed {
+        return_code: (i16, i16),
+        changes: Vec<ValueChange>,
+    },
+    // recorded, but the server didn't send it this time
+    Missing,
+    // sent this time, but not in the recording
+    Unexpected,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseResult {
+    pub opcode: u8,
+    // nth response to this opcode, from 0
+    pub index: usize,
+    pub outcome: Outcome,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReplayReport {
+    pub sent: usize,
+    // encrypted requests can't be replayed with a new key
+    pub skipped: usize,
+    pub responses: Vec<ResponseResult>,
+}
+
+impl ReplayReport {
+    pub fn differences(&self) -> usize {
+        self.responses
+            .iter()
+            .filter(|r| r.outcome != Outcome::Same)
+            .count()
+    }
+    pub fn render(&self, colour: bool) -> String {
+        let mut s = format!("sent {} requests", self.sent);
+        if self.skipped > 0 {
+            write!(s, ", skipped {} encrypted ones", self.skipped).unwrap();
+        }
+        s.push('\n');
+        for r in &self.responses {
+            let name = opcode_name(r.opcode);
+            match &r.outcome {
+                Outcome::Same => writeln!(s, "{} #{}: same", name, r.index),
+                Outcome::Missing => writeln!(s, "{} #{}: no response", name, r.index),
+                Outcome::Unexpected => writeln!(s, "{} #{}: not in the recording", name, r.index),
+                Outcome::Changed {
+                    return_code: (old, new),
+                    changes,
+                } => {
+                    writeln!(s, "{} #{}: differs", name, r.index).unwrap();
+                    if old != new {
+                        writeln!(s, "~ return code: {} -> {}", old, new).unwrap();
+                    }
+                    write!(s, "{}", value_diff::render(changes, colour))
+                }
+            }
+            .unwrap();
+        }
+        s
+    }
+}
+
+pub async fn replay(
+    messages: &[CapturedMessage],
+    target: &str,
+    options: &ReplayOptions,
+) -> Result<ReplayReport> {
+    let connection = match options.connection {
+        Some(c) => c,
+        None => messages
+            .iter()
+            .find(|m| m.direction == Direction::Outgoing)
+            .map(|m| m.connection)
+            .context("nothing was sent by the client in this capture")?,
+    };
+    let session: Vec<_> = messages
+        .iter()
+        .filter(|m| m.connection == connection)
+        .collect();
+    let recorded: Vec<_> = session
+        .iter()
+        .filter(|m| m.direction == Direction::Incoming)
+        .filter_map(|m| match &m.message {
+            Ok(Message::OperationResponse(r)) => Some(r.clone()),
+            _ => None,
+        })
+        .collect();
+
+    let stream = TcpStream::connect(target)
+        .await
+        .with_context(|| format!("could not connect to {}", target))?;
+    let (mut read, mut write) = stream.into_split();
+    let (tx, mut rx) = unbounded_channel();
+    let reader = spawn(async move {
+        let mut framer = FrameReader::new(Direction::Incoming);
+        let mut buf = vec![0; 65536];
+        loop {
+            let n = match read.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            framer.push(&buf[..n]);
+            while let Some(message) = framer.next_message() {
+                if let Ok(Message::OperationResponse(r)) = decode_message(&message) {
+                    if tx.send(r).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    });
+
+    let mut report = ReplayReport::default();
+    let mut last_sent = None;
+    for m in session
+        .iter()
+        .filter(|m| m.direction == Direction::Outgoing)
+    {
+        if is_encrypted(&m.raw) {
+            report.skipped += 1;
+            continue;
+        }
+        // requests are encoded again so substitutions can be made, everything else (init, pings, key
+        // exchange) goes out as it was recorded
+        let bytes = match &m.message {
+            Ok(Message::OperationRequest(request)) => {
+                let mut request = request.clone();
+                for s in &options.substitutions {
+                    if let Some(r) = s.apply(&request)? {
+                        request = r;
+                    }
+                }
+                encode_message(&Message::OperationRequest(request))?
+            }
+            _ => m.raw.clone(),
+        };
+        if let (false, Some(last)) = (options.fast, last_sent) {
+            sleep(m.timestamp.saturating_sub(last)).await;
+        }
+        last_sent = Some(m.timestamp);
+        write
+            .write_all(&frame_message(&bytes))
+            .await
+            .context("sending request")?;
+        report.sent += 1;
+    }
+
+    let mut replayed = Vec::new();
+    while replayed.len() < recorded.len() {
+        match timeout(options.timeout, rx.recv()).await {
+            Ok(Some(r)) => replayed.push(r),
+            Ok(None) | Err(_) => break,
+        }
+    }
+    reader.abort();
+    report.responses = compare_responses(&recorded, &replayed);
+    Ok(report)
+}
+
+pub fn compare_responses(
+    recorded: &[OperationResponse],
+    replayed: &[OperationResponse],
+) -> Vec<ResponseResult> {
+    let mut by_opcode: BTreeMap<u8, (Vec<&OperationResponse>, Vec<&OperationResponse>)> =
+        BTreeMap::new();
+    for r in recorded {
+        by_opcode.entry(r.opcode()).or_default().0.push(r);
+    }
+    for r in replayed {
+        by_opcode.entry(r.opcode()).or_default().1.push(r);
+    }
+    let mut results = Vec::new();
+    for (opcode, (old, new)) in by_opcode {
+        for index in 0..old.len().max(new.len()) {
+            let outcome = match (old.get(index), new.get(index)) {
+                (Some(old), Some(new)) => {
+                    let changes = diff_parameters(old.parameters(), new.parameters());
+                    if changes.is_empty() && old.return_code() == new.return_code() {
+                        Outcome::Same
+                    } else {
+                        Outcome::Changed {
+                            return_code: (old.return_code(), new.return_code()),
+                            changes,
+                        }
+                    }
+                }
+                (Some(_), None) => Outcome::Missing,
+                _ => Outcome::Unexpected,
+            };
+            results.push(ResponseResult {
+                opcode,
+                index,
+                outcome,
+            });
+        }
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc, time::Duration};
+
+    use tokio::{net::TcpListener, spawn};
+
+    use super::{compare_responses, replay, Outcome, ReplayOptions, Substitution};
+    use crate::{
+        capture::CapturedMessage,
+        message::{encode_message, Direction, Message},
+        serialization::{OperationRequest, OperationResponse, Value},
+        server::{serve, CannedResponses},
+    };
+
+    #[test]
+    fn test_substitute_and_compare() {
+        let request = OperationRequest::new(
+            66,
+            HashMap::from([(5, Value::String("old".into())), (6, Value::Int(1))]).into(),
+        );
+        let s: Substitution = "LoadWallet.6=42".parse().unwrap();
+        let replaced = s.apply(&request).unwrap().unwrap();
+        assert_eq!(replaced.parameters().get(&6), Some(&Value::Int(42)));
+        assert!("LoadWallet.6=x"
+            .parse::<Substitution>()
+            .unwrap()
+            .apply(&request)
+            .is_err());
+        assert!("1.6=42"
+            .parse::<Substitution>()
+            .unwrap()
+            .apply(&request)
+            .unwrap()
+            .is_none());
+
+        let response = |opcode, robits| {
+            OperationResponse::new(
+                opcode,
+                0,
+                Value::Null(()),
+                HashMap::from([(1, Value::Int(robits))]).into(),
+            )
+        };
+        let results = compare_responses(
+            &[response(66, 1), response(66, 2), response(3, 0)],
+            &[response(3, 0), response(66, 1), response(66, 3)],
+        );
+        let outcomes: Vec<_> = results.iter().map(|r| (r.opcode, &r.outcome)).collect();
+        assert_eq!(outcomes[0], (3, &Outcome::Same));
+        assert_eq!(outcomes[1], (66, &Outcome::Same));
+        assert!(matches!(outcomes[2], (66, Outcome::Changed { .. })));
+    }
+
+    fn captured(direction: Direction, message: Message) -> CapturedMessage {
+        CapturedMessage {
+            timestamp: Duration::ZERO,
+            direction,
+            connection: 0,
+            raw: encode_message(&message).unwrap(),
+            decrypted: None,
+            message: Ok(message),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_against_stub() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let rules = r#"
+            [[response]]
+            operation = "LoadWallet"
+            when = { 5 = "first" }
+            parameters = { 1 = { type = "Long", value = 12345 } }
+
+            [[response]]
+            operation = "LoadWallet"
+            when = { 5 = "second" }
+            return_code = 1
+        "#;
+        spawn(serve(
+            listener,
+            Arc::new(CannedResponses::from_toml(rules).unwrap()),
+            None,
+        ));
+
+        let request = |which: &str| {
+            let params = HashMap::from([(5, Value::String(which.into()))]);
+            Message::OperationRequest(OperationRequest::new(66, params.into()))
+        };
+        let response = |return_code, params: HashMap<u8, Value>| {
+            Message::OperationResponse(OperationResponse::new(
+                66,
+                return_code,
+                Value::Null(()),
+                params.into(),
+            ))
+        };
+        let recording = [
+            captured(Direction::Outgoing, request("first")),
+            captured(
+                Direction::Incoming,
+                response(0, HashMap::from([(1, Value::Long(12345))])),
+            ),
+            captured(Direction::Outgoing, request("second")),
+            captured(Direction::Incoming, response(1, HashMap::new())),
+        ];
+        let mut options = ReplayOptions {
+            substitutions: Vec::new(),
+            fast: true,
+            connection: None,
+            timeout: Duration::from_secs(5),
+        };
+
+        let report = replay(&recording, &addr, &options).await.unwrap();
+        assert_eq!(report.sent, 2);
+        assert_eq!(report.responses.len(), 2);
+        assert_eq!(report.differences(), 0);
+
+        // the first request now asks for what the second one did, so its answer changes
+        options.substitutions = vec!["LoadWallet.5=second".parse().unwrap()];
+        let report = replay(&recording, &addr, &options).await.unwrap();
+        assert_eq!(report.sent, 2);
+        assert_eq!(report.differences(), 1);
+        let Outcome::Changed {
+            return_code,
+            changes,
+        } = &report.responses[0].outcome
+        else {
+            panic!("{:?}", report.responses);
+        };
+        assert_eq!(*return_code, (0, 1));
+        assert_eq!(changes.len(), 1);
+        assert_eq!(report.responses[1].outcome, Outcome::Same);
+    }
+}
@@ -0,0 +1,118 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    message::{Direction, Message},
+    serialization::{type_code::TypeCode, ParameterTable},
+};
+
+pub mod codegen;
+pub mod diff;
+pub mod infer;
+pub mod validate;
+
+// Machine readable description of what the parameters of each operation/event look like.
+// Written by `schema infer`, but meant to be hand-editable too, so almost everything is optional
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Schema {
+    #[serde(default)]
+    pub messages: Vec<MessageSchema>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    Request,
+    Response,
+    Event,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MessageSchema {
+    pub direction: Direction,
+    pub kind: MessageKind,
+    pub code: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    // number of messages this was inferred from
+    #[serde(default)]
+    pub count: u64,
+    #[serde(default)]
+    pub parameters: BTreeMap<u8, ParameterSchema>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ParameterSchema {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub seen: u64,
+    #[serde(default = "always_present_default")]
+    pub always_present: bool,
+    #[serde(flatten)]
+    pub shape: ValueShape,
+}
+fn always_present_default() -> bool {
+    true
+}
+
+// Everything we know about the values seen in one position of the tree
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct ValueShape {
+    #[serde(default)]
+    pub types: BTreeSet<TypeCode>,
+    // Dictionary/Hashtable keys and values
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keys: Option<Box<ValueShape>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub values: Option<Box<ValueShape>>,
+    // Array/ObjectArray elements
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub elements: Option<Box<ValueShape>>,
+    // distinct scalar keys seen in a Dictionary/Hashtable
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub known_keys: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub examples: Vec<String>,
+}
+
+impl Schema {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text =
+            std::fs::read_to_string(path).context(format!("reading schema {}", path.display()))?;
+        serde_json::from_str(&text).context(format!("parsing schema {}", path.display()))
+    }
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        std::fs::write(path, self.to_json()?).context(format!("writing schema {}", path.display()))
+    }
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+    pub fn find(
+        &self,
+        direction: Direction,
+        kind: MessageKind,
+        code: u8,
+    ) -> Option<&MessageSchema> {
+        self.messages
+            .iter()
+            .find(|m| m.direction == direction && m.kind == kind && m.code == code)
+    }
+}
+
+// The parts of a message a schema is keyed on, None for messages without a parameter table
+pub fn schema_key(message: &Message) -> Option<(MessageKind, u8, &ParameterTable)> {
+    match message {
+        Message::OperationRequest(r) => Some((MessageKind::Request, r.opcode(), r.parameters())),
+        Message::OperationResponse(r) => Some((MessageKind::Response, r.opcode(), r.parameters())),
+        Message::Event(e) => Some((MessageKind::Event, e.event_code(), e.params())),
+        _ => None,
+    }
+}
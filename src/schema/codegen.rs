@@ -0,0 +1,404 @@
+use std::{collections::HashSet, fmt::Write};
+
+use crate::serialization::type_code::TypeCode;
+
+use super::{MessageKind, MessageSchema, ParameterSchema, Schema, ValueShape};
+
+// Generates Rust bindings for every message in a schema: one struct per message with conversions from/to
+// ParameterTable (and the message type itself), a Debug impl that shows parameter names, and a
+// `Messages` enum to decode any of them in one go.
+// `crate_path` is how the generated code refers to this crate, "rcsniff2" from a dependent crate or build script
+pub fn generate(schema: &Schema, crate_path: &str) -> String {
+    let mut out = String::new();
+    let c = crate_path;
+    writeln!(
+        out,
+        "// Generated by `rcsniff2 schema codegen`, edit the schema instead of this file"
+    )
+    .unwrap();
+    // outer attributes only, so the output also works with include!()
+    writeln!(out, "#[allow(unused_imports)]").unwrap();
+    writeln!(out, "use {c}::message::Message;").unwrap();
+    writeln!(out, "#[allow(unused_imports)]").unwrap();
+    // laid out the way rustfmt does it
+    writeln!(out, "use {c}::serialization::{{").unwrap();
+    writeln!(
+        out,
+        "    EventData, OperationRequest, OperationResponse, ParameterTable, Value,"
+    )
+    .unwrap();
+    writeln!(out, "}};").unwrap();
+    writeln!(out, "#[allow(unused_imports)]").unwrap();
+    writeln!(out, "use {c}::typed::{{read_field, write_field}};").unwrap();
+    writeln!(out).unwrap();
+
+    let mut names = HashSet::new();
+    let mut generated = Vec::new();
+    for m in &schema.messages {
+        let mut name = struct_name(m);
+        if !names.insert(name.clone()) {
+            name = format!("{}{}", name, m.code);
+            names.insert(name.clone());
+        }
+        generate_struct(&mut out, m, &name, c);
+        generated.push((name, m));
+    }
+
+    writeln!(out, "#[derive(Debug, Clone, PartialEq, Eq)]").unwrap();
+    writeln!(out, "pub enum Messages {{").unwrap();
+    for (name, _) in &generated {
+        writeln!(out, "    {name}({name}),").unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+    writeln!(out, "impl Messages {{").unwrap();
+    writeln!(
+        out,
+        "    // None if the message isn't covered by the schema"
+    )
+    .unwrap();
+    writeln!(out, "    #[allow(unreachable_code)]").unwrap();
+    writeln!(
+        out,
+        "    pub fn decode(message: &Message) -> Option<::anyhow::Result<Self>> {{"
+    )
+    .unwrap();
+    writeln!(out, "        Some(match message {{").unwrap();
+    for (variant, kind) in [
+        ("OperationRequest", MessageKind::Request),
+        ("OperationResponse", MessageKind::Response),
+        ("Event", MessageKind::Event),
+    ] {
+        let code_fn = match kind {
+            MessageKind::Event => "event_code",
+            _ => "opcode",
+        };
+        writeln!(
+            out,
+            "            Message::{variant}(m) => match m.{code_fn}() {{"
+        )
+        .unwrap();
+        let mut seen = HashSet::new();
+        for (name, m) in generated.iter().filter(|(_, m)| m.kind == kind) {
+            // the same code can show up once per direction, first one wins
+            if seen.insert(m.code) {
+                writeln!(
+                    out,
+                    "                {} => {name}::try_from(m).map(Self::{name}),",
+                    m.code
+                )
+                .unwrap();
+            }
+        }
+        writeln!(out, "                _ => return None,").unwrap();
+        writeln!(out, "            }},").unwrap();
+    }
+    writeln!(out, "            _ => return None,").unwrap();
+    writeln!(out, "        }})").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+fn generate_struct(out: &mut String, m: &MessageSchema, name: &str, crate_path: &str) {
+    let fields = field_names(m);
+    let (message_type, params_fn, code_fn) = match m.kind {
+        MessageKind::Request => ("OperationRequest", "parameters", "opcode"),
+        MessageKind::Response => ("OperationResponse", "parameters", "opcode"),
+        MessageKind::Event => ("EventData", "params", "event_code"),
+    };
+
+    writeln!(out, "#[derive(Clone, PartialEq, Eq)]").unwrap();
+    writeln!(out, "pub struct {name} {{").unwrap();
+    for ((code, p), field) in m.parameters.iter().zip(&fields) {
+        writeln!(out, "    // {}", parameter_label(*code, p)).unwrap();
+        writeln!(out, "    pub {field}: {},", field_type(p, crate_path)).unwrap();
+    }
+    writeln!(out, "}}").unwrap();
+    writeln!(out, "impl {name} {{").unwrap();
+    writeln!(out, "    pub const CODE: u8 = {};", m.code).unwrap();
+    writeln!(out, "}}").unwrap();
+
+    writeln!(out, "impl TryFrom<&ParameterTable> for {name} {{").unwrap();
+    writeln!(out, "    type Error = ::anyhow::Error;").unwrap();
+    writeln!(out, "    #[allow(unused_variables)]").unwrap();
+    writeln!(
+        out,
+        "    fn try_from(table: &ParameterTable) -> ::anyhow::Result<Self> {{"
+    )
+    .unwrap();
+    writeln!(out, "        Ok(Self {{").unwrap();
+    for ((code, p), field) in m.parameters.iter().zip(&fields) {
+        writeln!(
+            out,
+            "            {field}: read_field(table, {code}, {:?})?,",
+            parameter_label(*code, p)
+        )
+        .unwrap();
+    }
+    writeln!(out, "        }})").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}").unwrap();
+
+    writeln!(out, "impl From<{name}> for ParameterTable {{").unwrap();
+    writeln!(
+        out,
+        "    #[allow(unused_variables, unused_mut, clippy::let_and_return)]"
+    )
+    .unwrap();
+    writeln!(out, "    fn from(value: {name}) -> Self {{").unwrap();
+    writeln!(out, "        let mut table = ParameterTable::new();").unwrap();
+    for (code, field) in m.parameters.keys().zip(&fields) {
+        writeln!(
+            out,
+            "        write_field(&mut table, {code}, value.{field});"
+        )
+        .unwrap();
+    }
+    writeln!(out, "        table").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}").unwrap();
+
+    writeln!(out, "impl TryFrom<&{message_type}> for {name} {{").unwrap();
+    writeln!(out, "    type Error = ::anyhow::Error;").unwrap();
+    writeln!(
+        out,
+        "    fn try_from(m: &{message_type}) -> ::anyhow::Result<Self> {{"
+    )
+    .unwrap();
+    writeln!(out, "        if m.{code_fn}() != Self::CODE {{").unwrap();
+    writeln!(
+        out,
+        "            ::anyhow::bail!(\"expected code {{}}, got {{}}\", Self::CODE, m.{code_fn}());"
+    )
+    .unwrap();
+    writeln!(out, "        }}").unwrap();
+    // like the typed.rs bindings, a failed response doesn't have the parameters a successful one has
+    if m.kind == MessageKind::Response {
+        writeln!(out, "        if m.return_code() != 0 {{").unwrap();
+        writeln!(
+            out,
+            "            ::anyhow::bail!(\"{name} failed with return code {{}}\", m.return_code());"
+        )
+        .unwrap();
+        writeln!(out, "        }}").unwrap();
+    }
+    writeln!(out, "        Self::try_from(m.{params_fn}())").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}").unwrap();
+
+    writeln!(out, "impl From<{name}> for {message_type} {{").unwrap();
+    writeln!(out, "    fn from(value: {name}) -> Self {{").unwrap();
+    match m.kind {
+        MessageKind::Request | MessageKind::Event => writeln!(
+            out,
+            "        {message_type}::new({name}::CODE, value.into())"
+        ),
+        MessageKind::Response => writeln!(
+            out,
+            "        {message_type}::new({name}::CODE, 0, Value::Null(()), value.into())"
+        ),
+    }
+    .unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}").unwrap();
+
+    writeln!(out, "impl ::std::fmt::Debug for {name} {{").unwrap();
+    writeln!(
+        out,
+        "    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {{"
+    )
+    .unwrap();
+    writeln!(out, "        f.debug_struct({:?})", name).unwrap();
+    for ((code, p), field) in m.parameters.iter().zip(&fields) {
+        writeln!(
+            out,
+            "            .field({:?}, &self.{field})",
+            parameter_label(*code, p)
+        )
+        .unwrap();
+    }
+    writeln!(out, "            .finish()").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}").unwrap();
+    writeln!(out).unwrap();
+}
+
+fn struct_name(m: &MessageSchema) -> String {
+    let suffix = match m.kind {
+        MessageKind::Request => "Request",
+        MessageKind::Response => "Response",
+        MessageKind::Event => "Event",
+    };
+    let name = match m.name.as_deref().map(camel_case) {
+        Some(name) if name.is_empty() => return format!("Unknown{}{}", m.code, suffix),
+        Some(name) => name,
+        None => return format!("Unknown{}{}", m.code, suffix),
+    };
+    // identifiers can't start with a digit
+    let name = match name.starts_with(|c: char| c.is_ascii_digit()) {
+        true if m.kind == MessageKind::Event => format!("Event{}", name),
+        true => format!("Operation{}", name),
+        false => name,
+    };
+    if name.ends_with(suffix) {
+        name
+    } else {
+        format!("{}{}", name, suffix)
+    }
+}
+
+fn parameter_label(code: u8, p: &ParameterSchema) -> String {
+    match &p.name {
+        Some(name) => format!("{}({})", name, code),
+        None => code.to_string(),
+    }
+}
+
+fn field_names(m: &MessageSchema) -> Vec<String> {
+    let mut seen = HashSet::new();
+    m.parameters
+        .iter()
+        .map(|(code, p)| {
+            let mut name = match &p.name {
+                Some(name) => snake_case(name),
+                None => format!("param_{}", code),
+            };
+            if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+                name = format!("param_{}", name);
+            }
+            if is_keyword(&name) {
+                name.push('_');
+            }
+            if !seen.insert(name.clone()) {
+                name = format!("{}_{}", name.trim_end_matches('_'), code);
+            }
+            name
+        })
+        .collect()
+}
+
+fn field_type(p: &ParameterSchema, crate_path: &str) -> String {
+    let ty = shape_type(&p.shape, crate_path);
+    if p.always_present {
+        ty
+    } else {
+        format!("Option<{}>", ty)
+    }
+}
+
+// Rust type for a value, falls back to Value if the schema saw more than one type there
+fn shape_type(shape: &ValueShape, c: &str) -> String {
+    if shape.types.len() != 1 {
+        return "Value".into();
+    }
+    match shape.types.iter().next().unwrap() {
+        TypeCode::Boolean => "bool".into(),
+        TypeCode::Byte => "u8".into(),
+        TypeCode::Short => "i16".into(),
+        TypeCode::Integer => "i32".into(),
+        TypeCode::Long => "i64".into(),
+        TypeCode::Float => format!("{c}::util::HashableFloat"),
+        TypeCode::Double => format!("{c}::util::HashableDouble"),
+        TypeCode::String => "String".into(),
+        TypeCode::Array => "Vec<Value>".into(),
+        TypeCode::StringArray => "Vec<String>".into(),
+        TypeCode::IntegerArray => "Vec<i32>".into(),
+        TypeCode::ByteArray => "Vec<u8>".into(),
+        TypeCode::Dictionary => format!("{c}::util::HashableHashmap<Value, Value>"),
+        TypeCode::Hashtable => format!("{c}::serialization::HashTable<Value, Value>"),
+        TypeCode::ObjectArray => format!("{c}::serialization::ObjectArray"),
+        _ => "Value".into(),
+    }
+}
+
+fn camel_case(name: &str) -> String {
+    name.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            let mut c = s.chars();
+            let first = c.next().unwrap().to_ascii_uppercase();
+            std::iter::once(first).chain(c).collect::<String>()
+        })
+        .collect()
+}
+
+fn snake_case(name: &str) -> String {
+    let mut out = String::new();
+    let chars: Vec<char> = name.chars().collect();
+    for (i, c) in chars.iter().enumerate() {
+        if !c.is_ascii_alphanumeric() {
+            if !out.ends_with('_') && !out.is_empty() {
+                out.push('_');
+            }
+            continue;
+        }
+        if c.is_ascii_uppercase() && i > 0 {
+            let prev = chars[i - 1];
+            let next_lower = chars.get(i + 1).is_some_and(|n| n.is_ascii_lowercase());
+            if (prev.is_ascii_lowercase()
+                || prev.is_ascii_digit()
+                || (prev.is_ascii_uppercase() && next_lower))
+                && !out.ends_with('_')
+            {
+                out.push('_');
+            }
+        }
+        out.push(c.to_ascii_lowercase());
+    }
+    out.trim_end_matches('_').to_string()
+}
+
+fn is_keyword(name: &str) -> bool {
+    matches!(
+        name,
+        "as" | "break"
+            | "const"
+            | "continue"
+            | "crate"
+            | "else"
+            | "enum"
+            | "extern"
+            | "false"
+            | "fn"
+            | "for"
+            | "if"
+            | "impl"
+            | "in"
+            | "let"
+            | "loop"
+            | "match"
+            | "mod"
+            | "move"
+            | "mut"
+            | "pub"
+            | "ref"
+            | "return"
+            | "self"
+            | "static"
+            | "struct"
+            | "super"
+            | "trait"
+            | "true"
+            | "type"
+            | "unsafe"
+            | "use"
+            | "where"
+            | "while"
+            | "async"
+            | "await"
+            | "dyn"
+            | "abstract"
+            | "become"
+            | "box"
+            | "do"
+            | "final"
+            | "macro"
+            | "override"
+            | "priv"
+            | "typeof"
+            | "unsized"
+            | "virtual"
+            | "yield"
+            | "try"
+    )
+}
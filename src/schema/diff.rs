@@ -0,0 +1,336 @@
+use std::{collections::BTreeSet, fmt::Display};
+
+use serde::Serialize;
+
+use crate::{message::Direction, serialization::type_code::TypeCode};
+
+use super::{MessageKind, MessageSchema, ParameterSchema, Schema, ValueShape};
+
+// What changed on the wire between two schemas (usually inferred from captures of two game versions)
+#[derive(Debug, Clone, PartialEq, Default, Serialize)]
+pub struct SchemaDiff {
+    pub messages: Vec<MessageDiff>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MessageDiff {
+    pub direction: Direction,
+    pub kind: MessageKind,
+    pub code: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub change: MessageChange,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MessageChange {
+    Added,
+    Removed,
+    // a code disappeared and another one with exactly the same parameters showed up
+    Renumbered { from: u8 },
+    Changed { parameters: Vec<ParameterChange> },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ParameterChange {
+    pub code: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    // where in the value the change is, empty for the parameter itself. e.g. "values.elements"
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub path: String,
+    pub change: ParameterChangeKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ParameterChangeKind {
+    Added {
+        types: Vec<TypeCode>,
+    },
+    Removed,
+    TypeChanged {
+        old: Vec<TypeCode>,
+        new: Vec<TypeCode>,
+    },
+    NowOptional,
+    NowRequired,
+    NewKeys {
+        keys: Vec<String>,
+    },
+}
+
+pub fn diff_schemas(old: &Schema, new: &Schema) -> SchemaDiff {
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
+    let mut messages = Vec::new();
+    for n in &new.messages {
+        match old.find(n.direction, n.kind, n.code) {
+            Some(o) => {
+                let parameters = diff_parameters(o, n);
+                if !parameters.is_empty() {
+                    messages.push(message_diff(n, MessageChange::Changed { parameters }));
+                }
+            }
+            None => added.push(n),
+        }
+    }
+    for o in &old.messages {
+        if new.find(o.direction, o.kind, o.code).is_none() {
+            removed.push(o);
+        }
+    }
+    for a in added {
+        let same_shape = removed.iter().position(|r| {
+            r.direction == a.direction
+                && r.kind == a.kind
+                && diff_parameters(r, a).is_empty()
+                && !a.parameters.is_empty()
+        });
+        match same_shape {
+            Some(i) => {
+                let r = removed.remove(i);
+                let mut d = message_diff(a, MessageChange::Renumbered { from: r.code });
+                d.name = d.name.or_else(|| r.name.clone());
+                messages.push(d);
+            }
+            None => messages.push(message_diff(a, MessageChange::Added)),
+        }
+    }
+    for r in removed {
+        messages.push(message_diff(r, MessageChange::Removed));
+    }
+    messages.sort_by_key(|m| (m.code, m.kind, m.direction));
+    SchemaDiff { messages }
+}
+
+fn message_diff(m: &MessageSchema, change: MessageChange) -> MessageDiff {
+    MessageDiff {
+        direction: m.direction,
+        kind: m.kind,
+        code: m.code,
+        name: m.name.clone(),
+        change,
+    }
+}
+
+fn diff_parameters(old: &MessageSchema, new: &MessageSchema) -> Vec<ParameterChange> {
+    let mut out = Vec::new();
+    let codes: BTreeSet<u8> = old
+        .parameters
+        .keys()
+        .chain(new.parameters.keys())
+        .copied()
+        .collect();
+    for code in codes {
+        let o = old.parameters.get(&code);
+        let n = new.parameters.get(&code);
+        let name = n.or(o).and_then(|p| p.name.clone());
+        let mut push = |path: String, change| {
+            out.push(ParameterChange {
+                code,
+                name: name.clone(),
+                path,
+                change,
+            })
+        };
+        match (o, n) {
+            (None, Some(n)) => push(
+                String::new(),
+                ParameterChangeKind::Added {
+                    types: n.shape.types.iter().copied().collect(),
+                },
+            ),
+            (Some(_), None) => push(String::new(), ParameterChangeKind::Removed),
+            (Some(o), Some(n)) => diff_parameter(o, n, &mut push),
+            (None, None) => unreachable!(),
+        }
+    }
+    out
+}
+
+fn diff_parameter(
+    old: &ParameterSchema,
+    new: &ParameterSchema,
+    push: &mut impl FnMut(String, ParameterChangeKind),
+) {
+    match (old.always_present, new.always_present) {
+        (true, false) => push(String::new(), ParameterChangeKind::NowOptional),
+        (false, true) => push(String::new(), ParameterChangeKind::NowRequired),
+        _ => {}
+    }
+    diff_shape(String::new(), &old.shape, &new.shape, push);
+}
+
+fn diff_shape(
+    path: String,
+    old: &ValueShape,
+    new: &ValueShape,
+    push: &mut impl FnMut(String, ParameterChangeKind),
+) {
+    // hand-written schemas can leave types out, there's nothing to compare then
+    if !old.types.is_empty() && !new.types.is_empty() && old.types != new.types {
+        push(
+            path.clone(),
+            ParameterChangeKind::TypeChanged {
+                old: old.types.iter().copied().collect(),
+                new: new.types.iter().copied().collect(),
+            },
+        );
+    }
+    let new_keys: Vec<String> = new
+        .known_keys
+        .difference(&old.known_keys)
+        .cloned()
+        .collect();
+    if !new_keys.is_empty() {
+        push(
+            path.clone(),
+            ParameterChangeKind::NewKeys { keys: new_keys },
+        );
+    }
+    for (part, o, n) in [
+        ("keys", &old.keys, &new.keys),
+        ("values", &old.values, &new.values),
+        ("elements", &old.elements, &new.elements),
+    ] {
+        if let (Some(o), Some(n)) = (o, n) {
+            let path = if path.is_empty() {
+                part.to_string()
+            } else {
+                format!("{}.{}", path, part)
+            };
+            diff_shape(path, o, n, push);
+        }
+    }
+}
+
+fn types(t: &[TypeCode]) -> String {
+    t.iter()
+        .map(|t| format!("{:?}", t))
+        .collect::<Vec<_>>()
+        .join("|")
+}
+
+impl Display for SchemaDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.messages.is_empty() {
+            return writeln!(f, "no differences");
+        }
+        for m in &self.messages {
+            let dir = match m.direction {
+                Direction::Incoming => "in",
+                Direction::Outgoing => "out",
+            };
+            write!(f, "{} {:?} {}", dir, m.kind, m.code)?;
+            if let Some(name) = &m.name {
+                write!(f, " {}", name)?;
+            }
+            match &m.change {
+                MessageChange::Added => writeln!(f, ": added")?,
+                MessageChange::Removed => writeln!(f, ": removed")?,
+                MessageChange::Renumbered { from } => writeln!(f, ": renumbered (was {})", from)?,
+                MessageChange::Changed { parameters } => {
+                    writeln!(f, ":")?;
+                    for p in parameters {
+                        write!(f, "    {}", p.code)?;
+                        if let Some(name) = &p.name {
+                            write!(f, " {}", name)?;
+                        }
+                        if !p.path.is_empty() {
+                            write!(f, " .{}", p.path)?;
+                        }
+                        match &p.change {
+                            ParameterChangeKind::Added { types: t } => {
+                                writeln!(f, ": added ({})", types(t))?
+                            }
+                            ParameterChangeKind::Removed => writeln!(f, ": removed")?,
+                            ParameterChangeKind::TypeChanged { old, new } => {
+                                writeln!(f, ": {} -> {}", types(old), types(new))?
+                            }
+                            ParameterChangeKind::NowOptional => writeln!(f, ": now optional")?,
+                            ParameterChangeKind::NowRequired => {
+                                writeln!(f, ": now always present")?
+                            }
+                            ParameterChangeKind::NewKeys { keys } => {
+                                writeln!(f, ": new keys {}", keys.join(", "))?
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff_schemas, MessageChange, ParameterChange, ParameterChangeKind};
+    use crate::{schema::Schema, serialization::type_code::TypeCode};
+
+    fn schema(json: &str) -> Schema {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = schema(
+            r#"{"messages": [
+                {"direction": "in", "kind": "response", "code": 66, "name": "LoadWallet", "parameters": {
+                    "1": {"name": "Robits", "types": ["Long"]},
+                    "2": {"types": ["Dictionary"], "known_keys": ["a"]}
+                }},
+                {"direction": "out", "kind": "request", "code": 43, "parameters": {"1": {"types": ["Integer"]}}},
+                {"direction": "in", "kind": "event", "code": 1, "parameters": {}}
+            ]}"#,
+        );
+        let new = schema(
+            r#"{"messages": [
+                {"direction": "in", "kind": "response", "code": 66, "name": "LoadWallet", "parameters": {
+                    "1": {"name": "Robits", "types": ["Integer"]},
+                    "2": {"types": ["Dictionary"], "known_keys": ["a", "b"]}
+                }},
+                {"direction": "out", "kind": "request", "code": 44, "parameters": {"1": {"types": ["Integer"]}}},
+                {"direction": "in", "kind": "event", "code": 2, "parameters": {}}
+            ]}"#,
+        );
+        let diff = diff_schemas(&old, &new);
+        let changes: Vec<_> = diff.messages.iter().map(|m| (m.code, &m.change)).collect();
+        assert_eq!(
+            changes,
+            [
+                (1, &MessageChange::Removed),
+                // an event without parameters could be anything, so it isn't taken for a renumbering
+                (2, &MessageChange::Added),
+                (44, &MessageChange::Renumbered { from: 43 }),
+                (
+                    66,
+                    &MessageChange::Changed {
+                        parameters: vec![
+                            ParameterChange {
+                                code: 1,
+                                name: Some("Robits".into()),
+                                path: String::new(),
+                                change: ParameterChangeKind::TypeChanged {
+                                    old: vec![TypeCode::Long],
+                                    new: vec![TypeCode::Integer],
+                                },
+                            },
+                            ParameterChange {
+                                code: 2,
+                                name: None,
+                                path: String::new(),
+                                change: ParameterChangeKind::NewKeys {
+                                    keys: vec!["b".into()],
+                                },
+                            },
+                        ],
+                    }
+                ),
+            ]
+        );
+        assert_eq!(diff_schemas(&new, &new).messages, []);
+    }
+}
@@ -0,0 +1,204 @@
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::Result;
+
+use crate::{
+    capture,
+    message::{Direction, Message},
+    registry::{self, ParameterOwner},
+    serialization::{op_code::WebServicesOpCode, Value},
+};
+
+use super::{schema_key, MessageKind, MessageSchema, ParameterSchema, Schema, ValueShape};
+
+const MAX_EXAMPLES: usize = 3;
+const MAX_EXAMPLE_LEN: usize = 64;
+const MAX_KNOWN_KEYS: usize = 64;
+
+// Aggregates the shape of every message it is shown into a Schema
+#[derive(Default)]
+pub struct SchemaInferrer {
+    messages: BTreeMap<(Direction, MessageKind, u8), MessageSchema>,
+}
+
+impl SchemaInferrer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn observe(&mut self, direction: Direction, message: &Message) {
+        let Some((kind, code, params)) = schema_key(message) else {
+            return;
+        };
+        let entry = self
+            .messages
+            .entry((direction, kind, code))
+            .or_insert_with(|| MessageSchema {
+                direction,
+                kind,
+                code,
+                name: message_name(kind, code),
+                count: 0,
+                parameters: BTreeMap::new(),
+            });
+        entry.count += 1;
+        for (k, v) in params.iter() {
+            let p = entry
+                .parameters
+                .entry(*k)
+                .or_insert_with(|| ParameterSchema {
+                    name: parameter_name(kind, code, *k),
+                    seen: 0,
+                    always_present: false,
+                    shape: ValueShape::default(),
+                });
+            p.seen += 1;
+            p.shape.observe(v);
+        }
+    }
+    pub fn finish(self) -> Schema {
+        let mut messages: Vec<_> = self.messages.into_values().collect();
+        for m in messages.iter_mut() {
+            for p in m.parameters.values_mut() {
+                p.always_present = p.seen == m.count;
+            }
+        }
+        Schema { messages }
+    }
+}
+
+// Infers a schema from every message in a pcap/pcapng capture
+pub fn infer_capture(path: impl AsRef<Path>, port: u16) -> Result<Schema> {
+    let mut inferrer = SchemaInferrer::new();
+    for m in capture::read_messages(path, port)? {
+        if let Ok(message) = &m.message {
+            inferrer.observe(m.direction, message);
+        }
+    }
+    Ok(inferrer.finish())
+}
+
+pub(crate) fn message_name(kind: MessageKind, code: u8) -> Option<String> {
+    match kind {
+        MessageKind::Request | MessageKind::Response => {
+            WebServicesOpCode::from_repr(code).map(|c| format!("{:?}", c))
+        }
+        MessageKind::Event => registry::event_name(code),
+    }
+}
+pub(crate) fn parameter_name(kind: MessageKind, code: u8, parameter: u8) -> Option<String> {
+    let owner = match kind {
+        MessageKind::Request | MessageKind::Response => ParameterOwner::Operation(code),
+        MessageKind::Event => ParameterOwner::Event(code),
+    };
+    registry::parameter_name(Some(owner), parameter).map(str::to_owned)
+}
+
+impl ValueShape {
+    pub fn observe(&mut self, value: &Value) {
+        self.types.insert(value.type_code());
+        match value {
+            Value::Dictionary(m) => self.observe_map(m.iter()),
+            Value::HashTable(m) => self.observe_map(m.iter()),
+            Value::Array(v) => self.observe_elements(v),
+            Value::ObjectArray(v) => self.observe_elements(v),
+            Value::EventData(_)
+            | Value::OperationRequest(_)
+            | Value::OperationResponse(_)
+            | Value::Null(_) => {}
+            _ => {
+                if self.examples.len() < MAX_EXAMPLES {
+                    let mut example = format!("{:?}", value);
+                    if example.len() > MAX_EXAMPLE_LEN {
+                        let mut end = MAX_EXAMPLE_LEN;
+                        while !example.is_char_boundary(end) {
+                            end -= 1;
+                        }
+                        example.truncate(end);
+                        example.push_str("...");
+                    }
+                    if !self.examples.contains(&example) {
+                        self.examples.push(example);
+                    }
+                }
+            }
+        }
+    }
+    fn observe_map<'a>(&mut self, entries: impl Iterator<Item = (&'a Value, &'a Value)>) {
+        for (k, v) in entries {
+            self.keys.get_or_insert_with(Default::default).observe(k);
+            self.values.get_or_insert_with(Default::default).observe(v);
+            if self.known_keys.len() < MAX_KNOWN_KEYS {
+                if let Some(key) = key_name(k) {
+                    self.known_keys.insert(key);
+                }
+            }
+        }
+    }
+    fn observe_elements(&mut self, elements: &[Value]) {
+        for e in elements {
+            self.elements
+                .get_or_insert_with(Default::default)
+                .observe(e);
+        }
+    }
+}
+
+// Dictionary keys worth tracking individually. Strings are by far the most common
+pub(crate) fn key_name(key: &Value) -> Option<String> {
+    match key {
+        Value::String(s) => Some(s.clone()),
+        Value::Byte(b) => Some(b.to_string()),
+        Value::Short(b) => Some(b.to_string()),
+        Value::Int(b) => Some(b.to_string()),
+        Value::Long(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::SchemaInferrer;
+    use crate::{
+        message::{Direction, Message},
+        schema::MessageKind,
+        serialization::{type_code::TypeCode, OperationRequest, Value},
+        util::HashableHashmap,
+    };
+
+    #[test]
+    fn test_infer_presence_and_nesting() {
+        let mut inferrer = SchemaInferrer::new();
+        let dict: HashMap<Value, Value> =
+            HashMap::from([(Value::String("robits".into()), Value::Long(5))]);
+        let first = HashMap::from([
+            (1, Value::Int(3)),
+            (2, Value::Dictionary(HashableHashmap(dict))),
+        ]);
+        let second = HashMap::from([(1, Value::Short(3))]);
+        for params in [first, second] {
+            let m = Message::OperationRequest(OperationRequest::new(43, params.into()));
+            inferrer.observe(Direction::Outgoing, &m);
+        }
+        let schema = inferrer.finish();
+        let m = schema
+            .find(Direction::Outgoing, MessageKind::Request, 43)
+            .unwrap();
+        assert_eq!(m.count, 2);
+        assert_eq!(m.name.as_deref(), Some("LoadMachineRequest"));
+        let p1 = &m.parameters[&1];
+        assert!(p1.always_present);
+        assert_eq!(p1.shape.types.len(), 2);
+        let p2 = &m.parameters[&2];
+        assert!(!p2.always_present);
+        assert!(p2.shape.known_keys.contains("robits"));
+        assert!(p2
+            .shape
+            .values
+            .as_ref()
+            .unwrap()
+            .types
+            .contains(&TypeCode::Long));
+    }
+}
@@ -0,0 +1,157 @@
+use serde::Serialize;
+
+use crate::{
+    message::{Direction, Message},
+    serialization::type_code::TypeCode,
+};
+
+use super::{infer::parameter_name, schema_key, MessageKind, Schema};
+
+// One difference between a message and the schema it was checked against
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SchemaDrift {
+    pub direction: Direction,
+    pub kind: MessageKind,
+    pub code: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub parameter: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameter_name: Option<String>,
+    pub drift: DriftKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DriftKind {
+    // the schema says this parameter is always there
+    Missing,
+    TypeChanged {
+        expected: Vec<TypeCode>,
+        found: TypeCode,
+    },
+    New {
+        found: TypeCode,
+    },
+}
+
+pub struct SchemaValidator {
+    schema: Schema,
+}
+
+impl SchemaValidator {
+    pub fn new(schema: Schema) -> Self {
+        Self { schema }
+    }
+    // Messages the schema doesn't describe at all are not reported
+    pub fn validate(&self, direction: Direction, message: &Message) -> Vec<SchemaDrift> {
+        let Some((kind, code, params)) = schema_key(message) else {
+            return Vec::new();
+        };
+        let Some(schema) = self.schema.find(direction, kind, code) else {
+            return Vec::new();
+        };
+        let drift = |parameter: u8, drift: DriftKind| SchemaDrift {
+            direction,
+            kind,
+            code,
+            name: schema.name.clone(),
+            parameter,
+            parameter_name: schema
+                .parameters
+                .get(&parameter)
+                .and_then(|p| p.name.clone())
+                .or_else(|| parameter_name(kind, code, parameter)),
+            drift,
+        };
+        let mut out = Vec::new();
+        for (k, p) in &schema.parameters {
+            if p.always_present && !params.contains_key(k) {
+                out.push(drift(*k, DriftKind::Missing));
+            }
+        }
+        let mut present: Vec<_> = params.iter().collect();
+        present.sort_by_key(|(k, _)| **k);
+        for (k, v) in present {
+            let found = v.type_code();
+            match schema.parameters.get(k) {
+                None => out.push(drift(*k, DriftKind::New { found })),
+                Some(p) if !p.shape.types.is_empty() && !p.shape.types.contains(&found) => out
+                    .push(drift(
+                        *k,
+                        DriftKind::TypeChanged {
+                            expected: p.shape.types.iter().copied().collect(),
+                            found,
+                        },
+                    )),
+                Some(_) => {}
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{DriftKind, SchemaValidator};
+    use crate::{
+        message::{Direction, Message},
+        schema::infer::SchemaInferrer,
+        serialization::{type_code::TypeCode, OperationResponse, Value},
+    };
+
+    fn response(params: HashMap<u8, Value>) -> Message {
+        Message::OperationResponse(OperationResponse::new(
+            230,
+            0,
+            Value::Null(()),
+            params.into(),
+        ))
+    }
+
+    #[test]
+    fn test_drift() {
+        let mut inferrer = SchemaInferrer::new();
+        inferrer.observe(
+            Direction::Incoming,
+            &response(HashMap::from([
+                (221, Value::Long(1)),
+                (225, Value::Long(2)),
+            ])),
+        );
+        let validator = SchemaValidator::new(inferrer.finish());
+        let drift = validator.validate(
+            Direction::Incoming,
+            &response(HashMap::from([
+                (221, Value::Int(1)),
+                (210, Value::Bool(true)),
+            ])),
+        );
+        let kinds: Vec<_> = drift
+            .iter()
+            .map(|d| (d.parameter, d.drift.clone()))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (225, DriftKind::Missing),
+                (
+                    210,
+                    DriftKind::New {
+                        found: TypeCode::Boolean
+                    }
+                ),
+                (
+                    221,
+                    DriftKind::TypeChanged {
+                        expected: vec![TypeCode::Long],
+                        found: TypeCode::Integer
+                    }
+                ),
+            ]
+        );
+        assert_eq!(drift[0].parameter_name.as_deref(), Some("UserId"));
+    }
+}
@@ -0,0 +1,462 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, Context, Result};
+use rhai::{Array, Dynamic, Engine, EvalAltResult, FnPtr, Map, AST, INT};
+
+use crate::{
+    capture::CapturedMessage,
+    message::{is_encrypted, Message},
+    registry::{self, ParameterOwner},
+    serialization::{op_code::WebServicesOpCode, ParameterTable, Value},
+};
+
+// Rhai scripts that react to messages. A script runs once when it's loaded and registers handlers with `on`:
+//
+//   let failures = 0;
+//   on("response", "LoadWallet", |m| {
+//       if m.return_code != 0 { failures += 1; print(`LoadWallet failed ${failures} times`); }
+//   });
+//   on("event", |m| append_file("events.txt", `${m.name} ${m.params.to_json()}` + "\n"));
+//
+// `on(what, handler)` takes "*" (every message, decode errors too), a kind ("request", "response", "event",
+// "internal_request", "internal_response" or "error"), or an opcode or event by name or number (a number is an
+// opcode). `on(kind, opcode or event, handler)` takes both. Handlers are closures, so variables they capture keep
+// their values from one message to the next.
+//
+// Handlers get the message as a map: direction ("in"/"out"), connection, timestamp (seconds), kind, opcode or
+// event_code, name, return_code and debug_message for responses, params and raw (a blob), or error for messages
+// that didn't decode. Parameters are keyed by their name when parameters.toml knows it and by their code
+// otherwise; values are ints, floats, strings, bools, arrays, maps, blobs (byte arrays), nested messages or ().
+// Maps with keys that aren't strings get their keys written as strings.
+//
+// Besides Rhai's own functions scripts can use write_file(path, text) and append_file(path, text).
+
+pub struct Scripts {
+    engine: Engine,
+    scripts: Vec<Script>,
+    registry: Arc<Mutex<Registry>>,
+}
+
+struct Script {
+    path: String,
+    ast: AST,
+}
+
+// `on` is registered on the engine once for all scripts, so it's told which script is running (while it's loaded
+// or one of its handlers runs) to put the handler with the script it came from. Handlers run against their own
+// script's AST, and `on` called from a handler adds to that script's handlers.
+#[derive(Default)]
+struct Registry {
+    running: usize,
+    // by script, in the order of `Scripts::scripts`
+    handlers: Vec<Vec<Handler>>,
+}
+
+impl Registry {
+    fn add(&mut self, selector: Selector, function: FnPtr) {
+        self.handlers[self.running].push(Handler { selector, function });
+    }
+}
+
+struct Handler {
+    selector: Selector,
+    function: FnPtr,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Selector {
+    kind: Option<&'static str>,
+    code: Option<Code>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Code {
+    Opcode(u8),
+    Event(u8),
+}
+
+const KINDS: [&str; 6] = [
+    "request",
+    "response",
+    "event",
+    "internal_request",
+    "internal_response",
+    "error",
+];
+
+impl Selector {
+    fn kind(what: &str) -> Option<Self> {
+        if what == "*" {
+            return Some(Self::default());
+        }
+        let kind = KINDS.iter().copied().find(|k| *k == what)?;
+        Some(Self {
+            kind: Some(kind),
+            code: None,
+        })
+    }
+    // A number is an opcode unless the kind says it's an event, a name is looked up as an opcode first
+    fn code(kind: Option<&str>, what: &Dynamic) -> Result<Code, Box<EvalAltResult>> {
+        let event = kind == Some("event");
+        if let Ok(i) = what.as_int() {
+            let code = u8::try_from(i).map_err(|_| format!("code {} out of range", i))?;
+            return Ok(if event {
+                Code::Event(code)
+            } else {
+                Code::Opcode(code)
+            });
+        }
+        let name = what
+            .clone()
+            .into_string()
+            .map_err(|t| format!("expected an opcode or event, got {}", t))?;
+        match WebServicesOpCode::from_str(&name) {
+            Ok(op) if !event => Ok(Code::Opcode(op as u8)),
+            _ => match registry::event_code(&name) {
+                Some(e) if event || kind.is_none() => Ok(Code::Event(e)),
+                _ => Err(format!("unknown opcode or event {}", name).into()),
+            },
+        }
+    }
+    fn matches(&self, kind: &str, code: Option<Code>) -> bool {
+        (self.kind.is_none() || self.kind == Some(kind))
+            && (self.code.is_none() || self.code == code)
+    }
+}
+
+impl Scripts {
+    pub fn load(paths: &[impl AsRef<Path>]) -> Result<Self> {
+        let mut engine = Engine::new();
+        let registry = Arc::new(Mutex::new(Registry::default()));
+        let r = registry.clone();
+        engine.register_fn(
+            "on",
+            move |what: Dynamic, function: FnPtr| -> Result<(), Box<EvalAltResult>> {
+                let selector = match what
+                    .clone()
+                    .into_string()
+                    .ok()
+                    .and_then(|w| Selector::kind(&w))
+                {
+                    Some(s) => s,
+                    None => Selector {
+                        kind: None,
+                        code: Some(Selector::code(None, &what)?),
+                    },
+                };
+                r.lock().unwrap().add(selector, function);
+                Ok(())
+            },
+        );
+        let r = registry.clone();
+        engine.register_fn(
+            "on",
+            move |kind: &str, what: Dynamic, function: FnPtr| -> Result<(), Box<EvalAltResult>> {
+                let mut selector =
+                    Selector::kind(kind).ok_or_else(|| format!("unknown kind {}", kind))?;
+                selector.code = Some(Selector::code(selector.kind, &what)?);
+                r.lock().unwrap().add(selector, function);
+                Ok(())
+            },
+        );
+        engine
+            .register_fn("write_file", |path: &str, text: &str| {
+                fs::write(path, text).map_err(|e| file_error(path, e))
+            })
+            .register_fn("append_file", |path: &str, text: &str| {
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .and_then(|mut f| f.write_all(text.as_bytes()))
+                    .map_err(|e| file_error(path, e))
+            });
+        let mut scripts = Vec::new();
+        for path in paths {
+            let path = path.as_ref();
+            let source = fs::read_to_string(path)
+                .with_context(|| format!("reading script {}", path.display()))?;
+            {
+                let mut r = registry.lock().unwrap();
+                r.running = scripts.len();
+                r.handlers.push(Vec::new());
+            }
+            scripts.push(Script::load(&engine, path.display().to_string(), &source)?);
+        }
+        Ok(Self {
+            engine,
+            scripts,
+            registry,
+        })
+    }
+    // Runs every handler that wants this message. A handler that fails is reported and the others still run
+    pub fn handle(&mut self, m: &CapturedMessage) {
+        let (kind, code) = match &m.message {
+            Ok(message) => kind_and_code(message),
+            Err(_) => ("error", None),
+        };
+        let mut map = None;
+        for (i, script) in self.scripts.iter().enumerate() {
+            // taken out of the registry first, a handler calling `on` needs it
+            let functions: Vec<FnPtr> = {
+                let mut r = self.registry.lock().unwrap();
+                r.running = i;
+                r.handlers[i]
+                    .iter()
+                    .filter(|h| h.selector.matches(kind, code))
+                    .map(|h| h.function.clone())
+                    .collect()
+            };
+            for function in functions {
+                let map = map.get_or_insert_with(|| message_map(m)).clone();
+                let res = function.call::<Dynamic>(&self.engine, &script.ast, (map,));
+                if let Err(e) = res {
+                    eprintln!("error in {} handling a message: {}", script.path, e);
+                }
+            }
+        }
+    }
+}
+
+impl Script {
+    fn load(engine: &Engine, path: String, source: &str) -> Result<Self> {
+        let ast = engine
+            .compile(source)
+            .map_err(|e| anyhow!("{}", e))
+            .with_context(|| format!("compiling script {}", path))?;
+        engine
+            .run_ast(&ast)
+            .map_err(|e| anyhow!("{}", e))
+            .with_context(|| format!("running script {}", path))?;
+        Ok(Self { path, ast })
+    }
+}
+
+fn file_error(path: &str, e: std::io::Error) -> Box<EvalAltResult> {
+    format!("{}: {}", path, e).into()
+}
+
+fn kind_and_code(message: &Message) -> (&'static str, Option<Code>) {
+    match message {
+        Message::OperationRequest(r) => ("request", Some(Code::Opcode(r.opcode()))),
+        Message::OperationResponse(r) => ("response", Some(Code::Opcode(r.opcode()))),
+        Message::Event(e) => ("event", Some(Code::Event(e.event_code()))),
+        Message::InternalOperationRequest(r) => {
+            ("internal_request", Some(Code::Opcode(r.opcode())))
+        }
+        Message::InternalOperationResponse(r) => {
+            ("internal_response", Some(Code::Opcode(r.opcode())))
+        }
+        // not decoded, no handler can ask for these by kind
+        _ => ("other", None),
+    }
+}
+
+fn message_map(m: &CapturedMessage) -> Dynamic {
+    let mut map = Map::new();
+    map.insert("direction".into(), m.direction.as_str().into());
+    map.insert("connection".into(), (m.connection as INT).into());
+    map.insert("timestamp".into(), m.timestamp.as_secs_f64().into());
+    map.insert("raw".into(), Dynamic::from_blob(m.raw.clone()));
+    if let Some(decrypted) = &m.decrypted {
+        map.insert("decrypted".into(), Dynamic::from_blob(decrypted.clone()));
+    }
+    match &m.message {
+        Ok(message) => {
+            map.insert("kind".into(), kind_and_code(message).0.into());
+            message_fields(message, &mut map);
+        }
+        Err(e) => {
+            map.insert("kind".into(), "error".into());
+            map.insert("encrypted".into(), is_encrypted(&m.raw).into());
+            map.insert("error".into(), format!("{:#}", e).into());
+        }
+    }
+    map.into()
+}
+
+fn message_fields(message: &Message, map: &mut Map) {
+    let (table, owner) = match message {
+        Message::OperationRequest(r) | Message::InternalOperationRequest(r) => {
+            map.insert("opcode".into(), (r.opcode() as INT).into());
+            (r.parameters(), ParameterOwner::Operation(r.opcode()))
+        }
+        Message::OperationResponse(r) | Message::InternalOperationResponse(r) => {
+            map.insert("opcode".into(), (r.opcode() as INT).into());
+            map.insert("return_code".into(), (r.return_code() as INT).into());
+            map.insert("debug_message".into(), value_dynamic(r.debug_message()));
+            (r.parameters(), ParameterOwner::Operation(r.opcode()))
+        }
+        Message::Event(e) => {
+            map.insert("event_code".into(), (e.event_code() as INT).into());
+            (e.params(), ParameterOwner::Event(e.event_code()))
+        }
+        _ => return,
+    };
+    let internal = matches!(
+        message,
+        Message::InternalOperationRequest(_) | Message::InternalOperationResponse(_)
+    );
+    // internal operations have their own opcodes, the names are only for the WebServices ones
+    let name = match (message, internal) {
+        (_, true) => None,
+        (Message::Event(e), _) => registry::event_name(e.event_code()),
+        (Message::OperationRequest(r), _) => {
+            WebServicesOpCode::from_repr(r.opcode()).map(|o| format!("{:?}", o))
+        }
+        (Message::OperationResponse(r), _) => {
+            WebServicesOpCode::from_repr(r.opcode()).map(|o| format!("{:?}", o))
+        }
+        _ => None,
+    };
+    map.insert("name".into(), name.map_or(Dynamic::UNIT, Into::into));
+    map.insert(
+        "params".into(),
+        params_dynamic(table, (!internal).then_some(owner)),
+    );
+}
+
+fn params_dynamic(table: &ParameterTable, owner: Option<ParameterOwner>) -> Dynamic {
+    let mut map = Map::new();
+    for (code, value) in table.iter() {
+        let key =
+            registry::parameter_name(owner, *code).map_or_else(|| code.to_string(), str::to_string);
+        map.insert(key.into(), value_dynamic(value));
+    }
+    map.into()
+}
+
+fn value_dynamic(value: &Value) -> Dynamic {
+    match value {
+        Value::Byte(b) => (*b as INT).into(),
+        Value::Bool(b) => (*b).into(),
+        Value::Short(s) => (*s as INT).into(),
+        Value::Int(i) => (*i as INT).into(),
+        Value::Long(l) => (*l as INT).into(),
+        Value::Float(f) => (f.0 as f64).into(),
+        Value::Double(d) => d.0.into(),
+        Value::String(s) => s.clone().into(),
+        Value::Array(a) => a.iter().map(value_dynamic).collect::<Array>().into(),
+        Value::ObjectArray(a) => a.iter().map(value_dynamic).collect::<Array>().into(),
+        Value::StringArray(a) => a
+            .iter()
+            .cloned()
+            .map(Dynamic::from)
+            .collect::<Array>()
+            .into(),
+        Value::IntegerArray(a) => a
+            .iter()
+            .map(|i| Dynamic::from(*i as INT))
+            .collect::<Array>()
+            .into(),
+        Value::ByteArray(a) => Dynamic::from_blob(a.clone()),
+        Value::Dictionary(d) => map_dynamic(d.iter()),
+        Value::HashTable(h) => map_dynamic(h.iter()),
+        Value::EventData(e) => nested(Message::Event(e.clone())),
+        Value::OperationRequest(r) => nested(Message::OperationRequest(r.clone())),
+        Value::OperationResponse(r) => nested(Message::OperationResponse(r.clone())),
+        Value::Null(_) => Dynamic::UNIT,
+    }
+}
+
+fn nested(message: Message) -> Dynamic {
+    let mut map = Map::new();
+    map.insert("kind".into(), kind_and_code(&message).0.into());
+    message_fields(&message, &mut map);
+    map.into()
+}
+
+fn map_dynamic<'a>(entries: impl Iterator<Item = (&'a Value, &'a Value)>) -> Dynamic {
+    let mut map = Map::new();
+    for (k, v) in entries {
+        let key = match k {
+            Value::String(s) => s.clone(),
+            Value::Byte(b) => b.to_string(),
+            Value::Short(s) => s.to_string(),
+            Value::Int(i) => i.to_string(),
+            Value::Long(l) => l.to_string(),
+            k => format!("{:?}", k),
+        };
+        map.insert(key.into(), value_dynamic(v));
+    }
+    map.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Scripts;
+    use crate::{capture::CapturedMessage, message::Direction};
+
+    #[test]
+    fn test_script_handlers() {
+        let dir = std::env::temp_dir().join(format!("rcsniff2-script-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let out = dir.join("out.txt");
+        let script = dir.join("hooks.rhai");
+        std::fs::write(
+            &script,
+            format!(
+                r#"
+                let seen = 0;
+                on("LoadWallet", |m| {{
+                    seen += 1;
+                    append_file({out:?}, `${{m.kind}} ${{m.name}} ${{seen}} ${{m.params["5"]}}` + "\n");
+                }});
+                on("error", |m| append_file({out:?}, `error ${{m.direction}}` + "\n"));
+                // a number is an event code with the event kind, an opcode otherwise
+                on("event", 42, |m| append_file({out:?}, `event ${{m.event_code}}` + "\n"));
+                on(42, |m| append_file({out:?}, "opcode 42\n"));
+                "#,
+                out = out.to_str().unwrap()
+            ),
+        )
+        .unwrap();
+        // registers a handler from inside a handler, which has to end up with this script and not the one
+        // loaded after it
+        let nested = dir.join("nested.rhai");
+        std::fs::write(
+            &nested,
+            format!(
+                r#"
+                fn whose() {{ "nested" }}
+                let added = false;
+                on("request", |m| {{
+                    if !added {{
+                        added = true;
+                        on("request", |m| append_file({out:?}, `${{whose()}} ${{m.name}}` + "\n"));
+                    }}
+                }});
+                "#,
+                out = out.to_str().unwrap()
+            ),
+        )
+        .unwrap();
+        let mut scripts = Scripts::load(&[&nested, &script]).unwrap();
+        for raw in [
+            vec![0xF3, 2, 66, 0, 1, 5, 105, 0, 0, 0, 9],
+            vec![0xF3, 2, 3, 0, 0],
+            vec![0xF3, 2, 66, 0, 1, 5, 105, 0, 0, 0, 10],
+            vec![0xF3, 2, 66, 0, 1],
+            vec![0xF3, 4, 42, 0, 0],
+        ] {
+            scripts.handle(&CapturedMessage::decoded(Direction::Outgoing, raw));
+        }
+        let written = std::fs::read_to_string(&out).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            written,
+            "request LoadWallet 1 9\n\
+             nested GetPlayerLevelData\n\
+             nested LoadWallet\n\
+             request LoadWallet 2 10\n\
+             error out\n\
+             event 42\n"
+        );
+    }
+}
@@ -1,15 +1,29 @@
 use core::slice;
-use std::{collections::HashMap, fmt::Debug, hash::Hash, io::Read};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    hash::Hash,
+    io::{Read, Write},
+};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use enum_dispatch::enum_dispatch;
 use newtype::NewType;
 use strum::AsRefStr;
 
-use crate::util::{HashableDouble, HashableFloat, HashableHashmap};
+use crate::{
+    registry::{self, NamedParameters, ParameterOwner},
+    spans::SpanRecorder,
+    util::{HashableDouble, HashableFloat, HashableHashmap},
+    value_diff::PathSegment,
+};
 
-use self::{op_code::WebServicesOpCode, type_code::TypeCode};
+use self::{
+    op_code::{Service, WebServicesOpCode},
+    type_code::TypeCode,
+};
 
+pub mod event_code;
 pub mod op_code;
 pub mod type_code;
 #[enum_dispatch]
@@ -44,6 +58,31 @@ pub enum Value {
     OperationResponse(OperationResponse),
     Null(()),
 }
+impl Value {
+    pub fn type_code(&self) -> TypeCode {
+        match self {
+            Value::Byte(_) => TypeCode::Byte,
+            Value::Bool(_) => TypeCode::Boolean,
+            Value::Short(_) => TypeCode::Short,
+            Value::Int(_) => TypeCode::Integer,
+            Value::Long(_) => TypeCode::Long,
+            Value::Float(_) => TypeCode::Float,
+            Value::Double(_) => TypeCode::Double,
+            Value::String(_) => TypeCode::String,
+            Value::Array(_) => TypeCode::Array,
+            Value::ObjectArray(_) => TypeCode::ObjectArray,
+            Value::StringArray(_) => TypeCode::StringArray,
+            Value::IntegerArray(_) => TypeCode::IntegerArray,
+            Value::ByteArray(_) => TypeCode::ByteArray,
+            Value::Dictionary(_) => TypeCode::Dictionary,
+            Value::HashTable(_) => TypeCode::Hashtable,
+            Value::EventData(_) => TypeCode::EventData,
+            Value::OperationRequest(_) => TypeCode::OperationRequest,
+            Value::OperationResponse(_) => TypeCode::OperationResponse,
+            Value::Null(_) => TypeCode::Null,
+        }
+    }
+}
 impl Debug for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -69,7 +108,7 @@ impl Debug for Value {
         }
     }
 }
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Clone, PartialEq, Eq, Hash)]
 pub struct EventData {
     params: ParameterTable,
     event_code: u8,
@@ -86,6 +125,74 @@ pub struct OperationRequest {
     opcode: u8,
     parameters: ParameterTable,
 }
+impl EventData {
+    pub fn new(event_code: u8, params: ParameterTable) -> Self {
+        Self { params, event_code }
+    }
+    pub fn event_code(&self) -> u8 {
+        self.event_code
+    }
+    pub fn params(&self) -> &ParameterTable {
+        &self.params
+    }
+}
+impl OperationResponse {
+    pub fn new(
+        opcode: u8,
+        return_code: i16,
+        debug_message: Value,
+        parameters: ParameterTable,
+    ) -> Self {
+        Self {
+            opcode,
+            return_code,
+            debug_message: Box::new(debug_message),
+            parameters,
+        }
+    }
+    pub fn opcode(&self) -> u8 {
+        self.opcode
+    }
+    pub fn return_code(&self) -> i16 {
+        self.return_code
+    }
+    pub fn debug_message(&self) -> &Value {
+        &self.debug_message
+    }
+    pub fn parameters(&self) -> &ParameterTable {
+        &self.parameters
+    }
+}
+impl OperationRequest {
+    pub fn new(opcode: u8, parameters: ParameterTable) -> Self {
+        Self { opcode, parameters }
+    }
+    pub fn opcode(&self) -> u8 {
+        self.opcode
+    }
+    pub fn parameters(&self) -> &ParameterTable {
+        &self.parameters
+    }
+}
+impl Debug for EventData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut s = f.debug_struct("EventData");
+        if let Some(name) = registry::event_name(self.event_code) {
+            s.field("event_code", &format_args!("{}", name));
+        } else {
+            s.field("event_code", &self.event_code);
+        }
+        s.field(
+            "params",
+            &NamedParameters {
+                table: &self.params,
+                service: Service::WebServices,
+                owner: ParameterOwner::Event(self.event_code),
+            },
+        )
+        .finish()
+    }
+}
 impl Debug for OperationResponse {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut s = f.debug_struct("OperationResponse");
@@ -94,7 +201,15 @@ impl Debug for OperationResponse {
         } else {
             s.field("opcode", &self.opcode);
         }
-        s.field("parameters", &self.parameters).finish()
+        s.field(
+            "parameters",
+            &NamedParameters {
+                table: &self.parameters,
+                service: Service::WebServices,
+                owner: ParameterOwner::Operation(self.opcode),
+            },
+        )
+        .finish()
     }
 }
 impl Debug for OperationRequest {
@@ -105,11 +220,34 @@ impl Debug for OperationRequest {
         } else {
             s.field("opcode", &self.opcode);
         }
-        s.field("parameters", &self.parameters).finish()
+        s.field(
+            "parameters",
+            &NamedParameters {
+                table: &self.parameters,
+                service: Service::WebServices,
+                owner: ParameterOwner::Operation(self.opcode),
+            },
+        )
+        .finish()
     }
 }
 #[derive(Clone, NewType, PartialEq, Eq, Hash)]
 pub struct ParameterTable(HashableHashmap<u8, Value>);
+impl ParameterTable {
+    pub fn new() -> Self {
+        Self(HashMap::new().into())
+    }
+}
+impl Default for ParameterTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl From<HashMap<u8, Value>> for ParameterTable {
+    fn from(value: HashMap<u8, Value>) -> Self {
+        Self(value.into())
+    }
+}
 impl Debug for ParameterTable {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_tuple("Parameter Table").field(&self.0 .0).finish()
@@ -132,29 +270,93 @@ pub struct CustomType {
 pub struct StreamDeserializer<T> {
     pub reader: T,
     pub custom_type_impls: HashMap<u8, CustomType>,
+    // where each value came from, only recorded when set
+    pub spans: Option<SpanRecorder>,
+    // bytes read so far
+    position: usize,
 }
 impl<T: Read> StreamDeserializer<T> {
     pub fn new(reader: T) -> Self {
         Self {
             reader,
             custom_type_impls: HashMap::new(),
+            spans: None,
+            position: 0,
         }
     }
+    pub fn position(&self) -> usize {
+        self.position
+    }
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.reader.read_exact(buf)?;
+        self.position += buf.len();
+        Ok(())
+    }
     pub fn read_byte(&mut self) -> Result<u8> {
         let mut val = 0u8;
-        self.reader.read_exact(slice::from_mut(&mut val))?;
+        self.read_exact(slice::from_mut(&mut val))?;
         Ok(val)
     }
     pub fn read_bytes<const N: usize>(&mut self) -> Result<[u8; N]> {
         let mut buf = [0u8; N];
-        self.reader.read_exact(&mut buf)?;
+        self.read_exact(&mut buf)?;
         Ok(buf)
     }
+    // span helpers, these do nothing unless spans are being recorded
+    fn open_span(&mut self, segment: Option<PathSegment>, field: &'static str) {
+        if let Some(spans) = &mut self.spans {
+            spans.open(self.position, segment, field);
+        }
+    }
+    fn close_span(&mut self, type_code: Option<TypeCode>) {
+        if let Some(spans) = &mut self.spans {
+            spans.close(self.position, type_code);
+        }
+    }
+    // dictionary entries only know their path once the key is read
+    fn name_span(&mut self, segment: impl FnOnce() -> PathSegment) {
+        if let Some(spans) = &mut self.spans {
+            spans.set_segment(segment());
+        }
+    }
+    // the bytes read by `read` that aren't a value of their own: lengths, type codes, opcodes
+    fn spanned<R>(
+        &mut self,
+        field: &'static str,
+        read: impl FnOnce(&mut Self) -> Result<R>,
+    ) -> Result<R> {
+        let start = self.position;
+        let r = read(self)?;
+        if let Some(spans) = &mut self.spans {
+            spans.leaf(start..self.position, field);
+        }
+        Ok(r)
+    }
+    fn item<R>(
+        &mut self,
+        index: usize,
+        code: TypeCode,
+        read: impl FnOnce(&mut Self) -> Result<R>,
+    ) -> Result<R> {
+        self.open_span(Some(PathSegment::Index(index)), "");
+        let r = read(self)?;
+        self.close_span(Some(code));
+        Ok(r)
+    }
     pub fn deserialize(&mut self, type_code: u8) -> Result<Value> {
+        self.deserialize_at(None, "", type_code)
+    }
+    fn deserialize_at(
+        &mut self,
+        segment: Option<PathSegment>,
+        field: &'static str,
+        type_code: u8,
+    ) -> Result<Value> {
         let Ok(code) = TypeCode::from_repr(type_code).context("getting type code") else {
             return Ok(Value::Null(()));
         };
-        Ok(match code {
+        self.open_span(segment, field);
+        let value = match code {
             TypeCode::Unknown | TypeCode::Null => Value::Null(()),
             TypeCode::Boolean => self.deserialize_bool()?.into(),
             TypeCode::Byte => self.deserialize_byte()?.into(),
@@ -166,7 +368,7 @@ impl<T: Read> StreamDeserializer<T> {
             TypeCode::String => self.deserialize_string()?.into(),
             TypeCode::Array => self.deserialize_array()?.into(),
             TypeCode::Custom => {
-                let custom_code = self.read_byte()?;
+                let custom_code = self.spanned("custom type", Self::read_byte)?;
                 self.deserialize_custom(custom_code)?
             }
             TypeCode::Hashtable => self.deserialize_hashtable()?.into(),
@@ -178,7 +380,9 @@ impl<T: Read> StreamDeserializer<T> {
             TypeCode::OperationRequest => self.deserialize_operation_request()?.into(),
             TypeCode::OperationResponse => self.deserialize_operation_response()?.into(),
             TypeCode::EventData => self.deserialize_event_data()?.into(),
-        })
+        };
+        self.close_span(Some(code));
+        Ok(value)
     }
     pub fn deserialize_byte(&mut self) -> Result<u8> {
         Ok(self.read_byte()?)
@@ -202,69 +406,78 @@ impl<T: Read> StreamDeserializer<T> {
         Ok(f64::from_be_bytes(self.read_bytes()?).into())
     }
     pub fn deserialize_string(&mut self) -> Result<String> {
-        let len = self.deserialize_short()?;
+        let len = self.spanned("length", Self::deserialize_short)?;
         let mut v = vec![0; len as usize];
-        self.reader.read_exact(&mut v)?;
+        self.read_exact(&mut v)?;
         Ok(String::from_utf8(v)?)
     }
     pub fn read_type_code(&mut self) -> Result<TypeCode> {
-        let val = self.read_byte()?;
+        let val = self.spanned("type code", Self::read_byte)?;
         let ret = TypeCode::from_repr(val)
             .context(format!("parsing type code, got unexpected code {}", val));
         ret
     }
     pub fn deserialize_array(&mut self) -> Result<Vec<Value>> {
-        let len = self.deserialize_short()?;
+        let len = self.spanned("length", Self::deserialize_short)?;
         let mut v = Vec::with_capacity(len as usize);
         let item_type = self.read_type_code()?;
         match item_type {
             TypeCode::Array => {
-                for _ in 0..len {
-                    v.push(self.deserialize_array()?.into())
+                for i in 0..len as usize {
+                    v.push(self.item(i, item_type, Self::deserialize_array)?.into())
                 }
             }
             TypeCode::ByteArray => {
-                for _ in 0..len {
-                    v.push(self.deserialize_byte_array()?.into())
+                for i in 0..len as usize {
+                    v.push(
+                        self.item(i, item_type, Self::deserialize_byte_array)?
+                            .into(),
+                    )
                 }
             }
             TypeCode::Custom => {
-                let custom_type = self.read_byte()?;
+                let custom_type = self.spanned("custom type", Self::read_byte)?;
                 let custom_type = *self.custom_type_impls.get(&custom_type).context(format!(
                     "failed to get deserializer for custom type id {}",
                     custom_type
                 ))?;
                 let mut buf = Vec::new();
-                for _ in 0..len {
-                    let len2 = self.deserialize_short()?;
-                    buf.clear();
-                    buf.resize(len2 as usize, 0u8);
-                    self.reader.read_exact(&mut buf)?;
+                for i in 0..len as usize {
+                    self.item(i, item_type, |d| {
+                        let len2 = d.spanned("length", Self::deserialize_short)?;
+                        buf.clear();
+                        buf.resize(len2 as usize, 0u8);
+                        d.read_exact(&mut buf)
+                    })?;
                     v.push((custom_type.deserialize)(&buf));
                 }
             }
             TypeCode::Dictionary => self.deserialize_dict_array(len, &mut v)?,
             _ => {
-                for _ in 0..len {
-                    v.push(self.deserialize(item_type as u8)?);
+                for i in 0..len as usize {
+                    v.push(self.deserialize_at(
+                        Some(PathSegment::Index(i)),
+                        "",
+                        item_type as u8,
+                    )?);
                 }
             }
         }
         Ok(v)
     }
     pub fn deserialize_custom(&mut self, custom_type: u8) -> Result<Value> {
-        let len = self.deserialize_short()?;
+        let len = self.spanned("length", Self::deserialize_short)?;
         let custom_type = *self.custom_type_impls.get(&custom_type).context(format!(
             "failed to get deserializer for custom type id {}",
             custom_type
         ))?;
         let mut buf = vec![0; len as usize];
-        self.reader.read_exact(&mut buf)?;
+        self.read_exact(&mut buf)?;
 
         Ok((custom_type.deserialize)(&buf))
     }
     pub fn deserialize_dictionary_type(&mut self) -> Result<(TypeCode, TypeCode)> {
-        let arr = self.read_bytes::<2>()?;
+        let arr = self.spanned("key and value types", Self::read_bytes::<2>)?;
         Ok((
             TypeCode::from_repr(arr[0]).context("failed to get dictionary key type code")?,
             TypeCode::from_repr(arr[1]).context("failed to get dictionary key type code")?,
@@ -273,101 +486,119 @@ impl<T: Read> StreamDeserializer<T> {
     pub fn deserialize_dict_array(&mut self, size: i16, values: &mut Vec<Value>) -> Result<()> {
         let (key_type, value_type) = self.deserialize_dictionary_type()?;
 
-        for _ in 0..size {
-            let dictlen = self.deserialize_short()?;
-            let mut m = HashMap::with_capacity(size as usize);
-            for _ in 0..dictlen {
-                let key = self.deserialize_maybe_typed(key_type)?;
-                let value = self.deserialize_maybe_typed(value_type)?;
-                m.insert(key, value);
-            }
+        for i in 0..size as usize {
+            let m = self.item(i, TypeCode::Dictionary, |d| {
+                let dictlen = d.spanned("length", Self::deserialize_short)?;
+                let mut m = HashMap::with_capacity(size as usize);
+                for _ in 0..dictlen {
+                    let (key, value) = d.deserialize_entry(key_type, value_type)?;
+                    m.insert(key, value);
+                }
+                Ok(m)
+            })?;
             values.push(HashableHashmap(m).into());
         }
         Ok(())
     }
     pub fn deserialize_maybe_typed(&mut self, code: TypeCode) -> Result<Value> {
+        self.deserialize_maybe_typed_at("", code)
+    }
+    fn deserialize_maybe_typed_at(&mut self, field: &'static str, code: TypeCode) -> Result<Value> {
         let mut code = code;
         if code == TypeCode::Unknown {
             code = self.read_type_code()?;
         }
-        self.deserialize(code as u8)
+        self.deserialize_at(None, field, code as u8)
+    }
+    // A key and value of a dictionary or hashtable, Unknown types are read from before the key/value
+    fn deserialize_entry(
+        &mut self,
+        key_type: TypeCode,
+        value_type: TypeCode,
+    ) -> Result<(Value, Value)> {
+        self.open_span(None, "entry");
+        let key = self.deserialize_maybe_typed_at("key", key_type)?;
+        self.name_span(|| PathSegment::Key(key.clone()));
+        let value = self.deserialize_maybe_typed(value_type)?;
+        self.close_span(None);
+        Ok((key, value))
     }
     pub fn deserialize_byte_array(&mut self) -> Result<Vec<u8>> {
-        let len = self.deserialize_int()?;
+        let len = self.spanned("length", Self::deserialize_int)?;
         let mut v = vec![0; len as usize];
-        self.reader.read_exact(&mut v)?;
+        self.read_exact(&mut v)?;
         Ok(v)
     }
     pub fn deserialize_int_array(&mut self) -> Result<Vec<i32>> {
-        let len = self.deserialize_int()?;
+        let len = self.spanned("length", Self::deserialize_int)?;
         let mut v = Vec::with_capacity(len as usize);
-        for _ in 0..len {
-            v.push(self.deserialize_int()?);
+        for i in 0..len as usize {
+            v.push(self.item(i, TypeCode::Integer, Self::deserialize_int)?);
         }
         Ok(v)
     }
     pub fn deserialize_string_array(&mut self) -> Result<Vec<String>> {
-        let len = self.deserialize_short()?;
+        let len = self.spanned("length", Self::deserialize_short)?;
         let mut v = Vec::with_capacity(len as usize);
-        for _ in 0..len {
-            v.push(self.deserialize_string()?);
+        for i in 0..len as usize {
+            v.push(self.item(i, TypeCode::String, Self::deserialize_string)?);
         }
         Ok(v)
     }
     pub fn deserialize_object_array(&mut self) -> Result<ObjectArray> {
-        let len = self.deserialize_short()?;
+        let len = self.spanned("length", Self::deserialize_short)?;
         let mut v = Vec::with_capacity(len as usize);
-        for _ in 0..len {
+        for i in 0..len as usize {
             let c = self.read_type_code()? as u8;
-            v.push(self.deserialize(c)?);
+            v.push(self.deserialize_at(Some(PathSegment::Index(i)), "", c)?);
         }
         Ok(v.into())
     }
     pub fn deserialize_hashtable(&mut self) -> Result<HashTable<Value, Value>> {
-        let len = self.deserialize_short()?;
+        let len = self.spanned("length", Self::deserialize_short)?;
         let mut m = HashMap::with_capacity(len as usize);
         for _ in 0..len {
-            let keytype = self.read_type_code()?;
-            let key = self.deserialize(keytype as u8)?;
-            let valtype = self.read_type_code()?;
-            let val = self.deserialize(valtype as u8)?;
+            let (key, val) = self.deserialize_entry(TypeCode::Unknown, TypeCode::Unknown)?;
             m.insert(key, val);
         }
         Ok(HashTable(m.into()))
     }
     pub fn deserialize_dictionary(&mut self) -> Result<HashableHashmap<Value, Value>> {
         let (keytype, valtype) = self.deserialize_dictionary_type()?;
-        let len = self.deserialize_short()?;
+        let len = self.spanned("length", Self::deserialize_short)?;
         let mut m = HashMap::with_capacity(len as usize);
         for _ in 0..len {
-            let key = self.deserialize_maybe_typed(keytype)?;
-            let val = self.deserialize_maybe_typed(valtype)?;
+            let (key, val) = self.deserialize_entry(keytype, valtype)?;
             m.insert(key, val);
         }
         Ok(m.into())
     }
     pub fn deserialize_parameter_table(&mut self) -> Result<ParameterTable> {
-        let len = self.deserialize_short()?;
+        let len = self.spanned("parameter count", Self::deserialize_short)?;
         let mut m = HashMap::with_capacity(len as usize);
         for _ in 0..len {
-            let k = self.read_byte()?;
+            self.open_span(None, "parameter");
+            let k = self.spanned("key", Self::read_byte)?;
+            self.name_span(|| PathSegment::Parameter(k));
             let valtype = self.read_type_code()? as u8;
             let v = self.deserialize(valtype)?;
+            self.close_span(None);
             m.insert(k, v);
         }
         Ok(ParameterTable(m.into()))
     }
     pub fn deserialize_event_data(&mut self) -> Result<EventData> {
         Ok(EventData {
-            event_code: self.read_byte()?,
+            event_code: self.spanned("event code", Self::read_byte)?,
             params: self.deserialize_parameter_table()?,
         })
     }
     pub fn deserialize_operation_response(&mut self) -> Result<OperationResponse> {
-        let opcode = self.read_byte()?; //WebServicesOpCode::from_repr(self.read_byte()?).unwrap();
-        let return_code = self.deserialize_short()?;
+        let opcode = self.spanned("opcode", Self::read_byte)?; //WebServicesOpCode::from_repr(self.read_byte()?).unwrap();
+        let return_code = self.spanned("return code", Self::deserialize_short)?;
         let dbg_msgtype = self.read_type_code()?;
-        let debug_message = Box::new(self.deserialize(dbg_msgtype as u8)?);
+        let debug_message =
+            Box::new(self.deserialize_at(None, "debug message", dbg_msgtype as u8)?);
         let parameters = self.deserialize_parameter_table()?;
         Ok(OperationResponse {
             opcode,
@@ -377,8 +608,196 @@ impl<T: Read> StreamDeserializer<T> {
         })
     }
     pub fn deserialize_operation_request(&mut self) -> Result<OperationRequest> {
-        let opcode = self.read_byte()?;
+        let opcode = self.spanned("opcode", Self::read_byte)?;
         let parameters = self.deserialize_parameter_table()?;
         Ok(OperationRequest { opcode, parameters })
     }
 }
+
+// The inverse of StreamDeserializer. Photon doesn't record the declared key/value types of a dictionary, so
+// dictionaries (and arrays of them) are written with fixed types when every key/value has the same type and as
+// Unknown (typed per entry) otherwise. That decodes to the same Value, but may not be byte for byte what the game sent
+pub struct StreamSerializer<T> {
+    pub writer: T,
+}
+impl<T: Write> StreamSerializer<T> {
+    pub fn new(writer: T) -> Self {
+        Self { writer }
+    }
+    pub fn write_byte(&mut self, b: u8) -> Result<()> {
+        Ok(self.writer.write_all(&[b])?)
+    }
+    // type code followed by the value
+    pub fn serialize(&mut self, value: &Value) -> Result<()> {
+        self.write_byte(value.type_code() as u8)?;
+        self.serialize_untyped(value)
+    }
+    pub fn serialize_untyped(&mut self, value: &Value) -> Result<()> {
+        match value {
+            Value::Byte(b) => self.write_byte(*b),
+            Value::Bool(b) => self.write_byte(*b as u8),
+            Value::Short(s) => self.serialize_short(*s),
+            Value::Int(i) => self.serialize_int(*i),
+            Value::Long(l) => Ok(self.writer.write_all(&l.to_be_bytes())?),
+            Value::Float(f) => Ok(self.writer.write_all(&f.0.to_be_bytes())?),
+            Value::Double(d) => Ok(self.writer.write_all(&d.0.to_be_bytes())?),
+            Value::String(s) => self.serialize_string(s),
+            Value::Array(a) => self.serialize_array(a),
+            Value::ObjectArray(a) => {
+                self.serialize_len(a.0.len())?;
+                for v in &a.0 {
+                    self.serialize(v)?;
+                }
+                Ok(())
+            }
+            Value::StringArray(a) => {
+                self.serialize_len(a.len())?;
+                for s in a {
+                    self.serialize_string(s)?;
+                }
+                Ok(())
+            }
+            Value::IntegerArray(a) => {
+                self.serialize_int(i32::try_from(a.len())?)?;
+                for i in a {
+                    self.serialize_int(*i)?;
+                }
+                Ok(())
+            }
+            Value::ByteArray(a) => self.serialize_byte_array(a),
+            Value::Dictionary(d) => {
+                let (key_type, value_type) = dictionary_types([d]);
+                self.writer.write_all(&[key_type as u8, value_type as u8])?;
+                self.serialize_dictionary_entries(d, key_type, value_type)
+            }
+            Value::HashTable(h) => {
+                self.serialize_len(h.0.len())?;
+                for (k, v) in h.0.iter() {
+                    self.serialize(k)?;
+                    self.serialize(v)?;
+                }
+                Ok(())
+            }
+            Value::EventData(e) => self.serialize_event_data(e),
+            Value::OperationRequest(r) => self.serialize_operation_request(r),
+            Value::OperationResponse(r) => self.serialize_operation_response(r),
+            Value::Null(_) => Ok(()),
+        }
+    }
+    pub fn serialize_short(&mut self, s: i16) -> Result<()> {
+        Ok(self.writer.write_all(&s.to_be_bytes())?)
+    }
+    pub fn serialize_int(&mut self, i: i32) -> Result<()> {
+        Ok(self.writer.write_all(&i.to_be_bytes())?)
+    }
+    // most collections are limited to a short length
+    fn serialize_len(&mut self, len: usize) -> Result<()> {
+        let len = i16::try_from(len).context(format!("{} items don't fit in a short", len))?;
+        self.serialize_short(len)
+    }
+    pub fn serialize_string(&mut self, s: &str) -> Result<()> {
+        self.serialize_len(s.len())?;
+        Ok(self.writer.write_all(s.as_bytes())?)
+    }
+    pub fn serialize_byte_array(&mut self, a: &[u8]) -> Result<()> {
+        self.serialize_int(i32::try_from(a.len())?)?;
+        Ok(self.writer.write_all(a)?)
+    }
+    pub fn serialize_array(&mut self, a: &[Value]) -> Result<()> {
+        self.serialize_len(a.len())?;
+        let item_type = a.first().map(Value::type_code).unwrap_or(TypeCode::Null);
+        if a.iter().any(|v| v.type_code() != item_type) {
+            bail!("array items have different types, this can't be sent as an array");
+        }
+        self.write_byte(item_type as u8)?;
+        if item_type == TypeCode::Dictionary {
+            let dicts: Vec<_> = a
+                .iter()
+                .filter_map(|v| match v {
+                    Value::Dictionary(d) => Some(d),
+                    _ => None,
+                })
+                .collect();
+            let (key_type, value_type) = dictionary_types(dicts.iter().copied());
+            self.writer.write_all(&[key_type as u8, value_type as u8])?;
+            for d in dicts {
+                self.serialize_dictionary_entries(d, key_type, value_type)?;
+            }
+            return Ok(());
+        }
+        for v in a {
+            self.serialize_untyped(v)?;
+        }
+        Ok(())
+    }
+    fn serialize_dictionary_entries(
+        &mut self,
+        d: &HashableHashmap<Value, Value>,
+        key_type: TypeCode,
+        value_type: TypeCode,
+    ) -> Result<()> {
+        self.serialize_len(d.0.len())?;
+        for (k, v) in d.0.iter() {
+            self.serialize_maybe_typed(k, key_type)?;
+            self.serialize_maybe_typed(v, value_type)?;
+        }
+        Ok(())
+    }
+    pub fn serialize_maybe_typed(&mut self, value: &Value, code: TypeCode) -> Result<()> {
+        if code == TypeCode::Unknown {
+            self.serialize(value)
+        } else {
+            self.serialize_untyped(value)
+        }
+    }
+    pub fn serialize_parameter_table(&mut self, table: &ParameterTable) -> Result<()> {
+        self.serialize_len(table.0.len())?;
+        // sorted so the same table always gives the same bytes
+        let mut params: Vec<_> = table.0.iter().collect();
+        params.sort_by_key(|(k, _)| **k);
+        for (k, v) in params {
+            self.write_byte(*k)?;
+            self.serialize(v)?;
+        }
+        Ok(())
+    }
+    pub fn serialize_event_data(&mut self, event: &EventData) -> Result<()> {
+        self.write_byte(event.event_code)?;
+        self.serialize_parameter_table(&event.params)
+    }
+    pub fn serialize_operation_response(&mut self, response: &OperationResponse) -> Result<()> {
+        self.write_byte(response.opcode)?;
+        self.serialize_short(response.return_code)?;
+        self.serialize(&response.debug_message)?;
+        self.serialize_parameter_table(&response.parameters)
+    }
+    pub fn serialize_operation_request(&mut self, request: &OperationRequest) -> Result<()> {
+        self.write_byte(request.opcode)?;
+        self.serialize_parameter_table(&request.parameters)
+    }
+}
+
+// Shared key and value types of some dictionaries, Unknown where they differ
+fn dictionary_types<'a>(
+    dicts: impl IntoIterator<Item = &'a HashableHashmap<Value, Value>>,
+) -> (TypeCode, TypeCode) {
+    let mut key_type = None;
+    let mut value_type = None;
+    let merge = |acc: &mut Option<TypeCode>, code: TypeCode| {
+        *acc = match *acc {
+            None => Some(code),
+            Some(c) if c == code => Some(c),
+            Some(_) => Some(TypeCode::Unknown),
+        }
+    };
+    for d in dicts {
+        for (k, v) in d.0.iter() {
+            merge(&mut key_type, k.type_code());
+            merge(&mut value_type, v.type_code());
+        }
+    }
+    (
+        key_type.unwrap_or(TypeCode::Unknown),
+        value_type.unwrap_or(TypeCode::Unknown),
+    )
+}
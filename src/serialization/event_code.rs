@@ -0,0 +1,21 @@
+// Photon LoadBalancing reserves the 200+ range for its own events, everything below that is free for the game to use.
+// The game's events are named by [[event]] entries in data/parameters.toml (see registry::event_name)
+#[derive(strum::FromRepr, strum::EnumString, Clone, Copy, PartialEq, Eq, Debug, Hash)]
+#[repr(u8)]
+pub enum WebServicesEventCode {
+    //Builtin
+    AzureNodeInfo = 210,
+    AuthEvent = 223,
+    LobbyStats = 224,
+    AppStats = 226,
+    Match = 227,
+    QueueState = 228,
+    GameListUpdate = 229,
+    GameList = 230,
+    CacheSliceChanged = 250,
+    ErrorInfo = 251,
+    Disconnect = 252,
+    PropertiesChanged = 253,
+    Leave = 254,
+    Join = 255,
+}
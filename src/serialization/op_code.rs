@@ -1,5 +1,10 @@
+// Which photon application a connection belongs to. Opcodes, event codes and parameter codes are only meaningful per service
+#[derive(strum::EnumString, strum::AsRefStr, Clone, Copy, PartialEq, Eq, Debug, Hash)]
+pub enum Service {
+    WebServices,
+}
 #[allow(non_camel_case_types)]
-#[derive(strum::FromRepr, Clone, Copy, PartialEq, Eq, Debug, Hash)]
+#[derive(strum::FromRepr, strum::EnumString, Clone, Copy, PartialEq, Eq, Debug, Hash)]
 #[repr(u8)]
 pub enum WebServicesOpCode {
     FetchHasPendingCustomGameInvitation = 0,
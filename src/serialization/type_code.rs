@@ -1,7 +1,17 @@
-
-
-
-#[derive(strum::FromRepr, Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(
+    strum::FromRepr,
+    strum::EnumString,
+    serde::Serialize,
+    serde::Deserialize,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Debug,
+)]
 #[repr(u8)]
 pub enum TypeCode {
     Null = 42,
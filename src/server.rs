@@ -0,0 +1,531 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    sync::Arc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    spawn,
+    sync::mpsc::UnboundedSender,
+};
+
+use crate::{
+    capture::CapturedMessage,
+    encryption::{Encryption, KEY_PARAMETER},
+    framing::{frame_message, Frame, FrameReader},
+    input,
+    message::{decode_message, encode_message, is_encrypted, Direction, Message},
+    output::opcode_name,
+    registry::{self, resolve_event_code, resolve_opcode, CodeOrName, ParameterOwner},
+    serialization::{
+        op_code::{InternalOperation, Service},
+        type_code::TypeCode,
+        EventData, HashTable, ObjectArray, OperationRequest, OperationResponse, ParameterTable,
+        Value,
+    },
+    util::HashableHashmap,
+};
+
+// A stand-in WebServices server. It does the photon side of the handshake (init, pings, key exchange) itself and
+// answers operation requests from a list of canned responses, taken from a recorded session or a rule file.
+
+// What the client gets back for its init message. The client doesn't look past the message type
+const INIT_RESPONSE: [u8; 3] = [0xF3, 1, 0];
+// photon's "invalid operation" return code, sent when there is no canned response
+const NO_RESPONSE_RETURN_CODE: i16 = -2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CannedResponse {
+    pub opcode: u8,
+    // request parameters that have to be equal for this response to be used
+    pub when: ParameterTable,
+    pub response: OperationResponse,
+    // sent right after the response
+    pub events: Vec<EventData>,
+}
+
+// Responses to the same opcode are used in order, once per request, and the last one repeats
+#[derive(Debug, Clone, Default)]
+pub struct CannedResponses {
+    responses: Vec<CannedResponse>,
+}
+
+#[derive(Deserialize)]
+struct RuleFile {
+    #[serde(default)]
+    response: Vec<ResponseRule>,
+}
+#[derive(Deserialize)]
+struct ResponseRule {
+    operation: CodeOrName,
+    #[serde(default)]
+    return_code: i16,
+    debug_message: Option<String>,
+    #[serde(default)]
+    when: BTreeMap<String, serde_json::Value>,
+    #[serde(default)]
+    parameters: BTreeMap<String, serde_json::Value>,
+    #[serde(default)]
+    event: Vec<EventRule>,
+}
+#[derive(Deserialize)]
+struct EventRule {
+    event: CodeOrName,
+    #[serde(default)]
+    parameters: BTreeMap<String, serde_json::Value>,
+}
+
+impl CannedResponses {
+    pub fn new(responses: Vec<CannedResponse>) -> Self {
+        Self { responses }
+    }
+    // picks the format from the file extension like the parameter registry does
+    pub fn load_rules(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .context(format!("reading response rules {}", path.display()))?;
+        if path.extension().is_some_and(|e| e == "json") {
+            Self::from_json(&text)
+        } else {
+            Self::from_toml(&text)
+        }
+        .context(format!("loading response rules {}", path.display()))
+    }
+    pub fn from_toml(text: &str) -> Result<Self> {
+        Self::from_rules(toml::from_str(text)?)
+    }
+    pub fn from_json(text: &str) -> Result<Self> {
+        Self::from_rules(serde_json::from_str(text)?)
+    }
+    fn from_rules(file: RuleFile) -> Result<Self> {
+        let mut responses = Vec::new();
+        for rule in file.response {
+            let opcode = resolve_opcode(Service::WebServices, &rule.operation)?;
+            let owner = ParameterOwner::Operation(opcode);
+            let context = || format!("response to {}", opcode_name(opcode));
+            let mut events = Vec::new();
+            for e in rule.event {
+                let code = resolve_event_code(Service::WebServices, &e.event)?;
+                let params = parameters_from_json(&e.parameters, ParameterOwner::Event(code))
+                    .with_context(context)?;
+                events.push(EventData::new(code, params));
+            }
+            responses.push(CannedResponse {
+                opcode,
+                when: parameters_from_json(&rule.when, owner).with_context(context)?,
+                response: OperationResponse::new(
+                    opcode,
+                    rule.return_code,
+                    rule.debug_message.map_or(Value::Null(()), Value::String),
+                    parameters_from_json(&rule.parameters, owner).with_context(context)?,
+                ),
+                events,
+            });
+        }
+        Ok(Self { responses })
+    }
+    // Every response the server sent, in order. Events are sent after the response they followed in the session
+    pub fn from_session(messages: &[CapturedMessage]) -> Self {
+        let mut responses: Vec<CannedResponse> = Vec::new();
+        for m in messages
+            .iter()
+            .filter(|m| m.direction == Direction::Incoming)
+        {
+            match &m.message {
+                Ok(Message::OperationResponse(r)) => responses.push(CannedResponse {
+                    opcode: r.opcode(),
+                    when: ParameterTable::new(),
+                    response: r.clone(),
+                    events: Vec::new(),
+                }),
+                Ok(Message::Event(e)) => {
+                    if let Some(last) = responses.last_mut() {
+                        last.events.push(e.clone());
+                    }
+                }
+                _ => {}
+            }
+        }
+        Self { responses }
+    }
+    // `answered` is how many requests with this opcode were answered before on this connection
+    pub fn find(&self, request: &OperationRequest, answered: usize) -> Option<&CannedResponse> {
+        let candidates: Vec<_> = self
+            .responses
+            .iter()
+            .filter(|r| r.opcode == request.opcode())
+            .filter(|r| {
+                r.when
+                    .iter()
+                    .all(|(k, v)| request.parameters().get(k) == Some(v))
+            })
+            .collect();
+        candidates
+            .get(answered.min(candidates.len().saturating_sub(1)))
+            .copied()
+    }
+}
+
+// Rule files name parameters by code or registry name. Values are plain JSON/TOML, typed by the registry where it
+// knows the parameter's type and by the value itself otherwise. {type = "Long", value = 5} gives the type explicitly
+fn parameters_from_json(
+    params: &BTreeMap<String, serde_json::Value>,
+    owner: ParameterOwner,
+) -> Result<ParameterTable> {
+    let mut table = HashMap::new();
+    for (key, value) in params {
+        let code = match key.parse() {
+            Ok(code) => code,
+            Err(_) => registry::global()
+                .code(Service::WebServices, owner, key)
+                .context(format!("unknown parameter {}", key))?,
+        };
+        let hint = registry::global()
+            .get(Service::WebServices, owner, code)
+            .and_then(|i| i.type_code);
+        table.insert(
+            code,
+            value_from_json(value, hint).context(format!("parameter {}", key))?,
+        );
+    }
+    Ok(table.into())
+}
+
+pub fn value_from_json(json: &serde_json::Value, hint: Option<TypeCode>) -> Result<Value> {
+    use serde_json::Value as Json;
+    if let Json::Object(o) = json {
+        if let (2, Some(Json::String(t)), Some(v)) = (o.len(), o.get("type"), o.get("value")) {
+            let t: TypeCode = t.parse().context(format!("unknown type {}", t))?;
+            return value_from_json(v, Some(t));
+        }
+    }
+    let number = |json: &Json| json.as_f64().context("expected a number");
+    Ok(match (json, hint) {
+        (Json::Null, _) => Value::Null(()),
+        (Json::Bool(b), _) => Value::Bool(*b),
+        (Json::Number(n), Some(TypeCode::Byte)) => Value::Byte(n.to_string().parse()?),
+        (Json::Number(n), Some(TypeCode::Short)) => Value::Short(n.to_string().parse()?),
+        (Json::Number(n), Some(TypeCode::Integer)) => Value::Int(n.to_string().parse()?),
+        (Json::Number(n), Some(TypeCode::Long)) => Value::Long(n.to_string().parse()?),
+        (Json::Number(_), Some(TypeCode::Float)) => Value::Float((number(json)? as f32).into()),
+        (Json::Number(_), Some(TypeCode::Double)) => Value::Double(number(json)?.into()),
+        (Json::Number(n), _) => match n.as_i64() {
+            Some(i) => i32::try_from(i).map_or(Value::Long(i), Value::Int),
+            None => Value::Double(number(json)?.into()),
+        },
+        (Json::String(s), Some(TypeCode::ByteArray)) => Value::ByteArray(input::parse_hex(s)?),
+        (Json::String(s), _) => Value::String(s.clone()),
+        (Json::Array(a), Some(TypeCode::StringArray)) => Value::StringArray(
+            a.iter()
+                .map(|v| v.as_str().map(str::to_string).context("expected a string"))
+                .collect::<Result<_>>()?,
+        ),
+        (Json::Array(a), Some(TypeCode::IntegerArray)) => Value::IntegerArray(
+            a.iter()
+                .map(|v| Ok(v.to_string().parse()?))
+                .collect::<Result<_>>()?,
+        ),
+        (Json::Array(a), Some(TypeCode::ByteArray)) => Value::ByteArray(
+            a.iter()
+                .map(|v| Ok(v.to_string().parse()?))
+                .collect::<Result<_>>()?,
+        ),
+        (Json::Array(a), hint) => {
+            let items = a
+                .iter()
+                .map(|v| value_from_json(v, None))
+                .collect::<Result<Vec<_>>>()?;
+            // arrays have to have a single item type
+            let mixed = items
+                .windows(2)
+                .any(|w| w[0].type_code() != w[1].type_code());
+            if mixed || hint == Some(TypeCode::ObjectArray) {
+                Value::ObjectArray(ObjectArray::from(items))
+            } else {
+                Value::Array(items)
+            }
+        }
+        (Json::Object(o), hint) => {
+            let mut m = HashMap::new();
+            for (k, v) in o {
+                m.insert(Value::String(k.clone()), value_from_json(v, None)?);
+            }
+            if hint == Some(TypeCode::Hashtable) {
+                Value::HashTable(HashTable::from(HashableHashmap(m)))
+            } else {
+                Value::Dictionary(HashableHashmap(m))
+            }
+        }
+    })
+}
+
+// A message from the client and what goes back
+pub struct Exchange {
+    // decrypted, starting at 0xF3
+    pub request: Vec<u8>,
+    pub message: Message,
+    pub replies: Vec<Reply>,
+}
+pub struct Reply {
+    pub message: Message,
+    pub plain: Vec<u8>,
+    // encrypted when the request was
+    pub sent: Vec<u8>,
+}
+
+// Protocol state of one client connection
+pub struct ServerConnection {
+    responses: Arc<CannedResponses>,
+    answered: HashMap<u8, usize>,
+    encryption: Option<Encryption>,
+    started: Instant,
+}
+
+impl ServerConnection {
+    pub fn new(responses: Arc<CannedResponses>) -> Self {
+        Self {
+            responses,
+            answered: HashMap::new(),
+            encryption: None,
+            started: Instant::now(),
+        }
+    }
+    // ms since the connection was opened, what photon uses as the server time
+    fn server_time(&self) -> i32 {
+        self.started.elapsed().as_millis() as i32
+    }
+    // The client's ping is 0xF0 and its time. The answer has the server's time before it
+    pub fn answer_ping(&self, ping: &[u8]) -> Vec<u8> {
+        let mut out = vec![0xF0];
+        out.extend_from_slice(&self.server_time().to_be_bytes());
+        out.extend_from_slice(ping.get(1..5).unwrap_or(&[0; 4]));
+        out
+    }
+    // Takes a message from the client (starting at 0xF3) and works out the answer, encrypted if the message was
+    pub fn handle(&mut self, raw: &[u8]) -> Result<Exchange> {
+        let encrypted = is_encrypted(raw);
+        let request = if encrypted {
+            self.encryption
+                .as_ref()
+                .context("encrypted message before the key exchange")?
+                .decrypt_message(raw)
+                .context("could not decrypt message")?
+        } else {
+            raw.to_vec()
+        };
+        let message = decode_message(&request)?;
+        let replies = match &message {
+            Message::Init => vec![Message::InitResponse],
+            Message::InternalOperationRequest(r) => vec![self.internal_operation(r)?],
+            Message::OperationRequest(r) => {
+                let answered = self.answered.entry(r.opcode()).or_default();
+                let canned = self.responses.find(r, *answered);
+                *answered += 1;
+                match canned {
+                    Some(c) => std::iter::once(Message::OperationResponse(c.response.clone()))
+                        .chain(c.events.iter().cloned().map(Message::Event))
+                        .collect(),
+                    None => vec![Message::OperationResponse(OperationResponse::new(
+                        r.opcode(),
+                        NO_RESPONSE_RETURN_CODE,
+                        Value::String(format!(
+                            "no canned response for {}",
+                            opcode_name(r.opcode())
+                        )),
+                        ParameterTable::new(),
+                    ))],
+                }
+            }
+            _ => Vec::new(),
+        };
+        let mut out = Vec::with_capacity(replies.len());
+        for reply in replies {
+            let plain = match &reply {
+                Message::InitResponse => INIT_RESPONSE.to_vec(),
+                m => encode_message(m)?,
+            };
+            let sent = match (&self.encryption, encrypted) {
+                (Some(e), true) => e
+                    .encrypt_message(&plain)
+                    .context("could not encrypt message")?,
+                _ => plain.clone(),
+            };
+            out.push(Reply {
+                message: reply,
+                plain,
+                sent,
+            });
+        }
+        Ok(Exchange {
+            request,
+            message,
+            replies: out,
+        })
+    }
+    fn internal_operation(&mut self, request: &OperationRequest) -> Result<Message> {
+        let params: HashMap<u8, Value> = match InternalOperation::from_repr(request.opcode()) {
+            Some(InternalOperation::InitEncryption) => {
+                let Some(Value::ByteArray(key)) = request.parameters().get(&KEY_PARAMETER) else {
+                    bail!("key exchange without a client key");
+                };
+                let mut encryption = Encryption::new();
+                encryption.make_shared_key(key);
+                let public_key = encryption.public_key.to_bytes_be();
+                self.encryption = Some(encryption);
+                HashMap::from([(KEY_PARAMETER, Value::ByteArray(public_key))])
+            }
+            // the client's time comes back along with the server's
+            Some(InternalOperation::KeyOrPing) => {
+                let mut params = HashMap::from([(2, Value::Int(self.server_time()))]);
+                if let Some(client_time) = request.parameters().get(&1) {
+                    params.insert(1, client_time.clone());
+                }
+                params
+            }
+            _ => bail!("unknown internal operation {}", request.opcode()),
+        };
+        Ok(Message::InternalOperationResponse(OperationResponse::new(
+            request.opcode(),
+            0,
+            Value::Null(()),
+            params.into(),
+        )))
+    }
+}
+
+// Accepts connections until the listener fails. Everything received and sent is passed to `observer`, decrypted
+pub async fn serve(
+    listener: TcpListener,
+    responses: Arc<CannedResponses>,
+    observer: Option<UnboundedSender<CapturedMessage>>,
+) -> Result<()> {
+    let mut next_connection = 0u32;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let connection = next_connection;
+        next_connection = next_connection.wrapping_add(1);
+        let responses = responses.clone();
+        let observer = observer.clone();
+        spawn(async move {
+            if let Err(e) = handle_connection(stream, connection, responses, observer).await {
+                eprintln!("connection {}: {:#}", connection, e);
+            }
+        });
+    }
+}
+
+pub async fn handle_connection(
+    mut stream: TcpStream,
+    connection: u32,
+    responses: Arc<CannedResponses>,
+    observer: Option<UnboundedSender<CapturedMessage>>,
+) -> Result<()> {
+    let mut state = ServerConnection::new(responses);
+    let mut framer = FrameReader::new(Direction::Outgoing);
+    let mut buf = vec![0; 65536];
+    // what went over the wire, and the same decrypted when it was encrypted
+    let observe =
+        |direction, raw: Vec<u8>, decrypted: Option<Vec<u8>>, message: Result<Message>| {
+            if let Some(o) = &observer {
+                let _ = o.send(CapturedMessage {
+                    timestamp: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default(),
+                    direction,
+                    connection,
+                    decrypted,
+                    raw,
+                    message,
+                });
+            }
+        };
+    loop {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        framer.push(&buf[..n]);
+        while let Some(frame) = framer.next_frame() {
+            match frame {
+                Frame::Ping(ping) => stream.write_all(&state.answer_ping(&ping)).await?,
+                Frame::Message(raw) => {
+                    let exchange = match state.handle(&raw) {
+                        Ok(e) => e,
+                        Err(e) => {
+                            observe(Direction::Outgoing, raw, None, Err(e));
+                            continue;
+                        }
+                    };
+                    let decrypted = is_encrypted(&raw).then_some(exchange.request);
+                    observe(Direction::Outgoing, raw, decrypted, Ok(exchange.message));
+                    for reply in exchange.replies {
+                        stream.write_all(&frame_message(&reply.sent)).await?;
+                        let decrypted = is_encrypted(&reply.sent).then_some(reply.plain);
+                        observe(
+                            Direction::Incoming,
+                            reply.sent,
+                            decrypted,
+                            Ok(reply.message),
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{CannedResponses, ServerConnection};
+    use crate::{
+        message::{decode_message, encode_message, Message},
+        serialization::{OperationRequest, ParameterTable, Value},
+    };
+
+    #[test]
+    fn test_canned_responses() {
+        let responses = CannedResponses::from_toml(
+            r#"
+            [[response]]
+            operation = "LoadWallet"
+            parameters = { 1 = 12345, 2 = { type = "Long", value = 7 } }
+
+            [[response]]
+            operation = 66
+            return_code = 1
+            debug_message = "second"
+            "#,
+        )
+        .unwrap();
+        let mut conn = ServerConnection::new(Arc::new(responses));
+        let init = conn.handle(&[0xF3, 0, 1, 6]).unwrap();
+        assert_eq!(init.replies[0].sent, vec![0xF3, 1, 0]);
+
+        let request = encode_message(&Message::OperationRequest(OperationRequest::new(
+            66,
+            ParameterTable::new(),
+        )))
+        .unwrap();
+        let mut return_codes = Vec::new();
+        for _ in 0..3 {
+            let exchange = conn.handle(&request).unwrap();
+            let Message::OperationResponse(r) = decode_message(&exchange.replies[0].sent).unwrap()
+            else {
+                panic!("expected a response");
+            };
+            return_codes.push(r.return_code());
+            if return_codes.len() == 1 {
+                assert_eq!(r.parameters().get(&2), Some(&Value::Long(7)));
+            }
+        }
+        // the last response repeats
+        assert_eq!(return_codes, vec![0, 1, 1]);
+    }
+}
@@ -0,0 +1,247 @@
+use std::{fmt::Write, ops::Range};
+
+use crate::{
+    serialization::type_code::TypeCode,
+    value_diff::{PathSegment, ValuePath},
+};
+
+// Which bytes of a message every value (and every length, type code, opcode, ...) was read from, for when a
+// message fails to decode or decodes to something odd. Recorded by StreamDeserializer when its `spans` is set,
+// `message::decode_message_with_spans` does that for a whole message.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    // offsets into the message, starting at the 0xF3 byte
+    pub range: Range<usize>,
+    // None for bytes that aren't a value of their own, like lengths and type codes
+    pub type_code: Option<TypeCode>,
+    // the value the bytes belong to, in the same form value_diff and the terminal ui use
+    pub path: ValuePath,
+    // what the bytes are when the path doesn't say, e.g. "length" or "key". Empty for plain values
+    pub field: &'static str,
+    pub children: Vec<Span>,
+}
+
+impl Span {
+    pub fn label(&self) -> String {
+        let mut parts = Vec::new();
+        if !self.path.0.is_empty() {
+            parts.push(self.path.to_string());
+        }
+        if !self.field.is_empty() {
+            parts.push(self.field.to_string());
+        }
+        if let Some(code) = self.type_code {
+            parts.push(format!("({:?})", code));
+        }
+        parts.join(" ")
+    }
+    // The outermost span for this path, for a parameter that's the whole entry including its key
+    pub fn find(&self, path: &ValuePath) -> Option<&Span> {
+        if &self.path == path {
+            return Some(self);
+        }
+        self.children.iter().find_map(|c| c.find(path))
+    }
+    pub fn find_field(&self, field: &str) -> Option<&Span> {
+        if self.field == field {
+            return Some(self);
+        }
+        self.children.iter().find_map(|c| c.find_field(field))
+    }
+}
+
+// Builds the span tree while a deserializer reads. Positions passed in are the deserializer's, `offset` is
+// where its input starts in the message
+#[derive(Debug)]
+pub struct SpanRecorder {
+    offset: usize,
+    // spans that are still being read, innermost last. The first one is the message itself
+    open: Vec<Span>,
+}
+
+impl SpanRecorder {
+    pub fn new(offset: usize) -> Self {
+        Self {
+            offset,
+            open: vec![Span {
+                range: 0..offset,
+                type_code: None,
+                path: ValuePath::default(),
+                field: "message",
+                children: Vec::new(),
+            }],
+        }
+    }
+    fn current(&mut self) -> &mut Span {
+        // never empty, finish is the only thing that takes the message span out
+        self.open.last_mut().unwrap()
+    }
+    pub fn open(&mut self, position: usize, segment: Option<PathSegment>, field: &'static str) {
+        let parent = self.current();
+        let path = match segment {
+            Some(s) => parent.path.child(s),
+            None => parent.path.clone(),
+        };
+        self.open.push(Span {
+            range: self.offset + position..self.offset + position,
+            type_code: None,
+            path,
+            field,
+            children: Vec::new(),
+        });
+    }
+    pub fn close(&mut self, position: usize, type_code: Option<TypeCode>) {
+        if self.open.len() < 2 {
+            return;
+        }
+        let mut span = self.open.pop().unwrap();
+        span.range.end = self.offset + position;
+        span.type_code = type_code;
+        self.current().children.push(span);
+    }
+    pub fn leaf(&mut self, range: Range<usize>, field: &'static str) {
+        let path = self.current().path.clone();
+        let range = self.offset + range.start..self.offset + range.end;
+        self.current().children.push(Span {
+            range,
+            type_code: None,
+            path,
+            field,
+            children: Vec::new(),
+        });
+    }
+    // Moves the innermost open span (and what was already read of it) to a child path of its parent
+    pub fn set_segment(&mut self, segment: PathSegment) {
+        if self.open.len() < 2 {
+            return;
+        }
+        let path = self.open[self.open.len() - 2].path.child(segment);
+        fn repath(span: &mut Span, path: &ValuePath) {
+            span.path = path.clone();
+            for c in &mut span.children {
+                repath(c, path);
+            }
+        }
+        repath(self.current(), &path);
+    }
+    // Closes whatever is still open at `position`, which is where decoding stopped if it failed
+    pub fn finish(mut self, position: usize) -> Span {
+        while self.open.len() > 1 {
+            self.close(position, None);
+        }
+        let mut message = self.open.pop().unwrap();
+        message.range.end = self.offset + position;
+        message
+    }
+}
+
+const RESET: &str = "\x1b[0m";
+
+fn span_colour(span: &Span) -> &'static str {
+    match span.type_code {
+        None => "\x1b[2m",
+        Some(TypeCode::String | TypeCode::StringArray) => "\x1b[32m",
+        Some(TypeCode::ByteArray | TypeCode::Custom) => "\x1b[35m",
+        Some(
+            TypeCode::Boolean
+            | TypeCode::Byte
+            | TypeCode::Short
+            | TypeCode::Integer
+            | TypeCode::Long
+            | TypeCode::Float
+            | TypeCode::Double
+            | TypeCode::IntegerArray,
+        ) => "\x1b[36m",
+        Some(_) => "\x1b[33m",
+    }
+}
+
+// Hex dump of `raw` with one line per span, the bytes of a span that aren't in one of its children labelled with
+// the span itself. Bytes after the message (trailing garbage, or everything after where decoding stopped) are
+// labelled "not decoded"
+pub fn render_hex(raw: &[u8], spans: &Span, colour: bool) -> String {
+    let mut lines = Vec::new();
+    leaf_ranges(spans, &mut lines);
+    let end = spans.range.end.min(raw.len());
+    if end < raw.len() {
+        lines.push((end..raw.len(), "not decoded".to_string(), "\x1b[31m"));
+    }
+    let mut s = String::new();
+    for (range, label, code) in lines {
+        let range = range.start.min(raw.len())..range.end.min(raw.len());
+        let (colour, reset) = if colour { (code, RESET) } else { ("", "") };
+        // long values are wrapped, the label only goes on their first line
+        for (i, chunk) in raw[range.clone()].chunks(16).enumerate() {
+            let hex: Vec<_> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            write!(
+                s,
+                "{:04x}  {}{:<47}{}",
+                range.start + i * 16,
+                colour,
+                hex.join(" "),
+                reset
+            )
+            .unwrap();
+            if i == 0 {
+                write!(s, "  {}", label).unwrap();
+            }
+            s.push('\n');
+        }
+    }
+    s
+}
+
+fn leaf_ranges(span: &Span, out: &mut Vec<(Range<usize>, String, &'static str)>) {
+    let mut at = span.range.start;
+    for c in &span.children {
+        if at < c.range.start {
+            out.push((at..c.range.start, span.label(), span_colour(span)));
+        }
+        leaf_ranges(c, out);
+        at = c.range.end;
+    }
+    if at < span.range.end {
+        out.push((at..span.range.end, span.label(), span_colour(span)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render_hex;
+    use crate::{
+        message::decode_message_with_spans,
+        serialization::type_code::TypeCode,
+        value_diff::{PathSegment, ValuePath},
+    };
+
+    #[test]
+    fn test_spans() {
+        // LoadWallet request with params 5 = "ab" and 6 = ["x", "y"]
+        let raw = [
+            0xF3, 2, 66, 0, 2, 5, 115, 0, 2, 97, 98, 6, 97, 0, 2, 0, 1, 120, 0, 1, 121,
+        ];
+        let (res, spans) = decode_message_with_spans(&raw);
+        res.unwrap();
+        assert_eq!(spans.range, 0..raw.len());
+        let param = spans
+            .find(&ValuePath(vec![PathSegment::Parameter(5)]))
+            .unwrap();
+        assert_eq!(param.range, 5..11);
+        assert_eq!(param.children[2].type_code, Some(TypeCode::String));
+        let item = spans
+            .find(&ValuePath(vec![
+                PathSegment::Parameter(6),
+                PathSegment::Index(1),
+            ]))
+            .unwrap();
+        assert_eq!(item.range, 18..21);
+        assert!(render_hex(&raw, &spans, false).contains("0014  79"));
+
+        // cut off in the middle of the array, everything read so far is still there
+        let (res, spans) = decode_message_with_spans(&raw[..19]);
+        assert!(res.is_err());
+        assert_eq!(spans.find_field("opcode").unwrap().range, 2..3);
+        assert!(render_hex(&raw[..19], &spans, false).contains("not decoded"));
+    }
+}
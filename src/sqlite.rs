@@ -0,0 +1,360 @@
+use std::{path::Path, time::Duration};
+
+use anyhow::{Context, Result};
+use rusqlite::{params, types::Value as SqlValue, Connection};
+
+use crate::{
+    capture::CapturedMessage,
+    message::{is_encrypted, Message},
+    output::{event_name, opcode_name, value_json},
+    pairing::Pairing,
+    registry::{self, ParameterOwner},
+    serialization::Value,
+    value_diff::{self, PathSegment, Walked},
+};
+
+// Decoded sessions as an SQLite database, for poking at a capture with SQL instead of grep. Every frame is kept
+// (raw bytes and why it didn't decode, if it didn't), decoded messages get a row each with responses linked to the
+// request they answer, and every parameter down to the innermost value gets a row in `parameters`: scalars as
+// native sqlite values, containers as JSON.
+//
+// Each message is committed as it's written so the database is usable while a capture is still running. The
+// database is put in WAL mode with synchronous=NORMAL so that isn't an fsync per message: commits only go to the
+// log, which is synced when it's checkpointed. A crash can lose the last few messages but not the database.
+
+const SCHEMA: &str = "
+DROP TABLE IF EXISTS parameters;
+DROP TABLE IF EXISTS messages;
+DROP TABLE IF EXISTS frames;
+DROP TABLE IF EXISTS connections;
+CREATE TABLE connections (
+    id INTEGER PRIMARY KEY,
+    first_seen REAL NOT NULL,
+    last_seen REAL NOT NULL,
+    frames INTEGER NOT NULL
+);
+CREATE TABLE frames (
+    id INTEGER PRIMARY KEY,
+    connection INTEGER NOT NULL REFERENCES connections(id),
+    direction TEXT NOT NULL,
+    timestamp REAL NOT NULL,
+    length INTEGER NOT NULL,
+    raw BLOB NOT NULL,
+    decrypted BLOB,
+    encrypted INTEGER NOT NULL,
+    error TEXT
+);
+CREATE TABLE messages (
+    id INTEGER PRIMARY KEY,
+    frame_id INTEGER NOT NULL REFERENCES frames(id),
+    connection INTEGER NOT NULL,
+    direction TEXT NOT NULL,
+    timestamp REAL NOT NULL,
+    type TEXT NOT NULL,
+    opcode INTEGER,
+    event_code INTEGER,
+    name TEXT,
+    return_code INTEGER,
+    debug_message TEXT,
+    request_id INTEGER REFERENCES messages(id),
+    response_id INTEGER REFERENCES messages(id),
+    latency_ms REAL
+);
+CREATE TABLE parameters (
+    message_id INTEGER NOT NULL REFERENCES messages(id),
+    path TEXT NOT NULL,
+    parameter INTEGER NOT NULL,
+    name TEXT,
+    type TEXT NOT NULL,
+    value,
+    json TEXT
+);
+CREATE INDEX messages_by_name ON messages(name);
+CREATE INDEX parameters_by_message ON parameters(message_id);
+";
+
+pub struct SqliteWriter {
+    conn: Connection,
+    // ids of the requests that haven't been answered yet and when they were sent, by connection and opcode
+    waiting: Pairing<(u32, u8), (i64, Duration)>,
+}
+
+impl SqliteWriter {
+    // Replaces whatever tables of ours are already in the database
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let conn = Connection::open(path)
+            .with_context(|| format!("opening database {}", path.display()))?;
+        Self::new(conn)
+    }
+    pub fn new(conn: Connection) -> Result<Self> {
+        conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA synchronous = NORMAL;")
+            .context("setting up the database journal")?;
+        conn.execute_batch(SCHEMA)
+            .context("creating database tables")?;
+        Ok(Self {
+            conn,
+            waiting: Pairing::new(),
+        })
+    }
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+    // Returns the frame's id
+    pub fn write(&mut self, m: &CapturedMessage) -> Result<i64> {
+        let tx = self.conn.transaction()?;
+        let seen = m.timestamp.as_secs_f64();
+        tx.execute(
+            "INSERT INTO connections (id, first_seen, last_seen, frames) VALUES (?1, ?2, ?2, 1)
+             ON CONFLICT(id) DO UPDATE SET last_seen = ?2, frames = frames + 1",
+            params![m.connection, seen],
+        )?;
+        tx.execute(
+            "INSERT INTO frames
+             (connection, direction, timestamp, length, raw, decrypted, encrypted, error)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                m.connection,
+                m.direction.as_str(),
+                seen,
+                m.raw.len(),
+                m.raw,
+                m.decrypted,
+                is_encrypted(&m.raw),
+                m.message.as_ref().err().map(|e| format!("{:#}", e)),
+            ],
+        )?;
+        let frame_id = tx.last_insert_rowid();
+        let message = match &m.message {
+            Ok(message) => message,
+            Err(_) => {
+                tx.commit()?;
+                return Ok(frame_id);
+            }
+        };
+
+        let (kind, opcode, event_code, return_code, debug_message) = match message {
+            Message::OperationRequest(r) => {
+                ("operation_request", Some(r.opcode()), None, None, None)
+            }
+            Message::OperationResponse(r) => (
+                "operation_response",
+                Some(r.opcode()),
+                None,
+                Some(r.return_code()),
+                Some(r.debug_message()),
+            ),
+            Message::Event(e) => ("event", None, Some(e.event_code()), None, None),
+            Message::InternalOperationRequest(r) => (
+                "internal_operation_request",
+                Some(r.opcode()),
+                None,
+                None,
+                None,
+            ),
+            Message::InternalOperationResponse(r) => (
+                "internal_operation_response",
+                Some(r.opcode()),
+                None,
+                Some(r.return_code()),
+                Some(r.debug_message()),
+            ),
+            Message::Init => ("init", None, None, None, None),
+            Message::InitResponse => ("init_response", None, None, None, None),
+            Message::Message => ("message", None, None, None, None),
+            Message::RawMessage => ("raw_message", None, None, None, None),
+        };
+        let (table, owner) = match message {
+            Message::OperationRequest(r) => (
+                Some(r.parameters()),
+                Some(ParameterOwner::Operation(r.opcode())),
+            ),
+            Message::OperationResponse(r) => (
+                Some(r.parameters()),
+                Some(ParameterOwner::Operation(r.opcode())),
+            ),
+            Message::Event(e) => (
+                Some(e.params()),
+                Some(ParameterOwner::Event(e.event_code())),
+            ),
+            Message::InternalOperationRequest(r) => (Some(r.parameters()), None),
+            Message::InternalOperationResponse(r) => (Some(r.parameters()), None),
+            _ => (None, None),
+        };
+        // internal operations have their own opcodes, the names are only for the WebServices ones
+        let name = match message {
+            Message::OperationRequest(_) | Message::OperationResponse(_) => opcode.map(opcode_name),
+            Message::Event(_) => event_code.map(event_name),
+            _ => None,
+        };
+        let debug_message = match debug_message {
+            Some(Value::String(s)) => Some(s.clone()),
+            Some(Value::Null(_)) | None => None,
+            Some(v) => Some(format!("{:?}", v)),
+        };
+        tx.execute(
+            "INSERT INTO messages (frame_id, connection, direction, timestamp, type, opcode, event_code, name,
+                                   return_code, debug_message)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                frame_id,
+                m.connection,
+                m.direction.as_str(),
+                seen,
+                kind,
+                opcode,
+                event_code,
+                name,
+                return_code,
+                debug_message,
+            ],
+        )?;
+        let message_id = tx.last_insert_rowid();
+
+        match message {
+            Message::OperationRequest(r) => self
+                .waiting
+                .request((m.connection, r.opcode()), (message_id, m.timestamp)),
+            Message::OperationResponse(r) => {
+                if let Some((request_id, sent)) = self.waiting.response(&(m.connection, r.opcode()))
+                {
+                    let latency = m.timestamp.saturating_sub(sent).as_secs_f64() * 1000.0;
+                    tx.execute(
+                        "UPDATE messages SET request_id = ?1, latency_ms = ?2 WHERE id = ?3",
+                        params![request_id, latency, message_id],
+                    )?;
+                    tx.execute(
+                        "UPDATE messages SET response_id = ?1 WHERE id = ?2",
+                        params![message_id, request_id],
+                    )?;
+                }
+            }
+            _ => {}
+        }
+
+        if let Some(table) = table {
+            let mut insert = tx.prepare_cached(
+                "INSERT INTO parameters (message_id, path, parameter, name, type, value, json)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?;
+            for Walked { path, value, .. } in value_diff::walk_parameters(table, owner) {
+                let parameter = match path.0.first() {
+                    Some(PathSegment::Parameter(code)) => *code,
+                    // every path starts with the parameter it's in
+                    _ => continue,
+                };
+                // the name is the top level parameter's, a row further down would get the name of a parameter it
+                // is only inside of
+                let name = match path.0.len() {
+                    1 => registry::parameter_name(owner, parameter),
+                    _ => None,
+                };
+                let (scalar, json) = match scalar(&value) {
+                    Some(v) => (v, None),
+                    None => (SqlValue::Null, Some(value_json(&value).to_string())),
+                };
+                insert.execute(params![
+                    message_id,
+                    path.to_string(),
+                    parameter,
+                    name,
+                    format!("{:?}", value.type_code()),
+                    scalar,
+                    json,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(frame_id)
+    }
+}
+
+// None for containers, they're stored as JSON
+fn scalar(value: &Value) -> Option<SqlValue> {
+    Some(match value {
+        Value::Byte(b) => SqlValue::Integer(*b as i64),
+        Value::Bool(b) => SqlValue::Integer(*b as i64),
+        Value::Short(s) => SqlValue::Integer(*s as i64),
+        Value::Int(i) => SqlValue::Integer(*i as i64),
+        Value::Long(l) => SqlValue::Integer(*l),
+        Value::Float(f) => SqlValue::Real(f.0 as f64),
+        Value::Double(d) => SqlValue::Real(d.0),
+        Value::String(s) => SqlValue::Text(s.clone()),
+        Value::ByteArray(a) => SqlValue::Blob(a.clone()),
+        Value::Null(_) => SqlValue::Null,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rusqlite::Connection;
+
+    use super::SqliteWriter;
+    use crate::{capture::CapturedMessage, message::Direction};
+
+    #[test]
+    fn test_sqlite() {
+        let mut writer = SqliteWriter::new(Connection::open_in_memory().unwrap()).unwrap();
+        // LoadWallet request, then its response with 5 = {"a": 7} and a frame that doesn't decode
+        let frames = [
+            (Direction::Outgoing, vec![0xF3, 2, 66, 0, 0]),
+            (
+                Direction::Incoming,
+                vec![
+                    0xF3, 3, 66, 0, 0, 42, 0, 1, 5, 104, 0, 1, 115, 0, 1, 97, 105, 0, 0, 0, 7,
+                ],
+            ),
+            (Direction::Incoming, vec![0xF3, 3, 66]),
+        ];
+        for (i, (direction, raw)) in frames.into_iter().enumerate() {
+            writer
+                .write(&CapturedMessage {
+                    timestamp: Duration::from_millis(100 * i as u64),
+                    connection: 1,
+                    ..CapturedMessage::decoded(direction, raw)
+                })
+                .unwrap();
+        }
+        let conn = writer.connection();
+        let (request, latency): (i64, f64) = conn
+            .query_row(
+                "SELECT request_id, latency_ms FROM messages WHERE type = 'operation_response'",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((request, latency), (1, 100.0));
+        let value: i64 = conn
+            .query_row(
+                "SELECT value FROM parameters WHERE path = 'params[5].a'",
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert_eq!(value, 7);
+        let errors: i64 = conn
+            .query_row(
+                "SELECT count(*) FROM frames WHERE error IS NOT NULL",
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert_eq!(errors, 1);
+
+        // files don't get an fsync per message
+        let path = std::env::temp_dir().join(format!("rcsniff2-test-{}.db", std::process::id()));
+        let writer = SqliteWriter::create(&path).unwrap();
+        let mode: String = writer
+            .connection()
+            .query_row("PRAGMA journal_mode", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(mode, "wal");
+        drop(writer);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
@@ -0,0 +1,319 @@
+use std::{collections::BTreeMap, fmt::Write, time::Duration};
+
+use serde::Serialize;
+
+use crate::{
+    capture::CapturedMessage,
+    message::{is_encrypted, Direction, Message},
+    output::opcode_name,
+    pairing::Pairing,
+    serialization::op_code::Service,
+};
+
+// Traffic summary: how much went each way, which operations were requested, how often they failed and how long
+// the server took to answer. Only the WebServices port is sniffed, so everything counted is WebServices traffic
+// and the report is for that one service.
+
+#[derive(Debug, Clone)]
+pub struct Stats {
+    // timeline resolution
+    bucket: Duration,
+    first: Option<Duration>,
+    last: Option<Duration>,
+    directions: BTreeMap<Direction, DirectionStats>,
+    opcodes: BTreeMap<u8, OpcodeStats>,
+    // when the requests that haven't been answered yet were sent, by connection and opcode
+    waiting: Pairing<(u32, u8), Duration>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct DirectionStats {
+    messages: u64,
+    bytes: u64,
+    decode_failures: u64,
+    encrypted: u64,
+    // messages per bucket, from the first message seen either way
+    timeline: Vec<u64>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct OpcodeStats {
+    requests: u64,
+    responses: u64,
+    return_codes: BTreeMap<i16, u64>,
+    latencies: Vec<Duration>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StatsReport {
+    pub service: String,
+    pub seconds: f64,
+    pub bucket_seconds: f64,
+    pub directions: Vec<DirectionReport>,
+    pub opcodes: Vec<OpcodeReport>,
+    // over every opcode
+    pub latency: Option<Latency>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DirectionReport {
+    pub direction: Direction,
+    pub messages: u64,
+    pub bytes: u64,
+    pub per_second: f64,
+    pub decode_failures: u64,
+    pub encrypted: u64,
+    pub timeline: Vec<u64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OpcodeReport {
+    pub opcode: u8,
+    pub name: String,
+    pub requests: u64,
+    pub responses: u64,
+    // only the non-zero ones
+    pub return_codes: BTreeMap<i16, u64>,
+    pub latency: Option<Latency>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Latency {
+    pub count: usize,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+    pub max_ms: f64,
+}
+
+impl Latency {
+    fn new(latencies: &[Duration]) -> Option<Self> {
+        if latencies.is_empty() {
+            return None;
+        }
+        let mut sorted = latencies.to_vec();
+        sorted.sort();
+        // nearest rank
+        let percentile = |p: f64| {
+            let rank = ((p / 100.0 * sorted.len() as f64).ceil() as usize).max(1);
+            sorted[rank - 1].as_secs_f64() * 1000.0
+        };
+        Some(Self {
+            count: sorted.len(),
+            p50_ms: percentile(50.0),
+            p90_ms: percentile(90.0),
+            p99_ms: percentile(99.0),
+            max_ms: percentile(100.0),
+        })
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(10))
+    }
+}
+
+impl Stats {
+    pub fn new(bucket: Duration) -> Self {
+        Self {
+            bucket,
+            first: None,
+            last: None,
+            directions: BTreeMap::new(),
+            opcodes: BTreeMap::new(),
+            waiting: Pairing::new(),
+        }
+    }
+    pub fn observe(&mut self, m: &CapturedMessage) {
+        let first = *self.first.get_or_insert(m.timestamp);
+        self.last = Some(self.last.map_or(m.timestamp, |l| l.max(m.timestamp)));
+        let direction = self.directions.entry(m.direction).or_default();
+        direction.messages += 1;
+        direction.bytes += m.raw.len() as u64;
+        let bucket =
+            (m.timestamp.saturating_sub(first).as_secs_f64() / self.bucket.as_secs_f64()) as usize;
+        if direction.timeline.len() <= bucket {
+            direction.timeline.resize(bucket + 1, 0);
+        }
+        direction.timeline[bucket] += 1;
+        let message = match &m.message {
+            Ok(message) => message,
+            Err(_) if is_encrypted(&m.raw) => {
+                direction.encrypted += 1;
+                return;
+            }
+            Err(_) => {
+                direction.decode_failures += 1;
+                return;
+            }
+        };
+        match message {
+            Message::OperationRequest(r) => {
+                self.opcodes.entry(r.opcode()).or_default().requests += 1;
+                self.waiting
+                    .request((m.connection, r.opcode()), m.timestamp);
+            }
+            Message::OperationResponse(r) => {
+                let opcode = self.opcodes.entry(r.opcode()).or_default();
+                opcode.responses += 1;
+                if r.return_code() != 0 {
+                    *opcode.return_codes.entry(r.return_code()).or_default() += 1;
+                }
+                if let Some(sent) = self.waiting.response(&(m.connection, r.opcode())) {
+                    opcode.latencies.push(m.timestamp.saturating_sub(sent));
+                }
+            }
+            _ => {}
+        }
+    }
+    pub fn report(&self) -> StatsReport {
+        let seconds = match (self.first, self.last) {
+            (Some(first), Some(last)) => last.saturating_sub(first).as_secs_f64(),
+            _ => 0.0,
+        };
+        let all: Vec<_> = self
+            .opcodes
+            .values()
+            .flat_map(|o| o.latencies.iter().copied())
+            .collect();
+        StatsReport {
+            service: Service::WebServices.as_ref().to_string(),
+            seconds,
+            bucket_seconds: self.bucket.as_secs_f64(),
+            directions: self
+                .directions
+                .iter()
+                .map(|(direction, d)| DirectionReport {
+                    direction: *direction,
+                    messages: d.messages,
+                    bytes: d.bytes,
+                    // anything shorter than a second counts as one, a short burst isn't thousands a second
+                    per_second: d.messages as f64 / seconds.max(1.0),
+                    decode_failures: d.decode_failures,
+                    encrypted: d.encrypted,
+                    timeline: d.timeline.clone(),
+                })
+                .collect(),
+            opcodes: self
+                .opcodes
+                .iter()
+                .map(|(opcode, o)| OpcodeReport {
+                    opcode: *opcode,
+                    name: opcode_name(*opcode),
+                    requests: o.requests,
+                    responses: o.responses,
+                    return_codes: o.return_codes.clone(),
+                    latency: Latency::new(&o.latencies),
+                })
+                .collect(),
+            latency: Latency::new(&all),
+        }
+    }
+}
+
+impl StatsReport {
+    pub fn render(&self) -> String {
+        let mut s = String::new();
+        writeln!(s, "{} traffic over {:.1}s", self.service, self.seconds).unwrap();
+        writeln!(
+            s,
+            "{:<10}{:>10}{:>12}{:>9}{:>9}{:>11}{:>11}",
+            "direction", "messages", "bytes", "msg/s", "peak/s", "failures", "encrypted"
+        )
+        .unwrap();
+        for d in &self.directions {
+            let peak = d.timeline.iter().max().copied().unwrap_or(0) as f64 / self.bucket_seconds;
+            writeln!(
+                s,
+                "{:<10}{:>10}{:>12}{:>9.1}{:>9.1}{:>11}{:>11}",
+                d.direction.as_str(),
+                d.messages,
+                d.bytes,
+                d.per_second,
+                peak,
+                d.decode_failures,
+                d.encrypted
+            )
+            .unwrap();
+        }
+        if self.opcodes.is_empty() {
+            return s;
+        }
+        writeln!(
+            s,
+            "\n{:<44}{:>9}{:>10}{:>8}{:>9}{:>9}{:>9}{:>9}",
+            "operation", "requests", "responses", "errors", "p50 ms", "p90 ms", "p99 ms", "max ms"
+        )
+        .unwrap();
+        let latency_columns = |l: &Option<Latency>| match l {
+            Some(l) => format!(
+                "{:>9.1}{:>9.1}{:>9.1}{:>9.1}",
+                l.p50_ms, l.p90_ms, l.p99_ms, l.max_ms
+            ),
+            None => format!("{:>9}{:>9}{:>9}{:>9}", "-", "-", "-", "-"),
+        };
+        for o in &self.opcodes {
+            writeln!(
+                s,
+                "{:<44}{:>9}{:>10}{:>8}{}",
+                o.name,
+                o.requests,
+                o.responses,
+                o.return_codes.values().sum::<u64>(),
+                latency_columns(&o.latency)
+            )
+            .unwrap();
+            for (code, count) in &o.return_codes {
+                writeln!(s, "  return code {}: {}", code, count).unwrap();
+            }
+        }
+        writeln!(s, "{:<71}{}", "all", latency_columns(&self.latency)).unwrap();
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use super::Stats;
+    use crate::{
+        capture::CapturedMessage,
+        message::{encode_message, Direction, Message},
+        serialization::{OperationRequest, OperationResponse, Value},
+    };
+
+    #[test]
+    fn test_stats() {
+        let at = |ms, direction, raw| CapturedMessage {
+            timestamp: Duration::from_millis(ms),
+            ..CapturedMessage::decoded(direction, raw)
+        };
+        let request = || {
+            let request = OperationRequest::new(66, HashMap::new().into());
+            encode_message(&Message::OperationRequest(request)).unwrap()
+        };
+        let response = |return_code| {
+            let response =
+                OperationResponse::new(66, return_code, Value::Null(()), HashMap::new().into());
+            encode_message(&Message::OperationResponse(response)).unwrap()
+        };
+        let mut stats = Stats::default();
+        stats.observe(&at(0, Direction::Outgoing, request()));
+        stats.observe(&at(100, Direction::Outgoing, request()));
+        stats.observe(&at(150, Direction::Incoming, response(0)));
+        stats.observe(&at(400, Direction::Incoming, response(-1)));
+        stats.observe(&at(500, Direction::Incoming, vec![0xF3, 3, 66]));
+
+        let report = stats.report();
+        let op = &report.opcodes[0];
+        assert_eq!((op.requests, op.responses), (2, 2));
+        assert_eq!(op.return_codes.get(&-1), Some(&1));
+        let latency = op.latency.as_ref().unwrap();
+        assert_eq!((latency.p50_ms, latency.max_ms), (150.0, 300.0));
+        let incoming = &report.directions[0];
+        assert_eq!((incoming.messages, incoming.decode_failures), (3, 1));
+        assert!(report.render().contains("LoadWallet(66)"));
+    }
+}
@@ -0,0 +1,572 @@
+use std::{collections::HashSet, ops::Range, time::Duration};
+
+use anyhow::Result;
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Row, Table, TableState},
+    Frame,
+};
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use crate::{
+    capture::CapturedMessage,
+    filter::Filter,
+    message::{decode_message_with_spans, Direction, Message},
+    output::{event_name, opcode_name},
+    registry::{self, ParameterOwner},
+    serialization::Value,
+    spans::Span as ByteSpan,
+    value_diff::{self, PathSegment, ValuePath, Walked},
+};
+
+// Interactive browser for a session: message list on top, the selected message's value tree and hex dump below.
+// Keys: up/down (j/k) move, tab switches between list and tree, enter/space folds a tree node, / edits the
+// filter, f toggles following new messages, q quits
+
+pub fn run(messages: UnboundedReceiver<CapturedMessage>, filter: Option<Filter>) -> Result<()> {
+    let mut terminal = ratatui::try_init()?;
+    let mut app = App::new(messages, filter);
+    let res = (|| -> Result<()> {
+        loop {
+            app.receive();
+            terminal.draw(|f| app.draw(f))?;
+            if event::poll(Duration::from_millis(100))? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press && !app.key(key) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    })();
+    ratatui::restore();
+    res
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Focus {
+    List,
+    Tree,
+}
+
+struct App {
+    rx: UnboundedReceiver<CapturedMessage>,
+    messages: Vec<CapturedMessage>,
+    // indices into messages that pass the filter
+    visible: Vec<usize>,
+    filter: Option<Filter>,
+    list: TableState,
+    // first visible entry of the message list, only the rows that fit are built each frame
+    list_offset: usize,
+    follow: bool,
+    focus: Focus,
+    tree: ListState,
+    // folded nodes, kept across messages so the same parts stay folded while browsing
+    collapsed: HashSet<ValuePath>,
+    // the selected message's tree and spans, built when the selection changes instead of every frame
+    tree_cache: Option<TreeCache>,
+    // the filter being typed
+    input: Option<String>,
+    status: String,
+}
+
+impl App {
+    fn new(rx: UnboundedReceiver<CapturedMessage>, filter: Option<Filter>) -> Self {
+        Self {
+            rx,
+            messages: Vec::new(),
+            visible: Vec::new(),
+            filter,
+            list: TableState::default(),
+            list_offset: 0,
+            follow: true,
+            focus: Focus::List,
+            tree: ListState::default(),
+            collapsed: HashSet::new(),
+            tree_cache: None,
+            input: None,
+            status: String::new(),
+        }
+    }
+    fn receive(&mut self) {
+        while let Ok(m) = self.rx.try_recv() {
+            if self.filter.iter().all(|f| f.passes(&m)) {
+                self.visible.push(self.messages.len());
+            }
+            self.messages.push(m);
+        }
+        if self.follow && !self.visible.is_empty() {
+            self.list.select(Some(self.visible.len() - 1));
+        } else if self.list.selected().is_none() && !self.visible.is_empty() {
+            self.list.select(Some(0));
+        }
+    }
+    fn refilter(&mut self) {
+        let selected = self.selected_message().map(|(i, _)| i);
+        self.visible = (0..self.messages.len())
+            .filter(|i| self.filter.iter().all(|f| f.passes(&self.messages[*i])))
+            .collect();
+        // stay on the same message if it's still visible
+        let pos = selected.and_then(|s| self.visible.iter().position(|i| *i >= s));
+        self.list.select(pos.or(self.visible.len().checked_sub(1)));
+    }
+    fn selected_message(&self) -> Option<(usize, &CapturedMessage)> {
+        let i = *self.visible.get(self.list.selected()?)?;
+        Some((i, &self.messages[i]))
+    }
+    // false to quit
+    fn key(&mut self, key: KeyEvent) -> bool {
+        if let Some(input) = &mut self.input {
+            match key.code {
+                KeyCode::Enter => {
+                    let text = self.input.take().unwrap_or_default();
+                    match text.trim() {
+                        "" => self.filter = None,
+                        t => match Filter::parse(t) {
+                            Ok(f) => self.filter = Some(f),
+                            Err(e) => {
+                                self.status = format!("invalid filter: {:#}", e);
+                                return true;
+                            }
+                        },
+                    }
+                    self.status.clear();
+                    self.refilter();
+                }
+                KeyCode::Esc => self.input = None,
+                KeyCode::Backspace => {
+                    input.pop();
+                }
+                KeyCode::Char(c) => input.push(c),
+                _ => {}
+            }
+            return true;
+        }
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Char('/') => self.input = Some(String::new()),
+            KeyCode::Char('f') => self.follow = !self.follow,
+            KeyCode::Tab => {
+                self.focus = match self.focus {
+                    Focus::List => Focus::Tree,
+                    Focus::Tree => Focus::List,
+                }
+            }
+            KeyCode::Up | KeyCode::Char('k') => self.step(-1),
+            KeyCode::Down | KeyCode::Char('j') => self.step(1),
+            KeyCode::PageUp => self.step(-20),
+            KeyCode::PageDown => self.step(20),
+            KeyCode::Enter | KeyCode::Char(' ') if self.focus == Focus::Tree => {
+                let selected = self.tree.selected();
+                let row = selected.and_then(|i| self.tree_rows().get(i).copied());
+                if let Some(path) = row.filter(|r| r.children).map(|r| r.path.clone()) {
+                    if !self.collapsed.remove(&path) {
+                        self.collapsed.insert(path);
+                    }
+                }
+            }
+            _ => {}
+        }
+        true
+    }
+    fn step(&mut self, by: isize) {
+        let moved = |selected: Option<usize>, len: usize| {
+            (len > 0)
+                .then(|| (selected.unwrap_or(0) as isize + by).clamp(0, len as isize - 1) as usize)
+        };
+        match self.focus {
+            Focus::List => {
+                self.follow = false;
+                self.list
+                    .select(moved(self.list.selected(), self.visible.len()));
+            }
+            Focus::Tree => {
+                let len = self.tree_rows().len();
+                self.tree.select(moved(self.tree.selected(), len));
+            }
+        }
+    }
+    // rebuilds the tree cache if another message was selected since
+    fn refresh_tree(&mut self) {
+        let selected = self.selected_message().map(|(i, _)| i);
+        if self.tree_cache.as_ref().map(|c| c.message) == selected {
+            return;
+        }
+        self.tree_cache = self.selected_message().map(|(i, m)| TreeCache {
+            message: i,
+            rows: match &m.message {
+                Ok(message) => message_tree(message),
+                Err(_) => Vec::new(),
+            },
+            spans: decode_message_with_spans(m.message_bytes()).1,
+        });
+    }
+    fn tree_rows(&mut self) -> Vec<&TreeRow> {
+        self.refresh_tree();
+        match &self.tree_cache {
+            Some(cache) => visible_rows(&cache.rows, &self.collapsed),
+            None => Vec::new(),
+        }
+    }
+
+    fn draw(&mut self, f: &mut Frame) {
+        self.refresh_tree();
+        let [top, bottom, status] = Layout::vertical([
+            Constraint::Percentage(45),
+            Constraint::Min(5),
+            Constraint::Length(1),
+        ])
+        .areas(f.area());
+        let [tree_area, hex_area] =
+            Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+                .areas(bottom);
+        let focused = |focus| {
+            if self.focus == focus {
+                Style::default().fg(Color::Yellow)
+            } else {
+                Style::default()
+            }
+        };
+
+        // borders and header take 3 lines
+        let height = top.height.saturating_sub(3).max(1) as usize;
+        let selected = self.list.selected().unwrap_or(0);
+        self.list_offset = self
+            .list_offset
+            .clamp(selected.saturating_sub(height - 1), selected)
+            .min(self.visible.len().saturating_sub(height));
+        let end = (self.list_offset + height).min(self.visible.len());
+        let rows: Vec<_> = self.visible[self.list_offset..end]
+            .iter()
+            .map(|i| message_row(&self.messages[*i]))
+            .collect();
+        let title = format!(
+            " {} of {} messages{} ",
+            self.visible.len(),
+            self.messages.len(),
+            if self.follow { ", following" } else { "" }
+        );
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Length(12),
+                Constraint::Length(3),
+                Constraint::Length(18),
+                Constraint::Min(20),
+                Constraint::Length(6),
+                Constraint::Length(7),
+            ],
+        )
+        .header(
+            Row::new(["time", "dir", "type", "name", "code", "size"])
+                .style(Style::default().add_modifier(Modifier::BOLD)),
+        )
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(title)
+                .border_style(focused(Focus::List)),
+        )
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        let mut window =
+            TableState::default().with_selected(self.list.selected().map(|s| s - self.list_offset));
+        f.render_stateful_widget(table, top, &mut window);
+
+        let (rows, spans) = match &self.tree_cache {
+            Some(cache) => (
+                visible_rows(&cache.rows, &self.collapsed),
+                Some(&cache.spans),
+            ),
+            None => (Vec::new(), None),
+        };
+        if !matches!(self.tree.selected(), Some(s) if s < rows.len()) {
+            self.tree.select((!rows.is_empty()).then_some(0));
+        }
+        let items: Vec<_> = rows
+            .iter()
+            .map(|r| {
+                let marker = match (r.children, self.collapsed.contains(&r.path)) {
+                    (false, _) => "  ",
+                    (true, true) => "+ ",
+                    (true, false) => "- ",
+                };
+                ListItem::new(format!("{}{}{}", "  ".repeat(r.depth), marker, r.label))
+            })
+            .collect();
+        let list = List::new(items)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(" values ")
+                    .border_style(focused(Focus::Tree)),
+            )
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+        f.render_stateful_widget(list, tree_area, &mut self.tree);
+
+        let selected = self.selected_message();
+        let hex = match selected {
+            Some((_, m)) => match &m.message {
+                Err(e) => {
+                    let mut lines = hex_lines(m.message_bytes(), None);
+                    lines.insert(
+                        0,
+                        Line::styled(format!("{:#}", e), Style::default().fg(Color::Red)),
+                    );
+                    lines
+                }
+                Ok(_) => hex_lines(
+                    m.message_bytes(),
+                    spans.and_then(|spans| {
+                        selected_bytes(
+                            spans,
+                            self.tree.selected().and_then(|i| rows.get(i).copied()),
+                        )
+                    }),
+                ),
+            },
+            None => Vec::new(),
+        };
+        f.render_widget(
+            Paragraph::new(hex).block(Block::default().borders(Borders::ALL).title(" bytes ")),
+            hex_area,
+        );
+
+        let status_line = match &self.input {
+            Some(input) => format!("filter: {}_", input),
+            None if !self.status.is_empty() => self.status.clone(),
+            None => "q quit  / filter  tab switch pane  enter fold  f follow".to_string(),
+        };
+        f.render_widget(Paragraph::new(status_line), status);
+    }
+}
+
+fn message_row(m: &CapturedMessage) -> Row<'static> {
+    let time = {
+        let ms = m.timestamp.as_millis() % 86_400_000;
+        format!(
+            "{:02}:{:02}:{:02}.{:03}",
+            ms / 3_600_000,
+            ms / 60_000 % 60,
+            ms / 1000 % 60,
+            ms % 1000
+        )
+    };
+    let dir = match m.direction {
+        Direction::Incoming => "in",
+        Direction::Outgoing => "out",
+    };
+    let (kind, name, code) = match &m.message {
+        Ok(Message::OperationRequest(r)) => ("Request", opcode_name(r.opcode()), String::new()),
+        Ok(Message::OperationResponse(r)) => (
+            "Response",
+            opcode_name(r.opcode()),
+            r.return_code().to_string(),
+        ),
+        Ok(Message::Event(e)) => ("Event", event_name(e.event_code()), String::new()),
+        Ok(Message::InternalOperationRequest(r)) => {
+            ("Internal Request", r.opcode().to_string(), String::new())
+        }
+        Ok(Message::InternalOperationResponse(r)) => (
+            "Internal Response",
+            r.opcode().to_string(),
+            r.return_code().to_string(),
+        ),
+        Ok(m) => ("", format!("{:?}", m.message_type()), String::new()),
+        Err(_) => ("error", String::new(), String::new()),
+    };
+    let row = Row::new([
+        time,
+        dir.to_string(),
+        kind.to_string(),
+        name,
+        code,
+        m.raw.len().to_string(),
+    ]);
+    match &m.message {
+        Err(_) => row.style(Style::default().fg(Color::Red)),
+        Ok(Message::OperationResponse(r)) if r.return_code() != 0 => {
+            row.style(Style::default().fg(Color::Yellow))
+        }
+        _ => row,
+    }
+}
+
+struct TreeCache {
+    // index into App::messages
+    message: usize,
+    // every row, folded or not
+    rows: Vec<TreeRow>,
+    spans: ByteSpan,
+}
+
+#[derive(Clone)]
+struct TreeRow {
+    depth: usize,
+    label: String,
+    path: ValuePath,
+    children: bool,
+}
+
+fn message_tree(message: &Message) -> Vec<TreeRow> {
+    let mut rows = vec![TreeRow {
+        depth: 0,
+        label: crate::output::message_label(message),
+        path: ValuePath::default(),
+        children: true,
+    }];
+    let (table, owner) = match message {
+        Message::OperationRequest(r) => {
+            (r.parameters(), Some(ParameterOwner::Operation(r.opcode())))
+        }
+        Message::OperationResponse(r) => {
+            rows.push(TreeRow {
+                depth: 1,
+                label: format!("return code: {}", r.return_code()),
+                path: ValuePath(vec![PathSegment::Key(Value::String("return_code".into()))]),
+                children: false,
+            });
+            (r.parameters(), Some(ParameterOwner::Operation(r.opcode())))
+        }
+        Message::Event(e) => (e.params(), Some(ParameterOwner::Event(e.event_code()))),
+        Message::InternalOperationRequest(r) => (r.parameters(), None),
+        Message::InternalOperationResponse(r) => (r.parameters(), None),
+        _ => return rows,
+    };
+    for walked in value_diff::walk_parameters(table, owner) {
+        rows.push(value_row(walked));
+    }
+    rows
+}
+
+fn value_row(walked: Walked) -> TreeRow {
+    let value = walked.value.as_ref();
+    let name = match walked.path.0.last() {
+        Some(PathSegment::Parameter(code)) => {
+            let owner = walked.parameter.and_then(|(owner, _)| owner);
+            registry::parameter_name(owner, *code)
+                .map_or_else(|| code.to_string(), |n| format!("{}({})", n, code))
+        }
+        Some(PathSegment::Index(i)) => format!("[{}]", i),
+        Some(PathSegment::Key(k)) => format!("{:?}", k),
+        None => String::new(),
+    };
+    let len = match value {
+        Value::Dictionary(d) => Some(d.len()),
+        Value::HashTable(h) => Some(h.len()),
+        Value::Array(a) => Some(a.len()),
+        Value::ObjectArray(a) => Some(a.len()),
+        Value::StringArray(a) => Some(a.len()),
+        Value::IntegerArray(a) => Some(a.len()),
+        _ => None,
+    };
+    let nested = matches!(
+        value,
+        Value::EventData(_) | Value::OperationRequest(_) | Value::OperationResponse(_)
+    );
+    let summary = match len {
+        Some(len) => format!("{} ({})", value.as_ref(), len),
+        None if nested => value.as_ref().to_string(),
+        None => format!("{:?}", value),
+    };
+    TreeRow {
+        depth: walked.depth + 1,
+        label: format!("{}: {}", name, summary),
+        path: walked.path,
+        children: len.unwrap_or(0) > 0 || nested,
+    }
+}
+
+// drops the rows below folded nodes
+fn visible_rows<'a>(rows: &'a [TreeRow], collapsed: &HashSet<ValuePath>) -> Vec<&'a TreeRow> {
+    let mut out = Vec::with_capacity(rows.len());
+    let mut hide_below = None;
+    for row in rows {
+        if let Some(depth) = hide_below {
+            if row.depth > depth {
+                continue;
+            }
+            hide_below = None;
+        }
+        if row.children && collapsed.contains(&row.path) {
+            hide_below = Some(row.depth);
+        }
+        out.push(row);
+    }
+    out
+}
+
+// The bytes the selected row of the tree was decoded from
+fn selected_bytes(spans: &ByteSpan, row: Option<&TreeRow>) -> Option<Range<usize>> {
+    let span = match row?.path.0.first() {
+        // the return code row has a made up path, it isn't a parameter
+        Some(PathSegment::Key(_)) => spans.find_field("return code"),
+        _ => spans.find(&row?.path),
+    }?;
+    Some(span.range.clone())
+}
+
+// 16 bytes per line with an ascii column, the bytes in `highlight` reversed
+fn hex_lines(raw: &[u8], highlight: Option<Range<usize>>) -> Vec<Line<'static>> {
+    let marked = Style::default().add_modifier(Modifier::REVERSED);
+    raw.chunks(16)
+        .enumerate()
+        .map(|(row, chunk)| {
+            let mut spans = vec![Span::raw(format!("{:04x}  ", row * 16))];
+            for (i, b) in chunk.iter().enumerate() {
+                let style = match &highlight {
+                    Some(r) if r.contains(&(row * 16 + i)) => marked,
+                    _ => Style::default(),
+                };
+                spans.push(Span::styled(format!("{:02x}", b), style));
+                spans.push(Span::raw(" "));
+            }
+            spans.push(Span::raw("   ".repeat(16 - chunk.len())));
+            let ascii: String = chunk
+                .iter()
+                .map(|b| {
+                    if b.is_ascii_graphic() {
+                        *b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            spans.push(Span::raw(format!(" {}", ascii)));
+            Line::from(spans)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use super::{message_tree, visible_rows};
+    use crate::{
+        message::Message,
+        serialization::{OperationRequest, Value},
+    };
+
+    #[test]
+    fn test_fold_tree() {
+        let message = Message::OperationRequest(OperationRequest::new(
+            66,
+            HashMap::from([
+                (1, Value::Int(1)),
+                (2, Value::StringArray(vec!["a".into(), "b".into()])),
+            ])
+            .into(),
+        ));
+        let rows = message_tree(&message);
+        let all = visible_rows(&rows, &HashSet::new()).len();
+        assert_eq!(all, rows.len());
+        let array = rows.iter().find(|r| r.children && r.depth == 1).unwrap();
+        let folded = visible_rows(&rows, &HashSet::from([array.path.clone()]));
+        assert_eq!(folded.len(), all - 2);
+        let root = visible_rows(&rows, &HashSet::from([Default::default()]));
+        assert_eq!(root.len(), 1);
+    }
+}
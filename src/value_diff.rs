@@ -0,0 +1,518 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt::{Display, Write},
+};
+
+use crate::{
+    registry::ParameterOwner,
+    serialization::{OperationResponse, ParameterTable, Value},
+};
+
+// Structural diff between two Value trees, e.g. two responses to the same opcode
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum PathSegment {
+    Parameter(u8),
+    Key(Value),
+    Index(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct ValuePath(pub Vec<PathSegment>);
+
+impl ValuePath {
+    pub fn child(&self, segment: PathSegment) -> Self {
+        let mut v = self.0.clone();
+        v.push(segment);
+        Self(v)
+    }
+}
+impl Display for ValuePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0.is_empty() {
+            return f.write_str("$");
+        }
+        for (i, s) in self.0.iter().enumerate() {
+            match s {
+                PathSegment::Parameter(c) => write!(f, "params[{}]", c)?,
+                PathSegment::Key(Value::String(s)) if is_identifier(s) => {
+                    if i != 0 {
+                        f.write_char('.')?;
+                    }
+                    f.write_str(s)?
+                }
+                PathSegment::Key(Value::String(s)) => write!(f, "[{:?}]", s)?,
+                PathSegment::Key(k) => write!(f, "[{:?}]", k)?,
+                PathSegment::Index(i) => write!(f, "[{}]", i)?,
+            }
+        }
+        Ok(())
+    }
+}
+// One value met walking through a parameter table, see walk_parameters
+#[derive(Debug, Clone)]
+pub struct Walked<'a> {
+    pub path: ValuePath,
+    // 0 for the table's own parameters
+    pub depth: usize,
+    // elements of string and integer arrays aren't Values until they're made into one
+    pub value: Cow<'a, Value>,
+    // for parameters, of the walked table or of a message embedded in it: whose parameter it is and its code
+    pub parameter: Option<(Option<ParameterOwner>, u8)>,
+}
+
+// Every value in a parameter table, each followed by everything in it. Parameters are in code order and dictionary
+// entries in key order, so the same message always walks the same way
+pub fn walk_parameters(table: &ParameterTable, owner: Option<ParameterOwner>) -> Vec<Walked<'_>> {
+    let mut out = Vec::new();
+    walk_table(table, owner, &ValuePath::default(), 0, &mut out);
+    out
+}
+
+fn walk_table<'a>(
+    table: &'a ParameterTable,
+    owner: Option<ParameterOwner>,
+    path: &ValuePath,
+    depth: usize,
+    out: &mut Vec<Walked<'a>>,
+) {
+    let mut params: Vec<_> = table.iter().collect();
+    params.sort_by_key(|(k, _)| **k);
+    for (code, value) in params {
+        let path = path.child(PathSegment::Parameter(*code));
+        walk_value(value, path, depth, Some((owner, *code)), out);
+    }
+}
+
+fn walk_value<'a>(
+    value: &'a Value,
+    path: ValuePath,
+    depth: usize,
+    parameter: Option<(Option<ParameterOwner>, u8)>,
+    out: &mut Vec<Walked<'a>>,
+) {
+    out.push(Walked {
+        path: path.clone(),
+        depth,
+        value: Cow::Borrowed(value),
+        parameter,
+    });
+    let depth = depth + 1;
+    let element = |i: usize, value: Value| Walked {
+        path: path.child(PathSegment::Index(i)),
+        depth,
+        value: Cow::Owned(value),
+        parameter: None,
+    };
+    match value {
+        Value::Array(a) => walk_items(a, &path, depth, out),
+        Value::ObjectArray(a) => walk_items(a, &path, depth, out),
+        Value::StringArray(a) => out.extend(
+            a.iter()
+                .enumerate()
+                .map(|(i, s)| element(i, Value::String(s.clone()))),
+        ),
+        Value::IntegerArray(a) => out.extend(
+            a.iter()
+                .enumerate()
+                .map(|(i, v)| element(i, Value::Int(*v))),
+        ),
+        Value::Dictionary(d) => walk_map(d.iter(), &path, depth, out),
+        Value::HashTable(h) => walk_map(h.iter(), &path, depth, out),
+        // embedded messages' parameters belong to them, not to the table they're in
+        Value::EventData(e) => {
+            let owner = Some(ParameterOwner::Event(e.event_code()));
+            walk_table(e.params(), owner, &path, depth, out)
+        }
+        Value::OperationRequest(r) => {
+            let owner = Some(ParameterOwner::Operation(r.opcode()));
+            walk_table(r.parameters(), owner, &path, depth, out)
+        }
+        Value::OperationResponse(r) => {
+            let owner = Some(ParameterOwner::Operation(r.opcode()));
+            walk_table(r.parameters(), owner, &path, depth, out)
+        }
+        // byte arrays are one value, a hex string
+        _ => {}
+    }
+}
+
+fn walk_items<'a>(items: &'a [Value], path: &ValuePath, depth: usize, out: &mut Vec<Walked<'a>>) {
+    for (i, v) in items.iter().enumerate() {
+        walk_value(v, path.child(PathSegment::Index(i)), depth, None, out);
+    }
+}
+
+fn walk_map<'a>(
+    map: impl Iterator<Item = (&'a Value, &'a Value)>,
+    path: &ValuePath,
+    depth: usize,
+    out: &mut Vec<Walked<'a>>,
+) {
+    let mut entries: Vec<_> = map.collect();
+    entries.sort_by_cached_key(|(k, _)| format!("{:?}", k));
+    for (k, v) in entries {
+        walk_value(v, path.child(PathSegment::Key(k.clone())), depth, None, out);
+    }
+}
+
+pub(crate) fn is_identifier(s: &str) -> bool {
+    !s.is_empty()
+        && !s.starts_with(|c: char| c.is_ascii_digit())
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Added(Value),
+    Removed(Value),
+    // same type, different value
+    Changed { old: Value, new: Value },
+    TypeChanged { old: Value, new: Value },
+}
+
+// Array indices in `path` are into the new value, except for a `Removed` element which is only in the old one,
+// the same way a unified diff numbers its - lines by the old file and everything else by the new one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValueChange {
+    pub path: ValuePath,
+    pub change: Change,
+}
+
+impl ValueChange {
+    // whether `path` indexes into the old value
+    pub fn in_old(&self) -> bool {
+        matches!(self.change, Change::Removed(_))
+    }
+}
+
+pub fn diff_values(old: &Value, new: &Value) -> Vec<ValueChange> {
+    let mut out = Vec::new();
+    diff_into(&ValuePath::default(), old, new, &mut out);
+    out
+}
+
+pub fn diff_parameters(old: &ParameterTable, new: &ParameterTable) -> Vec<ValueChange> {
+    let mut out = Vec::new();
+    let mut codes: Vec<u8> = old.keys().chain(new.keys()).copied().collect();
+    codes.sort();
+    codes.dedup();
+    for code in codes {
+        let path = ValuePath(vec![PathSegment::Parameter(code)]);
+        diff_optional(path, old.get(&code), new.get(&code), &mut out);
+    }
+    out
+}
+
+fn diff_optional(
+    path: ValuePath,
+    old: Option<&Value>,
+    new: Option<&Value>,
+    out: &mut Vec<ValueChange>,
+) {
+    match (old, new) {
+        (Some(o), Some(n)) => diff_into(&path, o, n, out),
+        (Some(o), None) => out.push(ValueChange {
+            path,
+            change: Change::Removed(o.clone()),
+        }),
+        (None, Some(n)) => out.push(ValueChange {
+            path,
+            change: Change::Added(n.clone()),
+        }),
+        (None, None) => {}
+    }
+}
+
+fn diff_into(path: &ValuePath, old: &Value, new: &Value, out: &mut Vec<ValueChange>) {
+    if old == new {
+        return;
+    }
+    match (old, new) {
+        (Value::Dictionary(o), Value::Dictionary(n)) => diff_maps(path, o, n, out),
+        (Value::HashTable(o), Value::HashTable(n)) => diff_maps(path, o, n, out),
+        (Value::Array(o), Value::Array(n)) => diff_sequences(path, o, n, out),
+        (Value::ObjectArray(o), Value::ObjectArray(n)) => diff_sequences(path, o, n, out),
+        (Value::StringArray(o), Value::StringArray(n)) => diff_sequences(
+            path,
+            &o.iter().cloned().map(Value::String).collect::<Vec<_>>(),
+            &n.iter().cloned().map(Value::String).collect::<Vec<_>>(),
+            out,
+        ),
+        (Value::IntegerArray(o), Value::IntegerArray(n)) => diff_sequences(
+            path,
+            &o.iter().copied().map(Value::Int).collect::<Vec<_>>(),
+            &n.iter().copied().map(Value::Int).collect::<Vec<_>>(),
+            out,
+        ),
+        (Value::OperationRequest(o), Value::OperationRequest(n)) if o.opcode() == n.opcode() => {
+            diff_nested(path, o.parameters(), n.parameters(), out)
+        }
+        (Value::OperationResponse(o), Value::OperationResponse(n)) if o.opcode() == n.opcode() => {
+            diff_nested(path, o.parameters(), n.parameters(), out)
+        }
+        (Value::EventData(o), Value::EventData(n)) if o.event_code() == n.event_code() => {
+            diff_nested(path, o.params(), n.params(), out)
+        }
+        _ => {
+            // byte arrays are reported whole, element-wise diffs of binary blobs aren't readable
+            let change = if old.type_code() == new.type_code() {
+                Change::Changed {
+                    old: old.clone(),
+                    new: new.clone(),
+                }
+            } else {
+                Change::TypeChanged {
+                    old: old.clone(),
+                    new: new.clone(),
+                }
+            };
+            out.push(ValueChange {
+                path: path.clone(),
+                change,
+            })
+        }
+    }
+}
+
+fn diff_nested(
+    path: &ValuePath,
+    old: &ParameterTable,
+    new: &ParameterTable,
+    out: &mut Vec<ValueChange>,
+) {
+    for mut c in diff_parameters(old, new) {
+        let mut full = path.0.clone();
+        full.append(&mut c.path.0);
+        c.path = ValuePath(full);
+        out.push(c);
+    }
+}
+
+fn diff_maps(
+    path: &ValuePath,
+    old: &HashMap<Value, Value>,
+    new: &HashMap<Value, Value>,
+    out: &mut Vec<ValueChange>,
+) {
+    // sort keys by their debug output so the order is stable between runs
+    let mut keys: Vec<&Value> = old
+        .keys()
+        .chain(new.keys().filter(|k| !old.contains_key(k)))
+        .collect();
+    keys.sort_by_cached_key(|k| format!("{:?}", k));
+    for k in keys {
+        let path = path.child(PathSegment::Key(k.clone()));
+        diff_optional(path, old.get(k), new.get(k), out);
+    }
+}
+
+// above this many element comparisons arrays are compared index by index instead of aligned
+const MAX_ALIGN: usize = 1_000_000;
+
+fn diff_sequences(path: &ValuePath, old: &[Value], new: &[Value], out: &mut Vec<ValueChange>) {
+    if old.len() * new.len() > MAX_ALIGN {
+        for i in 0..old.len().max(new.len()) {
+            let path = path.child(PathSegment::Index(i));
+            diff_optional(path, old.get(i), new.get(i), out);
+        }
+        return;
+    }
+    // longest common subsequence, then walk it to find insertions and deletions
+    let (n, m) = (old.len(), new.len());
+    let mut lcs = vec![vec![0u32; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut removed = Vec::new();
+    let mut added = Vec::new();
+    let flush = |removed: &mut Vec<usize>, added: &mut Vec<usize>, out: &mut Vec<ValueChange>| {
+        // an element replaced in place is diffed recursively instead of reported as remove + add, at its
+        // index in the new array
+        let paired = removed.len().min(added.len());
+        for k in 0..paired {
+            diff_into(
+                &path.child(PathSegment::Index(added[k])),
+                &old[removed[k]],
+                &new[added[k]],
+                out,
+            );
+        }
+        for &r in &removed[paired..] {
+            out.push(ValueChange {
+                path: path.child(PathSegment::Index(r)),
+                change: Change::Removed(old[r].clone()),
+            });
+        }
+        for &a in &added[paired..] {
+            out.push(ValueChange {
+                path: path.child(PathSegment::Index(a)),
+                change: Change::Added(new[a].clone()),
+            });
+        }
+        removed.clear();
+        added.clear();
+    };
+    while i < n || j < m {
+        if i < n && j < m && old[i] == new[j] {
+            flush(&mut removed, &mut added, out);
+            i += 1;
+            j += 1;
+        } else if j < m && (i == n || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            added.push(j);
+            j += 1;
+        } else {
+            removed.push(i);
+            i += 1;
+        }
+    }
+    flush(&mut removed, &mut added, out);
+}
+
+// Remembers the last response for each opcode on each connection and diffs the next one against it, so two
+// clients' responses aren't diffed against each other
+#[derive(Default)]
+pub struct ResponseDiffer {
+    last: HashMap<(u32, u8), OperationResponse>,
+}
+
+impl ResponseDiffer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    // None for the first response to an opcode on the connection
+    pub fn observe(
+        &mut self,
+        connection: u32,
+        response: &OperationResponse,
+    ) -> Option<Vec<ValueChange>> {
+        let previous = self
+            .last
+            .insert((connection, response.opcode()), response.clone())?;
+        Some(diff_parameters(
+            previous.parameters(),
+            response.parameters(),
+        ))
+    }
+}
+
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const RESET: &str = "\x1b[0m";
+
+// One line per change, coloured with ANSI escapes if `colour` is set
+pub fn render(changes: &[ValueChange], colour: bool) -> String {
+    let mut s = String::new();
+    let (red, green, yellow, reset) = if colour {
+        (RED, GREEN, YELLOW, RESET)
+    } else {
+        ("", "", "", "")
+    };
+    for c in changes {
+        match &c.change {
+            Change::Added(v) => writeln!(s, "{green}+ {}: {:?}{reset}", c.path, v),
+            Change::Removed(v) => writeln!(s, "{red}- {}: {:?}{reset}", c.path, v),
+            Change::Changed { old, new } => {
+                writeln!(s, "{yellow}~ {}: {:?} -> {:?}{reset}", c.path, old, new)
+            }
+            Change::TypeChanged { old, new } => writeln!(
+                s,
+                "{yellow}~ {}: {:?} -> {:?} (type changed){reset}",
+                c.path, old, new
+            ),
+        }
+        .unwrap();
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{diff_values, render, walk_parameters, Change};
+    use crate::{
+        registry::ParameterOwner,
+        serialization::{OperationRequest, Value},
+        util::HashableHashmap,
+    };
+
+    #[test]
+    fn test_nested_diff() {
+        let robot = |name: &str, cpu: i32| {
+            Value::Dictionary(HashableHashmap(HashMap::from([
+                (Value::String("name".into()), Value::String(name.into())),
+                (Value::String("cpu".into()), Value::Int(cpu)),
+            ])))
+        };
+        let old = Value::Array(vec![robot("a", 1), robot("b", 2), robot("c", 3)]);
+        let new = Value::Array(vec![
+            robot("a", 1),
+            robot("c", 4),
+            robot("d", 5),
+            Value::Short(1),
+        ]);
+        let changes = diff_values(&old, &new);
+        let text = render(&changes, false);
+        assert_eq!(
+            text,
+            "~ [1].cpu: Int(2) -> Int(4)\n\
+             ~ [1].name: \"b\" -> \"c\"\n\
+             ~ [2].cpu: Int(3) -> Int(5)\n\
+             ~ [2].name: \"c\" -> \"d\"\n\
+             + [3]: Short(1)\n"
+        );
+        assert!(matches!(changes[4].change, Change::Added(_)));
+
+        // a removed element is at its old index, an added one at its new index
+        let ints = |v: &[i32]| Value::Array(v.iter().map(|i| Value::Int(*i)).collect());
+        let changes = diff_values(&ints(&[1, 2, 3, 4]), &ints(&[1, 3, 4, 5]));
+        assert_eq!(render(&changes, false), "- [1]: Int(2)\n+ [3]: Int(5)\n");
+        assert!(changes[0].in_old() && !changes[1].in_old());
+    }
+
+    #[test]
+    fn test_walk_parameters() {
+        let embedded = OperationRequest::new(7, HashMap::from([(2, Value::Int(1))]).into());
+        let table = HashMap::from([
+            (1, Value::StringArray(vec!["a".into()])),
+            (3, Value::OperationRequest(embedded)),
+        ])
+        .into();
+        let walked: Vec<_> = walk_parameters(&table, Some(ParameterOwner::Event(9)))
+            .into_iter()
+            .map(|w| (w.path.to_string(), w.depth, w.parameter))
+            .collect();
+        assert_eq!(
+            walked,
+            vec![
+                (
+                    "params[1]".into(),
+                    0,
+                    Some((Some(ParameterOwner::Event(9)), 1))
+                ),
+                ("params[1][0]".into(), 1, None),
+                (
+                    "params[3]".into(),
+                    0,
+                    Some((Some(ParameterOwner::Event(9)), 3))
+                ),
+                // the embedded request's parameter is its own
+                (
+                    "params[3]params[2]".into(),
+                    1,
+                    Some((Some(ParameterOwner::Operation(7)), 2))
+                ),
+            ]
+        );
+    }
+}
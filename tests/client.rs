@@ -0,0 +1,161 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use rcsniff2::{
+    client::{ClientOptions, PhotonClient},
+    framing::{frame_message, FrameReader},
+    message::{decode_message, encode_message, Direction, Message},
+    serialization::{OperationRequest, OperationResponse, Value},
+    server::{serve, CannedResponses},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+// Starts a fake server on a free port and returns its address
+async fn fake_server(rules: &str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let responses = Arc::new(CannedResponses::from_toml(rules).unwrap());
+    tokio::spawn(serve(listener, responses, None));
+    addr
+}
+
+const RULES: &str = r#"
+[[response]]
+operation = "LoadWallet"
+parameters = { 1 = { type = "Long", value = 12345 } }
+
+[[response.event]]
+event = 255
+parameters = { 254 = 3 }
+
+[[response]]
+operation = "LoadWallet"
+when = { 5 = "second" }
+return_code = 1
+"#;
+
+// a different answer for each of two requests to the same opcode
+const TAGGED_RULES: &str = r#"
+[[response]]
+operation = "LoadWallet"
+when = { 5 = "a" }
+parameters = { 1 = { type = "Long", value = 1 } }
+
+[[response]]
+operation = "LoadWallet"
+when = { 5 = "b" }
+parameters = { 1 = { type = "Long", value = 2 } }
+"#;
+
+fn load_wallet(params: HashMap<u8, Value>) -> OperationRequest {
+    OperationRequest::new(66, params.into())
+}
+fn tagged(tag: &str) -> OperationRequest {
+    load_wallet(HashMap::from([(5, Value::String(tag.into()))]))
+}
+
+#[tokio::test]
+async fn test_request_and_events() {
+    let addr = fake_server(RULES).await;
+    let client = PhotonClient::connect(&addr, ClientOptions::default())
+        .await
+        .unwrap();
+
+    let response = client.request(load_wallet(HashMap::new())).await.unwrap();
+    assert_eq!(response.return_code(), 0);
+    assert_eq!(response.parameters().get(&1), Some(&Value::Long(12345)));
+    let event = client.next_event().await.unwrap();
+    assert_eq!(event.event_code(), 255);
+    assert_eq!(event.params().get(&254), Some(&Value::Int(3)));
+
+    let response = client
+        .request(load_wallet(HashMap::from([(
+            5,
+            Value::String("second".into()),
+        )])))
+        .await
+        .unwrap();
+    assert_eq!(response.return_code(), 1);
+
+    // nothing canned for this one
+    let response = client
+        .request(OperationRequest::new(3, HashMap::new().into()))
+        .await
+        .unwrap();
+    assert_eq!(response.return_code(), -2);
+}
+
+#[tokio::test]
+async fn test_encrypted_requests() {
+    let addr = fake_server(TAGGED_RULES).await;
+    let client = PhotonClient::connect(&addr, ClientOptions::default())
+        .await
+        .unwrap();
+    assert!(client
+        .request_encrypted(load_wallet(HashMap::new()))
+        .await
+        .is_err());
+
+    client.exchange_keys().await.unwrap();
+    // concurrent requests to the same opcode get their responses in order
+    let (a, b) = tokio::join!(
+        client.request_encrypted(tagged("a")),
+        client.request(tagged("b")),
+    );
+    assert_eq!(a.unwrap().parameters().get(&1), Some(&Value::Long(1)));
+    assert_eq!(b.unwrap().parameters().get(&1), Some(&Value::Long(2)));
+}
+
+#[tokio::test]
+async fn test_timed_out_request() {
+    // answers init, never answers the first LoadWallet and echoes the tag of every one after it
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut framer = FrameReader::new(Direction::Outgoing);
+        let mut buf = vec![0; 4096];
+        let mut requests = 0;
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            if n == 0 {
+                return;
+            }
+            framer.push(&buf[..n]);
+            while let Some(raw) = framer.next_message() {
+                let answer = match decode_message(&raw).unwrap() {
+                    Message::Init => vec![0xF3, 1, 0],
+                    Message::OperationRequest(r) => {
+                        requests += 1;
+                        if requests == 1 {
+                            continue;
+                        }
+                        let params = HashMap::from([(1, r.parameters()[&5].clone())]);
+                        let response =
+                            OperationResponse::new(66, 0, Value::Null(()), params.into());
+                        encode_message(&Message::OperationResponse(response)).unwrap()
+                    }
+                    _ => continue,
+                };
+                stream.write_all(&frame_message(&answer)).await.unwrap();
+            }
+        }
+    });
+    let options = ClientOptions {
+        timeout: Duration::from_millis(200),
+        ping_interval: None,
+        ..Default::default()
+    };
+    let client = PhotonClient::connect(addr, options).await.unwrap();
+    assert!(client.request(tagged("lost")).await.is_err());
+    // the waiter of the lost one is gone, so these get their own answers
+    for tag in ["a", "b"] {
+        let response = client.request(tagged(tag)).await.unwrap();
+        assert_eq!(
+            response.parameters().get(&1),
+            Some(&Value::String(tag.into()))
+        );
+    }
+}
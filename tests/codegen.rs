@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+
+use rcsniff2::{
+    message::{decode_message, encode_message, Message},
+    schema::{codegen, Schema},
+    serialization::{EventData, OperationRequest, OperationResponse, Value},
+    util::HashableHashmap,
+};
+
+// Output of `rcsniff2 schema codegen tests/fixtures/codegen_schema.json`
+mod bindings {
+    include!("fixtures/codegen.rs");
+}
+use bindings::{
+    JoinEvent, LoadMachineRequest, LoadWalletResponse, Messages, Operation2FactorAuthRequest,
+};
+
+#[test]
+fn test_generated_bindings_are_current() {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+    let schema = Schema::load(format!("{}/codegen_schema.json", dir)).unwrap();
+    assert_eq!(
+        codegen::generate(&schema, "rcsniff2"),
+        include_str!("fixtures/codegen.rs"),
+        "regenerate tests/fixtures/codegen.rs"
+    );
+}
+
+// through the wire format and back into the same struct
+fn roundtrip(message: Message) -> Messages {
+    let decoded = decode_message(&encode_message(&message).unwrap()).unwrap();
+    assert_eq!(decoded, message);
+    Messages::decode(&decoded).unwrap().unwrap()
+}
+
+#[test]
+fn test_generated_roundtrip() {
+    let dict: HashMap<Value, Value> = [(Value::Byte(1), Value::Bool(true))].into();
+    let wallet = LoadWalletResponse {
+        robits: 12345,
+        cosmetic_credits: 7,
+        param_3: Some(HashableHashmap(dict)),
+        balance: Value::Float(1.5f32.into()),
+    };
+    let res = OperationResponse::from(wallet.clone());
+    assert_eq!(res.parameters().len(), 4);
+    assert_eq!(
+        roundtrip(Message::OperationResponse(res)),
+        Messages::LoadWalletResponse(wallet.clone())
+    );
+
+    let load = LoadMachineRequest {
+        garage_slot: 3,
+        type_: None,
+    };
+    let req = OperationRequest::from(load.clone());
+    assert_eq!(req.parameters().len(), 1);
+    assert_eq!(
+        roundtrip(Message::OperationRequest(req)),
+        Messages::LoadMachineRequest(load)
+    );
+
+    let join = JoinEvent {
+        actor_list: vec![1, 2],
+        actor_nr: 2,
+    };
+    assert_eq!(
+        roundtrip(Message::Event(EventData::from(join.clone()))),
+        Messages::JoinEvent(join)
+    );
+
+    // names that aren't identifiers as they are
+    let auth = Operation2FactorAuthRequest { param_3d_view: 1 };
+    assert_eq!(
+        roundtrip(Message::OperationRequest(auth.clone().into())),
+        Messages::Operation2FactorAuthRequest(auth)
+    );
+
+    // a failed response, even with all of its parameters
+    let ok = OperationResponse::from(wallet);
+    let failed = OperationResponse::new(66, 1, Value::Null(()), ok.parameters().clone());
+    assert!(LoadWalletResponse::try_from(&ok).is_ok());
+    assert!(LoadWalletResponse::try_from(&failed).is_err());
+    // a missing parameter that has to be there
+    let bad = OperationResponse::new(66, 0, Value::Null(()), Default::default());
+    assert!(LoadWalletResponse::try_from(&bad).is_err());
+    // and a message the schema doesn't cover
+    let other = OperationRequest::new(1, Default::default());
+    assert!(Messages::decode(&Message::OperationRequest(other)).is_none());
+}
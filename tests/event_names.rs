@@ -0,0 +1,47 @@
+use std::collections::HashMap;
+
+use rcsniff2::{
+    filter::Filter,
+    message::{decode_message, encode_message, Direction, Message},
+    output::{message_json, message_label},
+    registry::{self, ParameterRegistry},
+    serialization::{EventData, Value},
+};
+
+// The game's events are named by the parameter table. Installing one is once per process, which is why this is a
+// test binary of its own
+const TABLE: &str = r#"
+[[event]]
+service = "WebServices"
+code = 42
+name = "Example"
+
+[[parameter]]
+service = "WebServices"
+event = "Example"
+code = 1
+name = "Count"
+type = "Integer"
+"#;
+
+#[test]
+fn test_game_event_names() {
+    let mut table = ParameterRegistry::builtin();
+    table.load_toml(TABLE).unwrap();
+    assert!(registry::install(table));
+
+    let params: HashMap<u8, Value> = [(1, Value::Int(3))].into();
+    let bytes = encode_message(&Message::Event(EventData::new(42, params.into()))).unwrap();
+    let message = decode_message(&bytes).unwrap();
+    let debug = format!("{:?}", message);
+    assert!(debug.contains("event_code: Example"), "{}", debug);
+    assert!(debug.contains("Count(1)"), "{}", debug);
+    assert_eq!(message_label(&message), "Event Data Example(42)");
+    let json = message_json(Direction::Incoming, &message);
+    assert_eq!(json["name"], "Example");
+    assert_eq!(json["parameters"]["1"]["name"], "Count");
+
+    // and can be filtered on by name
+    let filter = Filter::parse("event == Example").unwrap();
+    assert!(filter.matches(Direction::Incoming, &message));
+}
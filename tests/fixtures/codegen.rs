@@ -0,0 +1,255 @@
+// Generated by `rcsniff2 schema codegen`, edit the schema instead of this file
+#[allow(unused_imports)]
+use rcsniff2::message::Message;
+#[allow(unused_imports)]
+use rcsniff2::serialization::{
+    EventData, OperationRequest, OperationResponse, ParameterTable, Value,
+};
+#[allow(unused_imports)]
+use rcsniff2::typed::{read_field, write_field};
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct LoadMachineRequest {
+    // GarageSlot(1)
+    pub garage_slot: i32,
+    // type(2)
+    pub type_: Option<String>,
+}
+impl LoadMachineRequest {
+    pub const CODE: u8 = 43;
+}
+impl TryFrom<&ParameterTable> for LoadMachineRequest {
+    type Error = ::anyhow::Error;
+    #[allow(unused_variables)]
+    fn try_from(table: &ParameterTable) -> ::anyhow::Result<Self> {
+        Ok(Self {
+            garage_slot: read_field(table, 1, "GarageSlot(1)")?,
+            type_: read_field(table, 2, "type(2)")?,
+        })
+    }
+}
+impl From<LoadMachineRequest> for ParameterTable {
+    #[allow(unused_variables, unused_mut, clippy::let_and_return)]
+    fn from(value: LoadMachineRequest) -> Self {
+        let mut table = ParameterTable::new();
+        write_field(&mut table, 1, value.garage_slot);
+        write_field(&mut table, 2, value.type_);
+        table
+    }
+}
+impl TryFrom<&OperationRequest> for LoadMachineRequest {
+    type Error = ::anyhow::Error;
+    fn try_from(m: &OperationRequest) -> ::anyhow::Result<Self> {
+        if m.opcode() != Self::CODE {
+            ::anyhow::bail!("expected code {}, got {}", Self::CODE, m.opcode());
+        }
+        Self::try_from(m.parameters())
+    }
+}
+impl From<LoadMachineRequest> for OperationRequest {
+    fn from(value: LoadMachineRequest) -> Self {
+        OperationRequest::new(LoadMachineRequest::CODE, value.into())
+    }
+}
+impl ::std::fmt::Debug for LoadMachineRequest {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        f.debug_struct("LoadMachineRequest")
+            .field("GarageSlot(1)", &self.garage_slot)
+            .field("type(2)", &self.type_)
+            .finish()
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct LoadWalletResponse {
+    // Robits(1)
+    pub robits: i64,
+    // CosmeticCredits(2)
+    pub cosmetic_credits: i64,
+    // 3
+    pub param_3: Option<rcsniff2::util::HashableHashmap<Value, Value>>,
+    // Balance(4)
+    pub balance: Value,
+}
+impl LoadWalletResponse {
+    pub const CODE: u8 = 66;
+}
+impl TryFrom<&ParameterTable> for LoadWalletResponse {
+    type Error = ::anyhow::Error;
+    #[allow(unused_variables)]
+    fn try_from(table: &ParameterTable) -> ::anyhow::Result<Self> {
+        Ok(Self {
+            robits: read_field(table, 1, "Robits(1)")?,
+            cosmetic_credits: read_field(table, 2, "CosmeticCredits(2)")?,
+            param_3: read_field(table, 3, "3")?,
+            balance: read_field(table, 4, "Balance(4)")?,
+        })
+    }
+}
+impl From<LoadWalletResponse> for ParameterTable {
+    #[allow(unused_variables, unused_mut, clippy::let_and_return)]
+    fn from(value: LoadWalletResponse) -> Self {
+        let mut table = ParameterTable::new();
+        write_field(&mut table, 1, value.robits);
+        write_field(&mut table, 2, value.cosmetic_credits);
+        write_field(&mut table, 3, value.param_3);
+        write_field(&mut table, 4, value.balance);
+        table
+    }
+}
+impl TryFrom<&OperationResponse> for LoadWalletResponse {
+    type Error = ::anyhow::Error;
+    fn try_from(m: &OperationResponse) -> ::anyhow::Result<Self> {
+        if m.opcode() != Self::CODE {
+            ::anyhow::bail!("expected code {}, got {}", Self::CODE, m.opcode());
+        }
+        if m.return_code() != 0 {
+            ::anyhow::bail!("LoadWalletResponse failed with return code {}", m.return_code());
+        }
+        Self::try_from(m.parameters())
+    }
+}
+impl From<LoadWalletResponse> for OperationResponse {
+    fn from(value: LoadWalletResponse) -> Self {
+        OperationResponse::new(LoadWalletResponse::CODE, 0, Value::Null(()), value.into())
+    }
+}
+impl ::std::fmt::Debug for LoadWalletResponse {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        f.debug_struct("LoadWalletResponse")
+            .field("Robits(1)", &self.robits)
+            .field("CosmeticCredits(2)", &self.cosmetic_credits)
+            .field("3", &self.param_3)
+            .field("Balance(4)", &self.balance)
+            .finish()
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct JoinEvent {
+    // ActorList(252)
+    pub actor_list: Vec<i32>,
+    // ActorNr(254)
+    pub actor_nr: i32,
+}
+impl JoinEvent {
+    pub const CODE: u8 = 255;
+}
+impl TryFrom<&ParameterTable> for JoinEvent {
+    type Error = ::anyhow::Error;
+    #[allow(unused_variables)]
+    fn try_from(table: &ParameterTable) -> ::anyhow::Result<Self> {
+        Ok(Self {
+            actor_list: read_field(table, 252, "ActorList(252)")?,
+            actor_nr: read_field(table, 254, "ActorNr(254)")?,
+        })
+    }
+}
+impl From<JoinEvent> for ParameterTable {
+    #[allow(unused_variables, unused_mut, clippy::let_and_return)]
+    fn from(value: JoinEvent) -> Self {
+        let mut table = ParameterTable::new();
+        write_field(&mut table, 252, value.actor_list);
+        write_field(&mut table, 254, value.actor_nr);
+        table
+    }
+}
+impl TryFrom<&EventData> for JoinEvent {
+    type Error = ::anyhow::Error;
+    fn try_from(m: &EventData) -> ::anyhow::Result<Self> {
+        if m.event_code() != Self::CODE {
+            ::anyhow::bail!("expected code {}, got {}", Self::CODE, m.event_code());
+        }
+        Self::try_from(m.params())
+    }
+}
+impl From<JoinEvent> for EventData {
+    fn from(value: JoinEvent) -> Self {
+        EventData::new(JoinEvent::CODE, value.into())
+    }
+}
+impl ::std::fmt::Debug for JoinEvent {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        f.debug_struct("JoinEvent")
+            .field("ActorList(252)", &self.actor_list)
+            .field("ActorNr(254)", &self.actor_nr)
+            .finish()
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Operation2FactorAuthRequest {
+    // 3dView(1)
+    pub param_3d_view: u8,
+}
+impl Operation2FactorAuthRequest {
+    pub const CODE: u8 = 99;
+}
+impl TryFrom<&ParameterTable> for Operation2FactorAuthRequest {
+    type Error = ::anyhow::Error;
+    #[allow(unused_variables)]
+    fn try_from(table: &ParameterTable) -> ::anyhow::Result<Self> {
+        Ok(Self {
+            param_3d_view: read_field(table, 1, "3dView(1)")?,
+        })
+    }
+}
+impl From<Operation2FactorAuthRequest> for ParameterTable {
+    #[allow(unused_variables, unused_mut, clippy::let_and_return)]
+    fn from(value: Operation2FactorAuthRequest) -> Self {
+        let mut table = ParameterTable::new();
+        write_field(&mut table, 1, value.param_3d_view);
+        table
+    }
+}
+impl TryFrom<&OperationRequest> for Operation2FactorAuthRequest {
+    type Error = ::anyhow::Error;
+    fn try_from(m: &OperationRequest) -> ::anyhow::Result<Self> {
+        if m.opcode() != Self::CODE {
+            ::anyhow::bail!("expected code {}, got {}", Self::CODE, m.opcode());
+        }
+        Self::try_from(m.parameters())
+    }
+}
+impl From<Operation2FactorAuthRequest> for OperationRequest {
+    fn from(value: Operation2FactorAuthRequest) -> Self {
+        OperationRequest::new(Operation2FactorAuthRequest::CODE, value.into())
+    }
+}
+impl ::std::fmt::Debug for Operation2FactorAuthRequest {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        f.debug_struct("Operation2FactorAuthRequest")
+            .field("3dView(1)", &self.param_3d_view)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Messages {
+    LoadMachineRequest(LoadMachineRequest),
+    LoadWalletResponse(LoadWalletResponse),
+    JoinEvent(JoinEvent),
+    Operation2FactorAuthRequest(Operation2FactorAuthRequest),
+}
+impl Messages {
+    // None if the message isn't covered by the schema
+    #[allow(unreachable_code)]
+    pub fn decode(message: &Message) -> Option<::anyhow::Result<Self>> {
+        Some(match message {
+            Message::OperationRequest(m) => match m.opcode() {
+                43 => LoadMachineRequest::try_from(m).map(Self::LoadMachineRequest),
+                99 => Operation2FactorAuthRequest::try_from(m).map(Self::Operation2FactorAuthRequest),
+                _ => return None,
+            },
+            Message::OperationResponse(m) => match m.opcode() {
+                66 => LoadWalletResponse::try_from(m).map(Self::LoadWalletResponse),
+                _ => return None,
+            },
+            Message::Event(m) => match m.event_code() {
+                255 => JoinEvent::try_from(m).map(Self::JoinEvent),
+                _ => return None,
+            },
+            _ => return None,
+        })
+    }
+}